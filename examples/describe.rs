@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
-use dfu_core::DfuIo;
 use dfu_libusb::*;
+use serde_json::json;
 
 #[derive(clap::Parser)]
 pub struct Cli {
@@ -22,6 +22,10 @@ pub struct Cli {
     #[clap(short, long)]
     serial_port: Option<String>,
 
+    /// Give up the serial reset if the port does not respond within this many milliseconds.
+    #[clap(long, default_value = "2000")]
+    reset_timeout: u64,
+
 
     /// Specify the Altsetting of the DFU Interface by number.
     #[clap(long, short, default_value = "0")]
@@ -30,6 +34,10 @@ pub struct Cli {
     /// Enable verbose logs.
     #[clap(long, short)]
     verbose: bool,
+
+    /// Print the description as a single JSON object to stdout, the logs going to stderr.
+    #[clap(long)]
+    json: bool,
 }
 
 impl Cli {
@@ -39,27 +47,34 @@ impl Cli {
             intf,
             alt,
             serial_port,
+            reset_timeout,
             verbose,
+            json,
         } = self;
         let log_level = if verbose {
             simplelog::LevelFilter::Trace
         } else {
             simplelog::LevelFilter::Info
         };
-        simplelog::SimpleLogger::init(log_level, Default::default())?;
+        if json {
+            simplelog::WriteLogger::init(log_level, Default::default(), std::io::stderr())?;
+        } else {
+            simplelog::SimpleLogger::init(log_level, Default::default())?;
+        }
 
         if let Some(serial_port) = &serial_port {
             // println!("Reseting MCU at {serial_port}");
             let bar = indicatif::ProgressBar::new_spinner();
             bar.set_message(format!("Reseting MCU at {serial_port}"));
             bar.tick();
-            match reset_mcu(&serial_port) {
-                Ok(()) => {
-                    for _ in 0..3 {
-                        std::thread::sleep(std::time::Duration::from_millis(200));
-                        bar.tick();
-                    }
-                }
+            match reset_mcu_with(
+                &serial_port,
+                ResetConfig {
+                    timeout: std::time::Duration::from_millis(reset_timeout),
+                    ..ResetConfig::MAPLE
+                },
+            ) {
+                Ok(()) => {}
                 Err(e) => {
                     bar.set_message(format!("Failed to reset MCU at {serial_port}: {e}"));
                 }
@@ -70,14 +85,220 @@ impl Cli {
         let (vid, pid) = device;
         let context = rusb::Context::new()?;
 
-        let device: Dfu<rusb::Context> =
-            DfuLibusb::open(&context, vid, pid, intf, alt).context("could not open device")?;
+        // The bootloader takes a moment to enumerate after the reset.
+        let mut device: Dfu<rusb::Context> = if serial_port.is_some() {
+            DfuLibusb::wait_for_dfu_mode(
+                &context,
+                vid,
+                pid,
+                intf,
+                alt,
+                std::time::Duration::from_secs(5),
+            )
+        } else {
+            DfuLibusb::open(&context, vid, pid, intf, alt)
+        }
+        .context("could not open device")?;
 
-        println!("{:?}", device.into_inner().functional_descriptor());
+        let manufacturer = device.manufacturer_string()?;
+        let product = device.product_string()?;
+        let serial = device.serial_string()?;
+        let version = device.device_version()?;
+        let version = format!("{:x}.{:02x}", version >> 8, version & 0xff);
+        let speed = device.connection_speed();
+
+        let handle = context
+            .open_device_with_vid_pid(vid, pid)
+            .context("could not open device")?;
+        let alt_settings = DfuLibusb::alt_settings(&handle)?;
+        drop(handle);
+
+        let functional_descriptor = device.functional_descriptor();
+        let commands = match device.get_dfuse_commands() {
+            Ok(commands) => Some(Ok(commands)),
+            Err(Error::DfuseUnsupported) => None,
+            Err(err) => Some(Err(err)),
+        };
+        // Only the STM32 bootloaders expose their option bytes.
+        let option_bytes = match device.read_option_bytes() {
+            Ok(option_bytes) => Some(Ok(option_bytes)),
+            Err(Error::NoSuchAltName { .. } | Error::DfuseUnsupported) => None,
+            Err(err) => Some(Err(err)),
+        };
+
+        if json {
+            Self::print_json(
+                (vid, pid),
+                [manufacturer, product, serial],
+                (&version, speed),
+                &alt_settings,
+                &functional_descriptor,
+                commands,
+                option_bytes,
+            );
+            return Ok(());
+        }
+
+        println!(
+            "manufacturer={:?} product={:?} serial={:?}",
+            manufacturer.as_deref().unwrap_or("UNKNOWN"),
+            product.as_deref().unwrap_or("UNKNOWN"),
+            serial.as_deref().unwrap_or("UNKNOWN"),
+        );
+        println!("version={}", version);
+        println!("speed={}", speed);
+
+        for alt_setting in &alt_settings {
+            let name = alt_setting.name.as_deref().unwrap_or_default();
+            println!(
+                "intf={} alt={} name={:?}",
+                alt_setting.intf, alt_setting.alt, name,
+            );
+            // Only DfuSe alt-settings describe their memory in their name.
+            if let Ok(memory_layout) = MemoryLayout::parse(name) {
+                println!("  {}:", memory_layout.name());
+                for segment in memory_layout.segments() {
+                    println!("    {}", segment);
+                }
+            }
+        }
+
+        println!("{}", functional_descriptor);
+
+        match commands {
+            Some(Ok(commands)) => {
+                println!("DfuSe commands:");
+                for command in commands {
+                    println!("  {:#04x}: {}", command, Self::command_name(command));
+                }
+            }
+            Some(Err(err)) => println!("Could not get the DfuSe commands: {}", err),
+            None => {}
+        }
+
+        match option_bytes {
+            Some(Ok(option_bytes)) => {
+                println!("Option bytes:");
+                println!(
+                    "  read protection: {:?} (RDP={:#04x})",
+                    option_bytes.read_protection, option_bytes.rdp
+                );
+                println!("  BOR level: {}", option_bytes.bor_level);
+                println!(
+                    "  WDG_SW={} nRST_STOP={} nRST_STDBY={}",
+                    option_bytes.software_watchdog as u8,
+                    option_bytes.no_reset_on_stop as u8,
+                    option_bytes.no_reset_on_standby as u8,
+                );
+                println!(
+                    "  write-protected sectors: {:?}{}",
+                    option_bytes.write_protected_sectors,
+                    if option_bytes.pcrop { " (PCROP)" } else { "" },
+                );
+            }
+            Some(Err(err)) => println!("Could not read the option bytes: {}", err),
+            None => {}
+        }
 
         Ok(())
     }
 
+    fn command_name(command: u8) -> &'static str {
+        match command {
+            0x00 => "Get Commands",
+            0x21 => "Set Address Pointer",
+            0x41 => "Erase",
+            0x92 => "Read Unprotect",
+            _ => "unknown",
+        }
+    }
+
+    fn print_json(
+        (vid, pid): (u16, u16),
+        [manufacturer, product, serial]: [Option<String>; 3],
+        (version, speed): (&str, Speed),
+        alt_settings: &[AltSetting],
+        functional_descriptor: &FunctionalDescriptor,
+        commands: Option<Result<Vec<u8>, Error>>,
+        option_bytes: Option<Result<OptionBytes, Error>>,
+    ) {
+        let alt_settings: Vec<_> = alt_settings
+            .iter()
+            .map(|alt_setting| {
+                let name = alt_setting.name.as_deref().unwrap_or_default();
+                let memory_layout = MemoryLayout::parse(name).ok().map(|memory_layout| {
+                    json!({
+                        "name": memory_layout.name(),
+                        "segments": memory_layout
+                            .segments()
+                            .iter()
+                            .map(|segment| json!({
+                                "start": segment.start,
+                                "page_size": segment.page_size,
+                                "page_count": segment.page_count,
+                                "readable": segment.readable,
+                                "erasable": segment.erasable,
+                                "writable": segment.writable,
+                            }))
+                            .collect::<Vec<_>>(),
+                    })
+                });
+                json!({
+                    "intf": alt_setting.intf,
+                    "alt": alt_setting.alt,
+                    "name": alt_setting.name,
+                    "memory_layout": memory_layout,
+                })
+            })
+            .collect();
+        let (major, minor) = functional_descriptor.dfu_version();
+        let commands = match commands {
+            Some(Ok(commands)) => json!(commands
+                .into_iter()
+                .map(|x| json!({ "command": x, "name": Self::command_name(x) }))
+                .collect::<Vec<_>>()),
+            Some(Err(err)) => json!({ "error": err.to_string() }),
+            None => json!(null),
+        };
+        let option_bytes = match option_bytes {
+            Some(Ok(option_bytes)) => json!({
+                "read_protection": format!("{:?}", option_bytes.read_protection),
+                "rdp": option_bytes.rdp,
+                "bor_level": option_bytes.bor_level,
+                "software_watchdog": option_bytes.software_watchdog,
+                "no_reset_on_stop": option_bytes.no_reset_on_stop,
+                "no_reset_on_standby": option_bytes.no_reset_on_standby,
+                "pcrop": option_bytes.pcrop,
+                "write_protected_sectors": option_bytes.write_protected_sectors,
+            }),
+            Some(Err(err)) => json!({ "error": err.to_string() }),
+            None => json!(null),
+        };
+        let description = json!({
+            "vid": vid,
+            "pid": pid,
+            "manufacturer": manufacturer,
+            "product": product,
+            "serial": serial,
+            "version": version,
+            "speed": speed.to_string(),
+            "max_bit_rate": speed.max_bit_rate(),
+            "alt_settings": alt_settings,
+            "functional_descriptor": {
+                "can_download": functional_descriptor.can_download(),
+                "can_upload": functional_descriptor.can_upload(),
+                "manifestation_tolerant": functional_descriptor.manifestation_tolerant(),
+                "will_detach": functional_descriptor.will_detach(),
+                "detach_timeout": functional_descriptor.detach_timeout().as_millis() as u64,
+                "transfer_size": functional_descriptor.transfer_size(),
+                "dfu_version": format!("{:x}.{:02x}", major, minor),
+            },
+            "dfuse_commands": commands,
+            "option_bytes": option_bytes,
+        });
+        println!("{}", description);
+    }
+
     pub fn parse_vid_pid(s: &str) -> Result<(u16, u16)> {
         let (vid, pid) = s
             .split_once(':')
@@ -1,13 +1,19 @@
 use anyhow::{Context, Result};
 use dfu_libusb::*;
-use std::convert::TryFrom;
-use std::io;
-use std::io::Seek;
+use serde_json::json;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(clap::Parser)]
+/// How long a burn-in iteration waits for the bootloader, so that a device that doesn't come back
+/// fails the iteration instead of stalling the run.
+const BURN_IN_WAIT: Duration = Duration::from_secs(10);
+
+#[derive(clap::Parser, Clone)]
 pub struct Cli {
-    /// Path to the firmware file to write to the device.
+    /// Path to the firmware file to write to the device: a raw binary, with or without a DFU
+    /// suffix, a DfuSe, HEX or ELF file, detected from its content.
     path: PathBuf,
 
     /// Wait for the device to appear.
@@ -22,6 +28,10 @@ pub struct Cli {
     #[clap(short, long)]
     serial_port: Option<String>,
 
+    /// Give up the serial reset if the port does not respond within this many milliseconds.
+    #[clap(long, default_value = "2000")]
+    reset_timeout: u64,
+
     /// Specify Vendor/Product ID(s) of DFU device.
     #[clap(
         long,
@@ -31,6 +41,14 @@ pub struct Cli {
     )]
     device: (u16, u16),
 
+    /// Specify the USB serial number of the DFU device.
+    #[clap(long)]
+    serial: Option<String>,
+
+    /// Specify the USB path of the DFU device (e.g. 1-4.2 for bus 1, port 2 of the hub on port 4).
+    #[clap(long = "path", value_parser = Self::parse_usb_path, value_name = "bus-port.port")]
+    usb_path: Option<(u8, Vec<u8>)>,
+
     /// Specify the DFU Interface number.
     #[clap(long, short, default_value = "0")]
     intf: u8,
@@ -39,43 +57,236 @@ pub struct Cli {
     #[clap(long, short, default_value = "2")]
     alt: u8,
 
-    /// Enable verbose logs.
-    #[clap(long, short)]
-    verbose: bool,
+    /// Specify the Altsetting of the DFU Interface by its name, or the start of it (e.g.
+    /// "@Internal Flash"), instead of --intf and --alt.
+    #[clap(long, conflicts_with_all = &["serial", "usb_path"])]
+    alt_name: Option<String>,
+
+    /// Enable verbose logs, twice to also dump the payloads of the control transfers.
+    #[clap(long, short, parse(from_occurrences))]
+    verbose: u8,
 
     /// Override start address (e.g. 0x0800C000)
     #[clap(long, short, value_parser=Self::parse_address, name="address")]
     override_address: Option<u32>,
+
+    /// Override the start address with the one of a memory segment of the device, by name
+    /// (e.g. "@Internal Flash", DfuSe only).
+    #[clap(long, value_name = "name", conflicts_with = "address")]
+    address_of: Option<String>,
+
+    /// Erase the whole flash before writing the firmware (DfuSe only).
+    #[clap(long)]
+    mass_erase: bool,
+
+    /// Read back the firmware after writing it and compare it against the file.
+    #[clap(long)]
+    verify: bool,
+
+    /// Override the transfer size reported by the device.
+    #[clap(long)]
+    transfer_size: Option<u16>,
+
+    /// Wait this many milliseconds between the blocks, a last resort for flaky USB stacks.
+    #[clap(long, value_name = "ms")]
+    inter_block_delay: Option<u64>,
+
+    /// Check the firmware against the device without writing anything.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Stay in DFU mode after the download instead of manifesting the firmware, so that the device
+    /// can still be talked to (e.g. with --verify). Use --reset to start the firmware anyway.
+    #[clap(long)]
+    no_manifest: bool,
+
+    /// Write the DFU suffix of the file along with the firmware instead of stripping it, for the
+    /// bootloaders expecting it.
+    #[clap(long)]
+    no_suffix_strip: bool,
+
+    /// Read the flash back first and only erase and write the pages that changed (DfuSe only).
+    #[clap(long, conflicts_with = "mass_erase")]
+    diff: bool,
+
+    /// Read the write protection of the flash sectors from the option bytes of this alt-setting
+    /// (STM32F2/F4/F7) and refuse to write to the protected ones.
+    #[clap(long, value_name = "alt")]
+    option_bytes_alt: Option<u8>,
+
+    /// Download the file even if its DFU suffix targets another device, if the override address
+    /// is not aligned to a page or if the firmware exceeds its memory segment.
+    #[clap(long)]
+    force: bool,
+
+    /// Print the outcome as a single JSON object to stdout, the messages and the progress bars
+    /// going to stderr.
+    #[clap(long)]
+    json: bool,
+
+    /// Flash the device this many times in a row for burn-in testing: reset it into its
+    /// bootloader through --serial-port, download, verify if asked, start the firmware and wait
+    /// for its serial port to come back, then tally the iterations that passed.
+    #[clap(long, value_name = "N", requires = "serial_port")]
+    count: Option<u32>,
 }
 
 
 
 impl Cli {
-    pub fn run(self) -> Result<()> {
+    /// Set up the logs and the Ctrl-C handler, once for all the iterations. Returns the flag set
+    /// on Ctrl-C.
+    pub fn init(&self) -> Result<Arc<AtomicBool>> {
+        let log_level = if self.verbose > 0 {
+            simplelog::LevelFilter::Trace
+        } else {
+            simplelog::LevelFilter::Info
+        };
+        let mut log_config = simplelog::ConfigBuilder::new();
+        if self.verbose < 2 {
+            log_config.add_filter_ignore_str(PAYLOAD_LOG_TARGET);
+        }
+        if self.json {
+            simplelog::WriteLogger::init(log_level, log_config.build(), std::io::stderr())?;
+        } else {
+            simplelog::SimpleLogger::init(log_level, log_config.build())?;
+        }
+
+        // Bring the device back to dfuIDLE on Ctrl-C instead of dying with it busy, a second
+        // Ctrl-C quits right away.
+        let cancel = Arc::new(AtomicBool::new(false));
+        ctrlc::set_handler({
+            let cancel = cancel.clone();
+            move || {
+                if cancel.swap(true, Ordering::Relaxed) {
+                    std::process::exit(130);
+                }
+            }
+        })
+        .context("could not install the Ctrl-C handler")?;
+        Ok(cancel)
+    }
+
+    /// Run the burn-in test asked with --count, see [`Self::run`] for an iteration.
+    pub fn burn_in(
+        self,
+        out: &mut Output,
+        context: &rusb::Context,
+        cancel: &Arc<AtomicBool>,
+        count: u32,
+    ) -> Result<()> {
+        let mut iterations = Vec::new();
+        let mut failures = 0;
+        for i in 1..=count {
+            if cancel.load(Ordering::Relaxed) {
+                out.note("Cancelled");
+                break;
+            }
+            out.note(format!("Iteration {}/{}", i, count));
+            let start = Instant::now();
+            let res = self.clone().run(out, context, cancel);
+            let duration = start.elapsed();
+            match &res {
+                Ok(()) => out.note(format!("Iteration {} passed in {:.1?}", i, duration)),
+                Err(err) => {
+                    failures += 1;
+                    out.note(format!(
+                        "Iteration {} failed after {:.1?}: {:#}",
+                        i, duration, err
+                    ));
+                }
+            }
+            iterations.push(json!({
+                "iteration": i,
+                "success": res.is_ok(),
+                "duration": duration.as_secs_f64(),
+                "error": res.err().map(|x| format!("{:#}", x)),
+            }));
+        }
+
+        let done = iterations.len() as u32;
+        out.note(format!(
+            "{} passed, {} failed out of {} iterations",
+            done - failures,
+            failures,
+            done
+        ));
+        out.set("iterations", json!(iterations));
+        if failures > 0 {
+            anyhow::bail!("{} of {} iterations failed", failures, done);
+        }
+        Ok(())
+    }
+
+    /// Flash the device once.
+    pub fn run(
+        self,
+        out: &mut Output,
+        context: &rusb::Context,
+        cancel: &Arc<AtomicBool>,
+    ) -> Result<()> {
         let Cli {
             path,
             wait,
             reset,
             serial_port,
+            reset_timeout,
             device,
+            serial,
+            usb_path,
             intf,
             alt,
-            verbose,
+            alt_name,
+            verbose: _,
             override_address,
+            address_of,
+            mass_erase,
+            verify,
+            transfer_size,
+            inter_block_delay,
+            dry_run,
+            no_manifest,
+            no_suffix_strip,
+            diff,
+            option_bytes_alt,
+            force,
+            json: _,
+            count,
         } = self;
-        let log_level = if verbose {
-            simplelog::LevelFilter::Trace
-        } else {
-            simplelog::LevelFilter::Info
+        // Every iteration of a burn-in waits for the bootloader and starts the firmware.
+        let burn_in = count.is_some();
+        let (wait, reset) = (wait || burn_in, reset || burn_in);
+        let wait_timeout = if burn_in { BURN_IN_WAIT } else { Duration::MAX };
+
+        let file = std::fs::File::open(&path).context("could not open firmware file")?;
+        let firmware = Firmware::parse(&path, file).context("could not read firmware file")?;
+        let file_size = match &firmware {
+            Firmware::Segments(segments) => segments.iter().map(|(_, x)| x.len() as u32).sum(),
+            Firmware::Dfu(file) if no_suffix_strip => file.len() + file.suffix_length(),
+            Firmware::Dfu(file) => file.len(),
+            Firmware::DfuSe(file) => file
+                .targets()
+                .iter()
+                .flat_map(|x| x.elements.iter().map(|(_, x)| x.len() as u32))
+                .sum(),
         };
-        simplelog::SimpleLogger::init(log_level, Default::default())?;
-        
+        // Nothing would be written, better not to touch the device at all.
+        if file_size == 0 {
+            return Err(Error::EmptyFirmware).context("could not read firmware file");
+        }
+
         if let Some(serial_port) = &serial_port {
             // println!("Reseting MCU at {serial_port}");
             let bar = indicatif::ProgressBar::new_spinner();
             bar.set_message(format!("Reseting MCU at {serial_port}"));
             bar.tick();
-            match reset_mcu(&serial_port) {
+            match reset_mcu_with(
+                &serial_port,
+                ResetConfig {
+                    timeout: std::time::Duration::from_millis(reset_timeout),
+                    ..ResetConfig::MAPLE
+                },
+            ) {
                 Ok(()) => {
                     for _ in 0..3 {
                         std::thread::sleep(std::time::Duration::from_millis(200));
@@ -90,33 +301,124 @@ impl Cli {
         }
 
         let (vid, pid) = device;
-        let context = rusb::Context::new()?;
-        let mut file = std::fs::File::open(path).context("could not open firmware file")?;
-
-        let file_size = u32::try_from(file.seek(io::SeekFrom::End(0))?)
-            .context("the firmware file is too big")?;
-        file.seek(io::SeekFrom::Start(0))?;
+        let open = || match (&serial, &usb_path, &alt_name) {
+            (Some(serial), _, _) => DfuLibusb::open_by_serial(context, vid, pid, serial, intf, alt),
+            (None, Some((bus, ports)), _) => {
+                DfuLibusb::open_by_path(context, *bus, ports, intf, alt)
+            }
+            (None, None, Some(name)) => DfuLibusb::open_by_alt_name(context, vid, pid, name),
+            (None, None, None) => DfuLibusb::open(context, vid, pid, intf, alt),
+        };
 
-        let mut device: Dfu<rusb::Context> = match DfuLibusb::open(&context, vid, pid, intf, alt) {
-            Err(Error::CouldNotOpenDevice) if wait => {
+        let device: Result<Dfu<rusb::Context>, Error> = match open() {
+            Err(Error::DeviceNotFound) if wait => {
                 let bar = indicatif::ProgressBar::new_spinner();
                 bar.set_message("Waiting for device");
-
-                loop {
-                    std::thread::sleep(std::time::Duration::from_millis(250));
-                    match DfuLibusb::open(&context, vid, pid, intf, alt) {
-                        Err(Error::CouldNotOpenDevice) => bar.tick(),
-                        r => {
-                            bar.finish();
-                            break r;
-                        }
-                    }
-                }
+                bar.enable_steady_tick(100);
+                let res =
+                    DfuLibusb::wait_for_dfu_mode_with(context, vid, pid, wait_timeout, |_| open());
+                bar.finish();
+                res
+            }
+            r => r,
+        };
+        let mut device = match device {
+            Err(Error::DeviceInRuntimeMode) => {
+                let bar = indicatif::ProgressBar::new_spinner();
+                bar.set_message("Detaching device");
+                bar.enable_steady_tick(100);
+                let res = DfuLibusb::open_runtime(context, vid, pid).and_then(|device| {
+                    device.detach_and_reopen(context, intf, alt, Duration::from_secs(5))
+                });
+                bar.finish();
+                res
             }
             r => r,
         }
         .context("could not open device")?;
 
+        let override_address = match &address_of {
+            Some(name) => Some(device.segment_base(name).with_context(|| {
+                format!(
+                    "no memory segment is named {:?} (available: {:?})",
+                    name,
+                    device.segment_names()
+                )
+            })?),
+            None => override_address,
+        };
+
+        device.set_cancel_flag(cancel.clone());
+
+        let version = device.device_version()?;
+        out.note(format!(
+            "Device version: {:x}.{:02x}",
+            version >> 8,
+            version & 0xff
+        ));
+        let speed = device.connection_speed();
+        out.note(format!("Connected at {}", speed));
+        out.set(
+            "device",
+            json!({
+                "vid": vid,
+                "pid": pid,
+                "alt": device.alt_setting(),
+                "version": format!("{:x}.{:02x}", version >> 8, version & 0xff),
+                "speed": speed.to_string(),
+                "max_bit_rate": speed.max_bit_rate(),
+                "transfer_size": device.functional_descriptor().transfer_size(),
+                "manifestation_tolerant": device.manifestation_tolerant(),
+            }),
+        );
+        let suffix = match &firmware {
+            Firmware::Dfu(file) => file.suffix(),
+            Firmware::DfuSe(file) => Some(file.suffix()),
+            Firmware::Segments(_) => None,
+        };
+        if let Some(suffix) = suffix.filter(|x| x.device != 0xffff && x.device < version) {
+            out.note(format!(
+                "Warning: downgrading from {:x}.{:02x} to {:x}.{:02x}",
+                version >> 8,
+                version & 0xff,
+                suffix.device >> 8,
+                suffix.device & 0xff,
+            ));
+        }
+
+        if let Some(alt) = option_bytes_alt {
+            let sectors = device
+                .read_write_protection(alt)
+                .context("could not read the option bytes")?;
+            if !sectors.is_empty() {
+                out.note(format!("Write-protected sectors: {:?}", sectors));
+            }
+            out.set("write_protected_sectors", json!(sectors));
+        }
+
+        // The pages are all erased before writing, so the erase bar is done when the write one
+        // starts.
+        let erase_bar = indicatif::ProgressBar::new(0);
+        erase_bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:27.yellow/blue}] \
+                        {pos}/{len} sectors {msg:10}",
+                )
+                .progress_chars("#>-"),
+        );
+        erase_bar.set_message("Erasing");
+        device.with_erase_progress({
+            let bar = erase_bar.clone();
+            move |sector, total_sectors| {
+                bar.set_length(total_sectors as u64);
+                bar.set_position(sector as u64 + 1);
+                if sector + 1 == total_sectors {
+                    bar.finish_with_message("Erased");
+                }
+            }
+        });
+
         let bar = indicatif::ProgressBar::new(file_size as u64);
         bar.set_style(
             indicatif::ProgressStyle::default_bar()
@@ -127,74 +429,139 @@ impl Cli {
                 .progress_chars("#>-"),
         );
 
-        device.with_progress({
+        device.with_progress_ex({
             let bar = bar.clone();
-            move |count| {
-                bar.inc(count as u64);
-                if bar.position() == file_size as u64 {
-                    bar.finish();
+            move |event| match event {
+                ProgressEvent::Downloading { bytes } => {
+                    bar.set_message("Writing");
+                    bar.inc(bytes as u64);
                 }
+                ProgressEvent::Manifesting => bar.set_message("Manifesting"),
+                ProgressEvent::Done { success: true } => bar.finish_with_message("Done"),
+                ProgressEvent::Done { success: false } => bar.abandon_with_message("Failed"),
+                ProgressEvent::Erasing { .. } | ProgressEvent::Uploading { .. } => {}
             }
         });
 
+        // Leave DFU mode at the start of the firmware unless told otherwise.
+        let override_address = match &firmware {
+            Firmware::Segments(segments) => override_address.or(segments.first().map(|x| x.0)),
+            Firmware::Dfu(_) | Firmware::DfuSe(_) => override_address,
+        };
         if let Some(address) = override_address {
             device.override_address(address);
         }
 
-        match device.download(file, file_size) {
-            Ok(_) => (),
-            Err(Error::LibUsb(e)) if bar.is_finished() => {
+        if let Some(transfer_size) = transfer_size {
+            device
+                .set_transfer_size(transfer_size)
+                .context("invalid transfer size")?;
+        }
 
-                device.usb_reset()?;
+        if let Some(delay) = inter_block_delay {
+            device.set_inter_block_delay(Duration::from_millis(delay));
+        }
 
-                if let Some(serial_port) = &serial_port {
+        device
+            .set_dry_run(dry_run)
+            .set_manifest_on_download(!no_manifest)
+            .set_strip_suffix(!no_suffix_strip)
+            .set_ignore_suffix_mismatch(force)
+            .set_allow_unaligned_address(force)
+            .set_ignore_segment_bounds(force);
 
-                    let bar = indicatif::ProgressBar::new_spinner();
-                    bar.set_message(format!("Waiting for {serial_port} to come up "));
-                    for _ in 0..20 {
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                        bar.tick();
+        #[cfg(feature = "sha256")]
+        device.set_digest(Some(DigestAlgorithm::Sha256));
+        #[cfg(not(feature = "sha256"))]
+        device.set_digest(Some(DigestAlgorithm::Crc32));
 
-                        if let Ok(r) = serialport::available_ports() {
-                            let r :Vec<String> = r.into_iter().map(|i| i.port_name).collect();
-                            if r.contains(&serial_port) {
-                                bar.set_message(format!("MCU at {serial_port} is back online"));
-                                bar.finish();
-                                return Ok(());
-                            }
-                        }
-                    }
+        if mass_erase {
+            let bar = indicatif::ProgressBar::new_spinner();
+            bar.set_message("Erasing flash");
+            bar.enable_steady_tick(100);
+            device.mass_erase().context("could not erase the device")?;
+            bar.finish();
+        }
+
+        let (res, segments) = match firmware {
+            Firmware::Dfu(_) if diff && no_suffix_strip => {
+                // The file is compared verbatim.
+                let file = std::fs::File::open(&path).context("could not open firmware file")?;
+                (device.download_diff(file, file_size), None)
+            }
+            Firmware::Dfu(file) if diff => (device.download_diff(file, file_size), None),
+            Firmware::Dfu(file) => (device.download_file(file), None),
+            Firmware::Segments(segments) => (device.download_segments(&segments), Some(segments)),
+            Firmware::DfuSe(file) => {
+                // Every image is written, then the device leaves DFU mode.
+                let reports = device
+                    .download_dfuse_file(&file)
+                    .context("could not write firmware to the device")?;
+                if verify && no_manifest {
+                    out.note("Verifying DfuSe files is not supported");
+                } else if verify {
+                    out.note("The device left DFU mode after the download, it can't be verified");
                 }
-                println!("USB error after upload; Device reset itself? {e}");
-                return Ok(());
-            },
-            Err(Error::Dfu(dfu_core::Error::InvalidState { got: dfu_core::State::DfuManifestWaitReset, expected: _ })) if bar.is_finished() => {
-                let _ = device.usb_reset();
-
-                if let Some(serial_port) = &serial_port {
-
-                    let bar = indicatif::ProgressBar::new_spinner();
-                    bar.set_message(format!("Waiting for {serial_port} to come up "));
-                    for _ in 0..20 {
-                        std::thread::sleep(std::time::Duration::from_millis(100));
-                        bar.tick();
+                reports.iter().for_each(|x| out.report(x));
+                return wait_for_app(serial_port.as_deref(), burn_in);
+            }
+        };
+        let report = res.context("could not write firmware to the device")?;
 
-                        if let Ok(r) = serialport::available_ports() {
-                            let r :Vec<String> = r.into_iter().map(|i| i.port_name).collect();
-                            if r.contains(&serial_port) {
-                                bar.set_message(format!("MCU at {serial_port} is back online"));
-                                bar.finish();
-                                return Ok(());
-                            }
-                        }
-                    }
+        if !device.manifestation_tolerant() && !dry_run && !no_manifest {
+            // The device has been reset to run the new firmware, it can't be talked to anymore.
+            if verify {
+                out.note("The device reset itself after the download, it can't be verified");
+            }
+            out.report(&report);
+            return wait_for_app(serial_port.as_deref(), burn_in);
+        }
+
+        if verify {
+            let bar = indicatif::ProgressBar::new(file_size as u64);
+            bar.set_style(
+                indicatif::ProgressStyle::default_bar()
+                    .template(
+                        "{spinner:.green} [{elapsed_precise}] [{bar:27.cyan/blue}] \
+                            {bytes}/{total_bytes} ({bytes_per_sec}) ({eta}) {msg:10}",
+                    )
+                    .progress_chars("#>-"),
+            );
+            bar.set_message("Verifying");
+            // Every segment is verified separately, so the bar is finished once they all are.
+            device.with_progress_ex({
+                let bar = bar.clone();
+                move |event| match event {
+                    ProgressEvent::Uploading { bytes } => bar.inc(bytes as u64),
+                    ProgressEvent::Done { success: false } => bar.abandon_with_message("Failed"),
+                    _ => {}
+                }
+            });
+
+            if let Some(segments) = &segments {
+                for (address, data) in segments {
+                    device
+                        .override_address(*address)
+                        .verify(&data[..], data.len() as u32)
+                        .context("could not verify the firmware")?;
+                }
+                if let Some(address) = override_address {
+                    device.override_address(address);
                 }
-                println!("Unexpected DfuManifestWaitReset could not be handled");
-                return Ok(());
+            } else if no_suffix_strip {
+                // The file has been written verbatim.
+                let file = std::fs::File::open(&path).context("could not open firmware file")?;
+                device
+                    .verify(file, file_size)
+                    .context("could not verify the firmware")?;
+            } else {
+                let file = std::fs::File::open(&path).context("could not open firmware file")?;
+                let file = DfuFile::parse(file).context("could not read firmware file")?;
+                device
+                    .verify(file, file_size)
+                    .context("could not verify the firmware")?;
             }
-            e => {
-                return e.context("could not write firmware to the device")
-            },
+            bar.finish_with_message("Verified");
         }
 
         if reset {
@@ -204,10 +571,22 @@ impl Cli {
             //
             // This matches the behaviour of dfu-util so should be safe
             // let _ = device.detach();
-            println!("Resetting device");
-            device.usb_reset()?;
+            match device.leave() {
+                Err(Error::DfuseUnsupported) => {
+                    out.note("Resetting device");
+                    device.usb_reset()?;
+                }
+                res => {
+                    res.context("could not leave DFU mode")?;
+                    out.note("Leaving DFU mode");
+                }
+            }
+            out.report(&report);
+            return wait_for_app(serial_port.as_deref(), burn_in);
         }
 
+        out.report(&report);
+
         Ok(())
     }
 
@@ -221,6 +600,19 @@ impl Cli {
         Ok((vid, pid))
     }
 
+    pub fn parse_usb_path(s: &str) -> Result<(u8, Vec<u8>)> {
+        let (bus, ports) = s
+            .split_once('-')
+            .context("could not parse USB path (missing `-')")?;
+        let bus = bus.parse().context("could not parse bus number")?;
+        let ports = ports
+            .split('.')
+            .map(|x| x.parse().context("could not parse port number"))
+            .collect::<Result<_>>()?;
+
+        Ok((bus, ports))
+    }
+
     pub fn parse_address(s: &str) -> Result<u32> {
         if s.to_ascii_lowercase().starts_with("0x") {
             u32::from_str_radix(&s[2..], 16).context("could not parse override address")
@@ -230,6 +622,174 @@ impl Cli {
     }
 }
 
+/// Wait for the application to bring its serial port back once it is started, which fails a
+/// burn-in iteration if it doesn't.
+fn wait_for_app(serial_port: Option<&str>, burn_in: bool) -> Result<()> {
+    match serial_port {
+        Some(serial_port) if !wait_for_serial_port(serial_port) && burn_in => {
+            anyhow::bail!("{} did not come back", serial_port)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn wait_for_serial_port(serial_port: &str) -> bool {
+    let bar = indicatif::ProgressBar::new_spinner();
+    bar.set_message(format!("Waiting for {serial_port} to come up"));
+    for _ in 0..20 {
+        std::thread::sleep(Duration::from_millis(100));
+        bar.tick();
+
+        if let Ok(ports) = serialport::available_ports() {
+            if ports.iter().any(|x| x.port_name == serial_port) {
+                bar.finish_with_message(format!("MCU at {serial_port} is back online"));
+                return true;
+            }
+        }
+    }
+    bar.finish_with_message(format!("{serial_port} did not come back"));
+    false
+}
+
+fn print_report(report: &DownloadReport) {
+    if let Some(digest) = report.digest {
+        println!(
+            "Wrote {} bytes in {:.1?} ({})",
+            report.bytes_written, report.duration, digest
+        );
+    }
+    println!(
+        "Waited {:.1?} for the device and {:.1?} for the transfers",
+        report.total_poll_wait, report.transfer_time
+    );
+    if report.pages_skipped > 0 {
+        println!("Skipped {} unchanged pages", report.pages_skipped);
+    }
+    if !report.total_inter_block_delay.is_zero() {
+        println!(
+            "Waited {:.1?} between the blocks",
+            report.total_inter_block_delay
+        );
+    }
+}
+
+/// Where the results go: printed as they come, or gathered into a single JSON object printed to
+/// stdout at the end with `--json`, in which case the messages go to stderr.
+pub struct Output {
+    fields: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl Output {
+    fn new(json: bool) -> Self {
+        Self {
+            fields: json.then(serde_json::Map::new),
+        }
+    }
+
+    /// Print a message meant for humans.
+    fn note(&self, message: impl std::fmt::Display) {
+        if self.fields.is_some() {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+
+    /// Set a field of the JSON object.
+    fn set(&mut self, key: &str, value: serde_json::Value) {
+        if let Some(fields) = &mut self.fields {
+            fields.insert(key.to_string(), value);
+        }
+    }
+
+    fn report(&mut self, report: &DownloadReport) {
+        let fields = match &mut self.fields {
+            Some(x) => x,
+            None => return print_report(report),
+        };
+        let report = json!({
+            "bytes_written": report.bytes_written,
+            "duration": report.duration.as_secs_f64(),
+            "digest": report.digest.map(|x| x.to_string()),
+            "total_poll_wait": report.total_poll_wait.as_secs_f64(),
+            "transfer_time": report.transfer_time.as_secs_f64(),
+            "total_inter_block_delay": report.total_inter_block_delay.as_secs_f64(),
+            "pages_skipped": report.pages_skipped,
+        });
+        if let Some(reports) = fields
+            .entry("reports")
+            .or_insert_with(|| json!([]))
+            .as_array_mut()
+        {
+            reports.push(report);
+        }
+    }
+
+    /// Print the JSON object with the outcome of the run, if enabled.
+    fn finish(self, res: &Result<()>) {
+        let mut fields = match self.fields {
+            Some(x) => x,
+            None => return,
+        };
+        fields.insert("success".to_string(), json!(res.is_ok()));
+        if let Err(err) = res {
+            fields.insert(
+                "error".to_string(),
+                json!({ "kind": error_kind(err), "message": format!("{:#}", err) }),
+            );
+        }
+        println!("{}", serde_json::Value::Object(fields));
+    }
+}
+
+/// Name of the variant of the error of the library behind `err`, if any, e.g. "DeviceNotFound".
+fn error_kind(err: &anyhow::Error) -> Option<String> {
+    let debug = err.chain().find_map(|x| {
+        x.downcast_ref::<Error>()
+            .map(|x| format!("{:?}", x))
+            .or_else(|| x.downcast_ref::<VerifyError>().map(|x| format!("{:?}", x)))
+    })?;
+    debug
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .map(ToString::to_string)
+}
+
+enum Firmware {
+    Dfu(DfuFile<std::fs::File>),
+    /// Data to write at specific addresses, from a HEX or ELF file.
+    Segments(Vec<(u32, Vec<u8>)>),
+    /// Images for several alt-settings, from a DfuSe file.
+    DfuSe(DfuSeFile),
+}
+
+impl Firmware {
+    fn parse(path: &std::path::Path, mut file: std::fs::File) -> Result<Self, Error> {
+        let extension = path.extension().and_then(|x| x.to_str());
+        match detect_format(&mut file, extension)? {
+            FirmwareFormat::Raw | FirmwareFormat::Dfu => Ok(Self::Dfu(DfuFile::parse(file)?)),
+            FirmwareFormat::DfuSe => Ok(Self::DfuSe(DfuSeFile::parse(file)?)),
+            FirmwareFormat::Hex => Ok(Self::Segments(HexFile::parse(file)?.into_segments())),
+            #[cfg(feature = "elf")]
+            FirmwareFormat::Elf => Ok(Self::Segments(ElfImage::load_segments(file)?)),
+            #[cfg(not(feature = "elf"))]
+            FirmwareFormat::Elf => Err(Error::FormatUnsupported(FirmwareFormat::Elf)),
+        }
+    }
+}
+
 fn main() -> Result<()> {
-    <Cli as clap::Parser>::from_args().run()
+    let cli = <Cli as clap::Parser>::from_args();
+    let mut out = Output::new(cli.json);
+    // The context is shared by all the iterations of a burn-in.
+    let res = cli.init().and_then(|cancel| {
+        let context = rusb::Context::new()?;
+        let count = cli.count;
+        match count {
+            Some(count) => cli.burn_in(&mut out, &context, &cancel, count),
+            None => cli.run(&mut out, &context, &cancel),
+        }
+    });
+    out.finish(&res);
+    res
 }
@@ -0,0 +1,34 @@
+use anyhow::{Context, Result};
+use dfu_libusb::*;
+use std::path::PathBuf;
+
+/// Download a firmware with the defaults of [`flash`], see the `download` example for all the
+/// options.
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Path to the firmware file to write to the device.
+    path: PathBuf,
+
+    /// Reset the application through this serial port first.
+    #[clap(short, long)]
+    serial_port: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let Cli { path, serial_port } = <Cli as clap::Parser>::from_args();
+    simplelog::SimpleLogger::init(simplelog::LevelFilter::Info, Default::default())?;
+
+    let context = rusb::Context::new()?;
+    let mut options = FlashOptions::new(0x1eaf, 0x0003, path);
+    options.alt = 2;
+    options.serial_port = serial_port;
+    options.reset = true;
+    options.progress = Some(Box::new(|event| log::debug!("{:?}", event)));
+    let report = flash(&context, options).context("could not flash the device")?;
+
+    println!(
+        "Wrote {} bytes in {:.1?}",
+        report.bytes_written, report.duration
+    );
+    Ok(())
+}
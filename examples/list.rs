@@ -0,0 +1,28 @@
+use anyhow::Result;
+use dfu_libusb::*;
+
+fn main() -> Result<()> {
+    let context = rusb::Context::new()?;
+
+    for device in DfuLibusb::enumerate(&context)? {
+        for alt_setting in &device.alt_settings {
+            println!(
+                "Found DFU: [{:04x}:{:04x}] bus={}, devnum={}, path={}, intf={}, alt={}, name={:?}, \
+                    manufacturer={:?}, product={:?}, serial={:?}",
+                device.vid,
+                device.pid,
+                device.bus,
+                device.address,
+                device.path(),
+                alt_setting.intf,
+                alt_setting.alt,
+                alt_setting.name.as_deref().unwrap_or("UNKNOWN"),
+                device.manufacturer.as_deref().unwrap_or("UNKNOWN"),
+                device.product.as_deref().unwrap_or("UNKNOWN"),
+                device.serial.as_deref().unwrap_or("UNKNOWN"),
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use dfu_libusb::*;
+
+/// Check that a device can be used from this machine, without writing anything to it.
+///
+/// Run this first when nothing works: every step is reported with what to do when it fails.
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Specify Vendor/Product ID(s) of DFU device.
+    #[clap(
+        long,
+        short,
+        parse(try_from_str = Self::parse_vid_pid), name = "vendor>:<product",
+        default_value = "1EAF:0003",
+    )]
+    device: (u16, u16),
+
+    /// Enable verbose logs.
+    #[clap(long, short)]
+    verbose: bool,
+}
+
+impl Cli {
+    pub fn run(self) -> Result<()> {
+        let Cli { device, verbose } = self;
+        let log_level = if verbose {
+            simplelog::LevelFilter::Trace
+        } else {
+            simplelog::LevelFilter::Info
+        };
+        simplelog::SimpleLogger::init(log_level, Default::default())?;
+
+        let (vid, pid) = device;
+        let context = rusb::Context::new().context("could not initialize libusb")?;
+
+        let mut passed = true;
+        for step in DfuLibusb::probe(&context, vid, pid) {
+            match &step.result {
+                Ok(found) => println!("[ OK ] {}: {}", step.description, found),
+                Err(err) => {
+                    passed = false;
+                    println!("[FAIL] {}: {}", step.description, err);
+                    println!("       {}", Self::advice(err));
+                }
+            }
+        }
+
+        if !passed {
+            anyhow::bail!("the self-test failed");
+        }
+        println!("The device is ready to be flashed");
+        Ok(())
+    }
+
+    /// What to try when a step fails.
+    fn advice(err: &Error) -> &'static str {
+        match err {
+            Error::DeviceNotFound => {
+                "Check the cable and the IDs given with --device, and that the bootloader runs."
+            }
+            Error::MultipleDevices(_) => "Unplug the other devices with the same IDs.",
+            Error::AccessDenied => {
+                "Install a udev rule granting access to the device, or run as root."
+            }
+            Error::InterfaceBusy { .. } => {
+                "Unbind the kernel driver holding the interface, the download does it by default."
+            }
+            Error::DeviceInRuntimeMode => {
+                "The device runs its application: reset it into its bootloader first."
+            }
+            Error::MissingLanguage | Error::NoDfuCapableDeviceFound => {
+                "The device does not look like a DFU bootloader."
+            }
+            Error::LibUsb(rusb::Error::NotSupported) => {
+                "Install a driver libusb can use for the device, e.g. WinUSB on Windows."
+            }
+            Error::LibUsb(_) => "Check the cable and the USB port, or try another one.",
+            _ => "The descriptors of the device could not be understood.",
+        }
+    }
+
+    pub fn parse_vid_pid(s: &str) -> Result<(u16, u16)> {
+        let (vid, pid) = s
+            .split_once(':')
+            .context("could not parse VID/PID (missing `:')")?;
+        let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+        let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+        Ok((vid, pid))
+    }
+}
+
+fn main() -> Result<()> {
+    <Cli as clap::Parser>::from_args().run()
+}
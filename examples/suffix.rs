@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use dfu_libusb::*;
+use std::path::PathBuf;
+
+/// Check the DFU suffix of a firmware file, like `dfu-suffix -c`.
+///
+/// The exit code is 1 if the file has no valid suffix or if its CRC is wrong.
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Path to the firmware file to check.
+    path: PathBuf,
+}
+
+impl Cli {
+    pub fn run(self) -> Result<bool> {
+        let Cli { path } = self;
+        let bytes = std::fs::read(&path).context("could not read firmware file")?;
+
+        let suffix = match DfuSuffix::from_bytes(&bytes) {
+            Some(x) => x,
+            None => {
+                println!("Signature: FAIL (no DFU suffix found)");
+                return Ok(false);
+            }
+        };
+        println!("Signature: PASS");
+        println!("Vendor ID: {:04x}", suffix.vendor);
+        println!("Product ID: {:04x}", suffix.product);
+        println!("Device release (bcdDevice): {:04x}", suffix.device);
+        println!(
+            "DFU version (bcdDFU): {:x}.{:02x}",
+            suffix.dfu_version >> 8,
+            suffix.dfu_version & 0xff
+        );
+
+        match DfuFile::parse(std::io::Cursor::new(&bytes)) {
+            Ok(file) if file.suffix().is_none() => {
+                println!("CRC: FAIL (invalid suffix length)");
+                Ok(false)
+            }
+            Ok(file) => {
+                println!("Payload: {} bytes", file.len());
+                println!("CRC: PASS");
+                Ok(true)
+            }
+            Err(Error::SuffixCrcMismatch { expected, got }) => {
+                println!(
+                    "CRC: FAIL (stored: {:08x}, computed: {:08x})",
+                    expected, got
+                );
+                Ok(false)
+            }
+            Err(err) => Err(err).context("could not parse firmware file"),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    if !<Cli as clap::Parser>::from_args().run()? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
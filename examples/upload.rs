@@ -0,0 +1,246 @@
+use anyhow::{Context, Result};
+use dfu_libusb::*;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(clap::Parser)]
+pub struct Cli {
+    /// Path to the file where the firmware read from the device is written.
+    ///
+    /// A DFU suffix targeting the device is appended if the file name ends with `.dfu`.
+    path: PathBuf,
+
+    /// Number of bytes to read from the device.
+    #[clap(long, short, value_parser=Self::parse_address)]
+    length: u32,
+
+    /// Wait for the device to appear.
+    #[clap(short, long)]
+    wait: bool,
+
+    /// Reset serial port
+    #[clap(short, long)]
+    serial_port: Option<String>,
+
+    /// Give up the serial reset if the port does not respond within this many milliseconds.
+    #[clap(long, default_value = "2000")]
+    reset_timeout: u64,
+
+    /// Specify Vendor/Product ID(s) of DFU device.
+    #[clap(
+        long,
+        short,
+        parse(try_from_str = Self::parse_vid_pid), name = "vendor>:<product",
+        default_value = "1EAF:0003",
+    )]
+    device: (u16, u16),
+
+    /// Specify the USB path of the DFU device (e.g. 1-4.2 for bus 1, port 2 of the hub on port 4).
+    #[clap(long = "path", value_parser = Self::parse_usb_path, value_name = "bus-port.port")]
+    usb_path: Option<(u8, Vec<u8>)>,
+
+    /// Specify the DFU Interface number.
+    #[clap(long, short, default_value = "0")]
+    intf: u8,
+
+    /// Specify the Altsetting of the DFU Interface by number.
+    #[clap(long, short, default_value = "2")]
+    alt: u8,
+
+    /// Enable verbose logs.
+    #[clap(long, short)]
+    verbose: bool,
+
+    /// Start address of the upload on DfuSe devices (e.g. 0x08000000)
+    #[clap(long, value_parser=Self::parse_address, name="address")]
+    address: Option<u32>,
+}
+
+impl Cli {
+    pub fn run(self) -> Result<()> {
+        let Cli {
+            path,
+            length,
+            wait,
+            serial_port,
+            reset_timeout,
+            device,
+            usb_path,
+            intf,
+            alt,
+            verbose,
+            address,
+        } = self;
+        let log_level = if verbose {
+            simplelog::LevelFilter::Trace
+        } else {
+            simplelog::LevelFilter::Info
+        };
+        simplelog::SimpleLogger::init(log_level, Default::default())?;
+
+        if let Some(serial_port) = &serial_port {
+            let bar = indicatif::ProgressBar::new_spinner();
+            bar.set_message(format!("Reseting MCU at {serial_port}"));
+            bar.tick();
+            match reset_mcu_with(
+                serial_port,
+                ResetConfig {
+                    timeout: std::time::Duration::from_millis(reset_timeout),
+                    ..ResetConfig::MAPLE
+                },
+            ) {
+                Ok(()) => {
+                    for _ in 0..3 {
+                        std::thread::sleep(std::time::Duration::from_millis(200));
+                        bar.tick();
+                    }
+                }
+                Err(e) => {
+                    bar.set_message(format!("Failed to reset MCU at {serial_port}: {e}"));
+                }
+            }
+            bar.finish();
+        }
+
+        let (vid, pid) = device;
+        let context = rusb::Context::new()?;
+        let mut file = std::fs::File::create(&path).context("could not create output file")?;
+
+        let open = || match &usb_path {
+            Some((bus, ports)) => DfuLibusb::open_by_path(&context, *bus, ports, intf, alt),
+            None => DfuLibusb::open(&context, vid, pid, intf, alt),
+        };
+
+        let mut device: Dfu<rusb::Context> = match open() {
+            Err(Error::DeviceNotFound) if wait => {
+                let bar = indicatif::ProgressBar::new_spinner();
+                bar.set_message("Waiting for device");
+
+                loop {
+                    std::thread::sleep(std::time::Duration::from_millis(250));
+                    match open() {
+                        Err(Error::DeviceNotFound) => bar.tick(),
+                        r => {
+                            bar.finish();
+                            break r;
+                        }
+                    }
+                }
+            }
+            r => r,
+        }
+        .context("could not open device")?;
+
+        // Bring the device back to dfuIDLE on Ctrl-C instead of dying with it busy, a second
+        // Ctrl-C quits right away.
+        let cancel = Arc::new(AtomicBool::new(false));
+        ctrlc::set_handler({
+            let cancel = cancel.clone();
+            move || {
+                if cancel.swap(true, Ordering::Relaxed) {
+                    std::process::exit(130);
+                }
+            }
+        })
+        .context("could not install the Ctrl-C handler")?;
+        device.set_cancel_flag(cancel);
+
+        let bar = indicatif::ProgressBar::new(length as u64);
+        bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:27.cyan/blue}] \
+                        {bytes}/{total_bytes} ({bytes_per_sec}) ({eta}) {msg:10}",
+                )
+                .progress_chars("#>-"),
+        );
+
+        device.with_progress_ex({
+            let bar = bar.clone();
+            move |event| match event {
+                ProgressEvent::Uploading { bytes } => bar.inc(bytes as u64),
+                ProgressEvent::Done { success: true } => {
+                    // The device may have fewer bytes than requested.
+                    bar.set_length(bar.position());
+                    bar.finish();
+                }
+                ProgressEvent::Done { success: false } => bar.abandon(),
+                _ => {}
+            }
+        });
+
+        if let Some(address) = address {
+            device.override_address(address);
+        }
+
+        let with_suffix = path
+            .extension()
+            .map_or(false, |x| x.eq_ignore_ascii_case("dfu"));
+        let mut firmware = Vec::new();
+        let read = device
+            .upload(&mut firmware, length)
+            .context("could not read firmware from the device")?;
+
+        if with_suffix {
+            let descriptor = device.device_descriptor()?;
+            let version = descriptor.device_version();
+            let bcd_device = (version.major() as u16) << 8
+                | (version.minor() as u16) << 4
+                | version.sub_minor() as u16;
+            DfuFile::write_with_suffix(
+                &mut file,
+                &firmware,
+                descriptor.vendor_id(),
+                descriptor.product_id(),
+                bcd_device,
+            )
+            .context("could not write output file")?;
+        } else {
+            file.write_all(&firmware)
+                .context("could not write output file")?;
+        }
+
+        if read < length {
+            println!("The device returned {read} bytes out of {length}");
+        }
+
+        Ok(())
+    }
+
+    pub fn parse_vid_pid(s: &str) -> Result<(u16, u16)> {
+        let (vid, pid) = s
+            .split_once(':')
+            .context("could not parse VID/PID (missing `:')")?;
+        let vid = u16::from_str_radix(vid, 16).context("could not parse VID")?;
+        let pid = u16::from_str_radix(pid, 16).context("could not parse PID")?;
+
+        Ok((vid, pid))
+    }
+
+    pub fn parse_usb_path(s: &str) -> Result<(u8, Vec<u8>)> {
+        let (bus, ports) = s
+            .split_once('-')
+            .context("could not parse USB path (missing `-')")?;
+        let bus = bus.parse().context("could not parse bus number")?;
+        let ports = ports
+            .split('.')
+            .map(|x| x.parse().context("could not parse port number"))
+            .collect::<Result<_>>()?;
+
+        Ok((bus, ports))
+    }
+
+    pub fn parse_address(s: &str) -> Result<u32> {
+        if s.to_ascii_lowercase().starts_with("0x") {
+            u32::from_str_radix(&s[2..], 16).context("could not parse number")
+        } else {
+            s.parse().context("could not parse number")
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    <Cli as clap::Parser>::from_args().run()
+}
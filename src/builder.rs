@@ -0,0 +1,308 @@
+use crate::{
+    DfuSync, DigestAlgorithm, Error, FunctionalDescriptorOverrides, MemoryLayout, ProgressEvent,
+};
+use dfu_core::DfuIo;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Settings to apply to a [`DfuSync`] at once, see [`DfuBuilder::build`].
+///
+/// Every setting left untouched keeps the value of the [`DfuSync`] given to [`DfuBuilder::build`],
+/// the default unless set before. The mutating methods of [`DfuSync`] can still be used on the
+/// result.
+#[derive(Default)]
+pub struct DfuBuilder {
+    override_address: Option<u32>,
+    memory_layout: Option<MemoryLayout>,
+    ignore_segment_bounds: Option<bool>,
+    ignore_suffix_mismatch: Option<bool>,
+    allow_unaligned_address: Option<bool>,
+    write_protected_sectors: Option<Vec<u32>>,
+    descriptor_overrides: Option<FunctionalDescriptorOverrides>,
+    busy_timeout_factor: Option<u32>,
+    max_poll_iterations: Option<u32>,
+    poll_timeout_min: Option<Duration>,
+    poll_timeout_cap: Option<Duration>,
+    inter_block_delay: Option<Duration>,
+    retries: Option<u32>,
+    transfer_size: Option<u16>,
+    transfer_size_fallback: Option<Option<u16>>,
+    operation_timeout: Option<Duration>,
+    dry_run: Option<bool>,
+    strip_suffix: Option<bool>,
+    manifest_on_download: Option<bool>,
+    recover_state: Option<bool>,
+    read_status_string: Option<bool>,
+    digest: Option<DigestAlgorithm>,
+    read_memory_limit: Option<u32>,
+    cancel_flag: Option<Arc<AtomicBool>>,
+    progress: Option<Box<dyn FnMut(ProgressEvent)>>,
+    progress_interval: Option<Duration>,
+    erase_progress: Option<Box<dyn FnMut(u32, u32)>>,
+}
+
+impl DfuBuilder {
+    /// Create a builder with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`DfuSync::override_address`].
+    pub fn override_address(mut self, address: u32) -> Self {
+        self.override_address = Some(address);
+        self
+    }
+
+    /// See [`DfuSync::set_memory_layout`].
+    pub fn memory_layout(mut self, memory_layout: MemoryLayout) -> Self {
+        self.memory_layout = Some(memory_layout);
+        self
+    }
+
+    /// See [`DfuSync::set_ignore_segment_bounds`].
+    pub fn ignore_segment_bounds(mut self, ignore: bool) -> Self {
+        self.ignore_segment_bounds = Some(ignore);
+        self
+    }
+
+    /// See [`DfuSync::set_ignore_suffix_mismatch`].
+    pub fn ignore_suffix_mismatch(mut self, ignore: bool) -> Self {
+        self.ignore_suffix_mismatch = Some(ignore);
+        self
+    }
+
+    /// See [`DfuSync::set_allow_unaligned_address`].
+    pub fn allow_unaligned_address(mut self, allow: bool) -> Self {
+        self.allow_unaligned_address = Some(allow);
+        self
+    }
+
+    /// See [`DfuSync::set_write_protected_sectors`].
+    pub fn write_protected_sectors(mut self, sectors: Vec<u32>) -> Self {
+        self.write_protected_sectors = Some(sectors);
+        self
+    }
+
+    /// Override fields of the functional descriptor of the device, for the known-quirky clones.
+    /// See [`DfuSync::set_functional_descriptor_overrides`]: the fields left to `None` keep the
+    /// values forced before, or those of the device.
+    pub fn force_descriptor(mut self, overrides: FunctionalDescriptorOverrides) -> Self {
+        self.descriptor_overrides =
+            Some(overrides.or(self.descriptor_overrides.unwrap_or_default()));
+        self
+    }
+
+    /// See [`DfuSync::set_busy_timeout_factor`].
+    pub fn busy_timeout_factor(mut self, factor: u32) -> Self {
+        self.busy_timeout_factor = Some(factor);
+        self
+    }
+
+    /// See [`DfuSync::set_max_poll_iterations`].
+    pub fn max_poll_iterations(mut self, count: u32) -> Self {
+        self.max_poll_iterations = Some(count);
+        self
+    }
+
+    /// See [`DfuSync::set_poll_timeout_min`].
+    pub fn poll_timeout_min(mut self, min: Duration) -> Self {
+        self.poll_timeout_min = Some(min);
+        self
+    }
+
+    /// See [`DfuSync::set_poll_timeout_cap`].
+    pub fn poll_timeout_cap(mut self, max: Duration) -> Self {
+        self.poll_timeout_cap = Some(max);
+        self
+    }
+
+    /// See [`DfuSync::set_inter_block_delay`].
+    pub fn inter_block_delay(mut self, delay: Duration) -> Self {
+        self.inter_block_delay = Some(delay);
+        self
+    }
+
+    /// See [`DfuSync::set_retries`].
+    pub fn retries(mut self, count: u32) -> Self {
+        self.retries = Some(count);
+        self
+    }
+
+    /// See [`DfuSync::set_transfer_size`]. The size is checked against the device by
+    /// [`Self::build`].
+    pub fn transfer_size(mut self, size: u16) -> Self {
+        self.transfer_size = Some(size);
+        self
+    }
+
+    /// See [`DfuSync::set_transfer_size_fallback`].
+    pub fn transfer_size_fallback(mut self, fallback: Option<u16>) -> Self {
+        self.transfer_size_fallback = Some(fallback);
+        self
+    }
+
+    /// See [`DfuSync::set_operation_timeout`].
+    pub fn operation_timeout(mut self, timeout: Duration) -> Self {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`DfuSync::set_dry_run`].
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = Some(dry_run);
+        self
+    }
+
+    /// See [`DfuSync::set_strip_suffix`].
+    pub fn strip_suffix(mut self, strip: bool) -> Self {
+        self.strip_suffix = Some(strip);
+        self
+    }
+
+    /// See [`DfuSync::set_manifest_on_download`].
+    pub fn manifest_on_download(mut self, manifest: bool) -> Self {
+        self.manifest_on_download = Some(manifest);
+        self
+    }
+
+    /// See [`DfuSync::set_recover_state`].
+    pub fn recover_state(mut self, recover: bool) -> Self {
+        self.recover_state = Some(recover);
+        self
+    }
+
+    /// See [`DfuSync::set_digest`].
+    pub fn digest(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.digest = Some(algorithm);
+        self
+    }
+
+    /// See [`DfuSync::set_read_memory_limit`].
+    pub fn read_memory_limit(mut self, limit: u32) -> Self {
+        self.read_memory_limit = Some(limit);
+        self
+    }
+
+    /// See [`DfuSync::set_cancel_flag`].
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel_flag = Some(cancel);
+        self
+    }
+
+    /// See [`DfuSync::set_read_status_string`].
+    pub fn read_status_string(mut self, read: bool) -> Self {
+        self.read_status_string = Some(read);
+        self
+    }
+
+    /// See [`DfuSync::with_progress_ex`].
+    pub fn progress(mut self, progress: impl FnMut(ProgressEvent) + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// See [`DfuSync::set_progress_interval`].
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = Some(interval);
+        self
+    }
+
+    /// See [`DfuSync::with_erase_progress`].
+    pub fn erase_progress(mut self, progress: impl FnMut(u32, u32) + 'static) -> Self {
+        self.erase_progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Apply the settings to an opened device.
+    ///
+    /// An error is returned, and the device dropped, if a setting is not supported by the device.
+    pub fn build<IO>(self, mut dfu: DfuSync<IO>) -> Result<DfuSync<IO>, Error>
+    where
+        IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = Error>,
+    {
+        if let Some(overrides) = self.descriptor_overrides {
+            dfu.set_functional_descriptor_overrides(overrides);
+        }
+        if let Some(size) = self.transfer_size {
+            dfu.set_transfer_size(size)?;
+        }
+        if let Some(fallback) = self.transfer_size_fallback {
+            dfu.set_transfer_size_fallback(fallback);
+        }
+        if let Some(address) = self.override_address {
+            dfu.override_address(address);
+        }
+        if let Some(memory_layout) = self.memory_layout {
+            dfu.set_memory_layout(memory_layout);
+        }
+        if let Some(factor) = self.busy_timeout_factor {
+            dfu.set_busy_timeout_factor(factor);
+        }
+        if let Some(count) = self.max_poll_iterations {
+            dfu.set_max_poll_iterations(count);
+        }
+        if let Some(min) = self.poll_timeout_min {
+            dfu.set_poll_timeout_min(min);
+        }
+        if let Some(max) = self.poll_timeout_cap {
+            dfu.set_poll_timeout_cap(max);
+        }
+        if let Some(delay) = self.inter_block_delay {
+            dfu.set_inter_block_delay(delay);
+        }
+        if let Some(count) = self.retries {
+            dfu.set_retries(count);
+        }
+        if let Some(timeout) = self.operation_timeout {
+            dfu.set_operation_timeout(timeout);
+        }
+        if let Some(strip) = self.strip_suffix {
+            dfu.set_strip_suffix(strip);
+        }
+        if let Some(manifest) = self.manifest_on_download {
+            dfu.set_manifest_on_download(manifest);
+        }
+        if let Some(recover) = self.recover_state {
+            dfu.set_recover_state(recover);
+        }
+        if let Some(read) = self.read_status_string {
+            dfu.set_read_status_string(read);
+        }
+        if let Some(limit) = self.read_memory_limit {
+            dfu.set_read_memory_limit(limit);
+        }
+        if let Some(cancel) = self.cancel_flag {
+            dfu.set_cancel_flag(cancel);
+        }
+        if let Some(progress) = self.progress {
+            dfu.with_progress_ex(progress);
+        }
+        if let Some(interval) = self.progress_interval {
+            dfu.set_progress_interval(interval);
+        }
+        if let Some(progress) = self.erase_progress {
+            dfu.with_erase_progress(progress);
+        }
+        if let Some(dry_run) = self.dry_run {
+            dfu.set_dry_run(dry_run);
+        }
+        if let Some(algorithm) = self.digest {
+            dfu.set_digest(Some(algorithm));
+        }
+        if let Some(ignore) = self.ignore_segment_bounds {
+            dfu.set_ignore_segment_bounds(ignore);
+        }
+        if let Some(ignore) = self.ignore_suffix_mismatch {
+            dfu.set_ignore_suffix_mismatch(ignore);
+        }
+        if let Some(allow) = self.allow_unaligned_address {
+            dfu.set_allow_unaligned_address(allow);
+        }
+        if let Some(sectors) = self.write_protected_sectors {
+            dfu.set_write_protected_sectors(sectors);
+        }
+
+        Ok(dfu)
+    }
+}
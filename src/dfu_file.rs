@@ -0,0 +1,216 @@
+use crate::Error;
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Size of the DFU suffix as defined by the DFU 1.1 specification.
+pub const SUFFIX_LENGTH: usize = 16;
+
+const SIGNATURE: &[u8; 3] = b"UFD";
+
+/// bcdDFU written in the suffix of plain DFU 1.0 files.
+const DFU_VERSION: u16 = 0x0100;
+
+/// DFU suffix appended at the end of a `.dfu` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfuSuffix {
+    /// idVendor of the targeted device (0xffff matches any vendor).
+    pub vendor: u16,
+    /// idProduct of the targeted device (0xffff matches any product).
+    pub product: u16,
+    /// bcdDevice of the targeted device (0xffff matches any release).
+    pub device: u16,
+    /// bcdDFU of the DFU specification the file complies with.
+    pub dfu_version: u16,
+}
+
+impl DfuSuffix {
+    /// Returns whether the suffix targets the device with the given vendor and product IDs.
+    pub fn matches(&self, vendor: u16, product: u16) -> bool {
+        (self.vendor == 0xffff || self.vendor == vendor)
+            && (self.product == 0xffff || self.product == product)
+    }
+
+    /// Parse the DFU suffix at the end of `bytes`, which is either a whole file or its last
+    /// [`SUFFIX_LENGTH`] bytes.
+    ///
+    /// Returns `None` if there is no valid signature. The CRC is not checked, see
+    /// [`DfuFile::parse`].
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(bytes.len().checked_sub(SUFFIX_LENGTH)?..)?;
+        if &bytes[8..11] != SIGNATURE || (bytes[11] as usize) < SUFFIX_LENGTH {
+            return None;
+        }
+
+        Some(Self {
+            device: u16::from_le_bytes([bytes[0], bytes[1]]),
+            product: u16::from_le_bytes([bytes[2], bytes[3]]),
+            vendor: u16::from_le_bytes([bytes[4], bytes[5]]),
+            dfu_version: u16::from_le_bytes([bytes[6], bytes[7]]),
+        })
+    }
+}
+
+/// A firmware file whose DFU suffix, if any, has been validated and stripped.
+///
+/// Reading from it yields only the payload of the file.
+pub struct DfuFile<R> {
+    reader: io::Take<R>,
+    length: u32,
+    suffix: Option<DfuSuffix>,
+    suffix_length: u32,
+}
+
+impl<R: Read + Seek> DfuFile<R> {
+    /// Parse the DFU suffix at the end of a firmware file.
+    ///
+    /// The CRC of the file is verified when a suffix is found. Files without a suffix are passed
+    /// through unchanged.
+    pub fn parse(mut reader: R) -> Result<Self, Error> {
+        let size = u32::try_from(reader.seek(SeekFrom::End(0))?)
+            .map_err(|_| dfu_core::Error::OutOfCapabilities)?;
+
+        let suffix = if size as usize >= SUFFIX_LENGTH {
+            let mut bytes = [0; SUFFIX_LENGTH];
+            reader.seek(SeekFrom::Start((size as usize - SUFFIX_LENGTH) as u64))?;
+            reader.read_exact(&mut bytes)?;
+            Self::parse_suffix(&mut reader, size, &bytes)?
+        } else {
+            None
+        };
+
+        let suffix_length = match suffix {
+            Some((_, suffix_length)) => suffix_length,
+            None => {
+                log::info!("No DFU suffix found, the file is used as is");
+                0
+            }
+        };
+        let length = size - suffix_length;
+        reader.seek(SeekFrom::Start(0))?;
+
+        Ok(Self {
+            reader: reader.take(length as u64),
+            length,
+            suffix: suffix.map(|(suffix, _)| suffix),
+            suffix_length,
+        })
+    }
+
+    fn parse_suffix(
+        reader: &mut R,
+        size: u32,
+        bytes: &[u8; SUFFIX_LENGTH],
+    ) -> Result<Option<(DfuSuffix, u32)>, Error> {
+        let suffix = match DfuSuffix::from_bytes(bytes) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let suffix_length = bytes[11] as u32;
+        if suffix_length > size {
+            log::info!("Invalid DFU suffix length: {}", suffix_length);
+            return Ok(None);
+        }
+
+        let expected = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        reader.seek(SeekFrom::Start(0))?;
+        let got = crc32_reader(reader.take(size as u64 - 4))?;
+        if got != expected {
+            return Err(Error::SuffixCrcMismatch { expected, got });
+        }
+
+        log::debug!("DFU suffix found: {:04x?}", suffix);
+
+        Ok(Some((suffix, suffix_length)))
+    }
+}
+
+impl<R> DfuFile<R> {
+    /// Returns the suffix of the file if it had one.
+    pub fn suffix(&self) -> Option<&DfuSuffix> {
+        self.suffix.as_ref()
+    }
+
+    /// Returns the length of the payload, without the suffix.
+    pub fn len(&self) -> u32 {
+        self.length
+    }
+
+    /// Returns the length of the suffix, 0 if the file has none.
+    pub fn suffix_length(&self) -> u32 {
+        self.suffix_length
+    }
+
+    /// Yield the suffix after the payload instead of stripping it, see
+    /// [`crate::DfuSync::set_strip_suffix`]. Must be called before reading.
+    pub(crate) fn keep_suffix(mut self) -> Self {
+        self.length += self.suffix_length;
+        self.reader.set_limit(self.length as u64);
+        self
+    }
+
+    /// Returns whether the payload is empty.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl DfuFile<()> {
+    /// Write a firmware followed by a DFU suffix targeting the given device.
+    ///
+    /// The CRC is computed the same way as dfu-util so the file can be downloaded by either tool.
+    pub fn write_with_suffix<W: Write>(
+        mut writer: W,
+        payload: &[u8],
+        vendor: u16,
+        product: u16,
+        device: u16,
+    ) -> Result<(), Error> {
+        let mut suffix = [0; SUFFIX_LENGTH];
+        suffix[0..2].copy_from_slice(&device.to_le_bytes());
+        suffix[2..4].copy_from_slice(&product.to_le_bytes());
+        suffix[4..6].copy_from_slice(&vendor.to_le_bytes());
+        suffix[6..8].copy_from_slice(&DFU_VERSION.to_le_bytes());
+        suffix[8..11].copy_from_slice(SIGNATURE);
+        suffix[11] = SUFFIX_LENGTH as u8;
+        let crc = crc32(crc32(0xffffffff, payload), &suffix[..SUFFIX_LENGTH - 4]);
+        suffix[12..].copy_from_slice(&crc.to_le_bytes());
+
+        writer.write_all(payload)?;
+        writer.write_all(&suffix)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DfuFile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Update a CRC32 the way dfu-util does it (no final XOR).
+pub(crate) fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc32_reader(mut reader: impl Read) -> Result<u32, io::Error> {
+    let mut crc = 0xffffffff;
+    let mut buffer = [0; 4096];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(crc);
+        }
+        crc = crc32(crc, &buffer[..n]);
+    }
+}
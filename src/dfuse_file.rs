@@ -0,0 +1,162 @@
+use crate::{DfuFile, DfuSuffix};
+use std::convert::TryInto;
+use std::io::{Read, Seek};
+use thiserror::Error;
+
+const PREFIX_SIGNATURE: &[u8; 5] = b"DfuSe";
+const PREFIX_LENGTH: usize = 11;
+const TARGET_SIGNATURE: &[u8; 6] = b"Target";
+const TARGET_PREFIX_LENGTH: usize = 274;
+const ELEMENT_HEADER_LENGTH: usize = 8;
+
+/// bcdDFU of the suffix of DfuSe files.
+const DFUSE_VERSION: u16 = 0x011a;
+
+/// Error while parsing a DfuSe file.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("the file has no DFU suffix")]
+    MissingSuffix,
+    #[error("the DFU suffix has version {0:#06x} instead of 0x011a")]
+    UnsupportedSuffixVersion(u16),
+    #[error("missing DfuSe prefix")]
+    MissingPrefix,
+    #[error("unsupported DfuSe version {0}")]
+    UnsupportedVersion(u8),
+    #[error("the DfuSe prefix gives a size of {expected} bytes but the image has {got}")]
+    SizeMismatch { expected: u32, got: u32 },
+    #[error("target {0}: missing target prefix")]
+    MissingTargetPrefix(usize),
+    #[error("target {0}: the size of the elements does not match the size of the target")]
+    TargetSizeMismatch(usize),
+    #[error("the DfuSe file is truncated")]
+    Truncated,
+}
+
+/// An image of a DfuSe file, to be written through one alt-setting of the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuSeTarget {
+    /// Alt-setting the image is meant for.
+    pub alt: u8,
+    /// Name of the target, if any.
+    pub name: Option<String>,
+    /// Data to write at specific addresses, as `(address, data)` pairs.
+    pub elements: Vec<(u32, Vec<u8>)>,
+}
+
+/// A firmware in the DfuSe format produced by ST's tools, made of several images.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuSeFile {
+    suffix: DfuSuffix,
+    targets: Vec<DfuSeTarget>,
+}
+
+impl DfuSeFile {
+    /// Parse a DfuSe file.
+    ///
+    /// The DFU suffix, CRC included, and the DfuSe prefixes are validated.
+    pub fn parse<R: Read + Seek>(reader: R) -> Result<Self, crate::Error> {
+        let mut file = DfuFile::parse(reader)?;
+        let suffix = *file.suffix().ok_or(Error::MissingSuffix)?;
+        if suffix.dfu_version != DFUSE_VERSION {
+            return Err(Error::UnsupportedSuffixVersion(suffix.dfu_version).into());
+        }
+        let mut bytes = Vec::with_capacity(file.len() as usize);
+        file.read_to_end(&mut bytes)?;
+
+        Ok(Self {
+            suffix,
+            targets: Self::parse_targets(&bytes)?,
+        })
+    }
+
+    /// Returns whether the bytes start with the prefix of a DfuSe file.
+    pub fn is_dfuse(bytes: &[u8]) -> bool {
+        bytes.starts_with(PREFIX_SIGNATURE)
+    }
+
+    fn parse_targets(bytes: &[u8]) -> Result<Vec<DfuSeTarget>, Error> {
+        if !Self::is_dfuse(bytes) || bytes.len() < PREFIX_LENGTH {
+            return Err(Error::MissingPrefix);
+        }
+        if bytes[5] != 0x01 {
+            return Err(Error::UnsupportedVersion(bytes[5]));
+        }
+        let size = read_u32(bytes, 6)?;
+        if size as usize != bytes.len() {
+            return Err(Error::SizeMismatch {
+                expected: size,
+                got: bytes.len() as u32,
+            });
+        }
+
+        let mut offset = PREFIX_LENGTH;
+        let mut targets = Vec::with_capacity(bytes[10] as usize);
+        for index in 0..bytes[10] as usize {
+            let prefix = bytes
+                .get(offset..offset + TARGET_PREFIX_LENGTH)
+                .ok_or(Error::Truncated)?;
+            if !prefix.starts_with(TARGET_SIGNATURE) {
+                return Err(Error::MissingTargetPrefix(index));
+            }
+            let name = match read_u32(prefix, 7)? {
+                0 => None,
+                _ => {
+                    let name = &prefix[11..266];
+                    let end = name.iter().position(|&x| x == 0).unwrap_or(name.len());
+                    Some(String::from_utf8_lossy(&name[..end]).into_owned())
+                }
+            };
+            let target_size = read_u32(prefix, 266)? as usize;
+            let element_count = read_u32(prefix, 270)?;
+            offset += TARGET_PREFIX_LENGTH;
+
+            let target_end = offset.checked_add(target_size).ok_or(Error::Truncated)?;
+            let mut elements = Vec::new();
+            for _ in 0..element_count {
+                let address = read_u32(bytes, offset)?;
+                let length = read_u32(bytes, offset + 4)? as usize;
+                offset += ELEMENT_HEADER_LENGTH;
+                let data = offset
+                    .checked_add(length)
+                    .and_then(|end| bytes.get(offset..end))
+                    .ok_or(Error::Truncated)?;
+                elements.push((address, data.to_vec()));
+                offset += length;
+            }
+            if offset != target_end {
+                return Err(Error::TargetSizeMismatch(index));
+            }
+
+            targets.push(DfuSeTarget {
+                alt: prefix[6],
+                name,
+                elements,
+            });
+        }
+
+        Ok(targets)
+    }
+
+    /// Returns the DFU suffix of the file.
+    pub fn suffix(&self) -> &DfuSuffix {
+        &self.suffix
+    }
+
+    /// Returns the images of the file.
+    pub fn targets(&self) -> &[DfuSeTarget] {
+        &self.targets
+    }
+
+    /// Returns the images of the file.
+    pub fn into_targets(self) -> Vec<DfuSeTarget> {
+        self.targets
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .ok_or(Error::Truncated)
+}
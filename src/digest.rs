@@ -0,0 +1,83 @@
+use std::fmt;
+
+/// Algorithm used to compute the digest of a downloaded firmware, see
+/// [`crate::DfuSync::set_digest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    /// The CRC32 used by zlib and `crc32(1)`, as opposed to the one of the DFU suffix.
+    Crc32,
+    /// SHA-256 (requires the `sha256` feature).
+    #[cfg(feature = "sha256")]
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    /// Compute the digest of everything a reader returns, e.g. a firmware file to compare with
+    /// [`crate::DfuSync::upload_digest`].
+    pub fn digest<R: std::io::Read>(self, mut reader: R) -> std::io::Result<Digest> {
+        let mut hasher = Hasher::new(self);
+        let mut buffer = [0; 4096];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => return Ok(hasher.finalize()),
+                Ok(n) => hasher.update(&buffer[..n]),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Digest of the firmware written to a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Digest {
+    Crc32(u32),
+    #[cfg(feature = "sha256")]
+    Sha256([u8; 32]),
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Digest::Crc32(crc) => write!(f, "crc32:{:08x}", crc),
+            #[cfg(feature = "sha256")]
+            Digest::Sha256(hash) => {
+                write!(f, "sha256:")?;
+                hash.iter().try_for_each(|x| write!(f, "{:02x}", x))
+            }
+        }
+    }
+}
+
+/// Running computation of a [`Digest`].
+pub(crate) enum Hasher {
+    Crc32(u32),
+    #[cfg(feature = "sha256")]
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Crc32 => Hasher::Crc32(0xffffffff),
+            #[cfg(feature = "sha256")]
+            DigestAlgorithm::Sha256 => Hasher::Sha256(sha2::Digest::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(crc) => *crc = crate::dfu_file::crc32(*crc, data),
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(hasher) => sha2::Digest::update(hasher, data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Digest {
+        match self {
+            Hasher::Crc32(crc) => Digest::Crc32(!crc),
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(hasher) => Digest::Sha256(sha2::Digest::finalize(hasher).into()),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::convert::TryInto;
+use std::io::Read;
+use thiserror::Error;
+
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+
+/// Error while parsing an ELF file.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("not an ELF file")]
+    InvalidMagic,
+    #[error("only 32-bit little-endian ELF files are supported")]
+    UnsupportedFormat,
+    #[error("the ELF file targets machine {0}, not ARM")]
+    NotArm(u16),
+    #[error("the ELF file is truncated")]
+    Truncated,
+}
+
+/// A firmware in the ELF format, as produced by the linker.
+pub struct ElfImage;
+
+impl ElfImage {
+    /// Read the loadable (`PT_LOAD`) segments of a 32-bit ARM ELF file.
+    ///
+    /// Each segment is returned with its physical address, sorted by address. Only the bytes
+    /// present in the file are kept: zero-filled regions such as `.bss` are skipped.
+    pub fn load_segments<R: Read>(mut reader: R) -> Result<Vec<(u32, Vec<u8>)>, crate::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Ok(Self::parse(&bytes)?)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+        if bytes.get(..4) != Some(b"\x7fELF") {
+            return Err(Error::InvalidMagic);
+        }
+        // e_machine is at the same offset whatever the class, only its endianness varies.
+        let machine = read_u16(bytes, 18)?;
+        let machine = match bytes[5] {
+            2 => machine.swap_bytes(),
+            _ => machine,
+        };
+        if machine != EM_ARM {
+            return Err(Error::NotArm(machine));
+        }
+        // EI_CLASS must be ELFCLASS32 and EI_DATA ELFDATA2LSB.
+        if bytes.get(4..6) != Some(&[1, 1]) {
+            return Err(Error::UnsupportedFormat);
+        }
+
+        let phoff = read_u32(bytes, 28)? as usize;
+        let phentsize = read_u16(bytes, 42)? as usize;
+        let phnum = read_u16(bytes, 44)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let header = i
+                .checked_mul(phentsize)
+                .and_then(|x| x.checked_add(phoff))
+                .ok_or(Error::Truncated)?;
+            if read_u32(bytes, header)? != PT_LOAD {
+                continue;
+            }
+            let offset = read_u32(bytes, header + 4)? as usize;
+            let address = read_u32(bytes, header + 12)?;
+            let filesz = read_u32(bytes, header + 16)? as usize;
+            if filesz == 0 {
+                continue;
+            }
+            let data = offset
+                .checked_add(filesz)
+                .and_then(|end| bytes.get(offset..end))
+                .ok_or(Error::Truncated)?;
+            segments.push((address, data.to_vec()));
+        }
+        segments.sort_by_key(|(address, _)| *address);
+
+        Ok(segments)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, Error> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|x| u16::from_le_bytes(x.try_into().unwrap()))
+        .ok_or(Error::Truncated)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .ok_or(Error::Truncated)
+}
@@ -0,0 +1,117 @@
+use crate::{reset_mcu, Dfu, DfuFile, DfuLibusb, DownloadReport, Error, ProgressEvent};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long [`flash`] waits for the bootloader after a serial reset, unless told otherwise.
+const RESET_WAIT: Duration = Duration::from_secs(5);
+
+/// What [`flash`] does, see [`FlashOptions::new`].
+pub struct FlashOptions {
+    /// Vendor ID of the device in DFU mode.
+    pub vid: u16,
+    /// Product ID of the device in DFU mode.
+    pub pid: u16,
+    /// DFU interface number.
+    pub intf: u8,
+    /// Alt-setting of the DFU interface.
+    pub alt: u8,
+    /// Firmware file to download, with or without a DFU suffix.
+    pub path: PathBuf,
+    /// Wait up to this long for the device to show up if it isn't connected yet.
+    pub wait: Option<Duration>,
+    /// Leave DFU mode once the firmware is written, to start it.
+    pub reset: bool,
+    /// Serial port of the running application to reset into its bootloader first, see
+    /// [`reset_mcu`].
+    pub serial_port: Option<String>,
+    /// See [`crate::DfuSync::override_address`].
+    pub override_address: Option<u32>,
+    /// See [`crate::DfuSync::with_progress_ex`].
+    pub progress: Option<Box<dyn FnMut(ProgressEvent)>>,
+}
+
+impl FlashOptions {
+    /// Download `path` into the first alt-setting of the first interface of the device, without
+    /// waiting nor resetting.
+    pub fn new(vid: u16, pid: u16, path: impl Into<PathBuf>) -> Self {
+        Self {
+            vid,
+            pid,
+            intf: 0,
+            alt: 0,
+            path: path.into(),
+            wait: None,
+            reset: false,
+            serial_port: None,
+            override_address: None,
+            progress: None,
+        }
+    }
+}
+
+/// Download a firmware file into a device the way the `download` example does.
+///
+/// The application is first reset into its bootloader through [`FlashOptions::serial_port`], if
+/// given, in which case the device is waited for 5 seconds unless [`FlashOptions::wait`] is set.
+/// A device found running its application is detached. Once the firmware is written, devices
+/// that are not manifestation tolerant reset by themselves, the others leave DFU mode if
+/// [`FlashOptions::reset`] is set: at the download address for DfuSe devices, with a USB reset
+/// for the others.
+pub fn flash<C: rusb::UsbContext + 'static>(
+    context: &C,
+    options: FlashOptions,
+) -> Result<DownloadReport, Error> {
+    let FlashOptions {
+        vid,
+        pid,
+        intf,
+        alt,
+        path,
+        mut wait,
+        reset,
+        serial_port,
+        override_address,
+        progress,
+    } = options;
+
+    let file = DfuFile::parse(std::fs::File::open(path)?)?;
+
+    if let Some(serial_port) = &serial_port {
+        // The bootloader may already be running, in which case there is nothing to reset.
+        if let Err(err) = reset_mcu(serial_port) {
+            log::warn!("Could not reset the MCU at {}: {}", serial_port, err);
+        }
+        wait = wait.or(Some(RESET_WAIT));
+    }
+
+    let device: Result<Dfu<C>, Error> = DfuLibusb::open(context, vid, pid, intf, alt);
+    let device = match (device, wait) {
+        (Err(Error::DeviceNotFound), Some(timeout)) => {
+            DfuLibusb::wait_for_dfu_mode(context, vid, pid, intf, alt, timeout)
+        }
+        (res, _) => res,
+    };
+    let mut device = match device {
+        Err(Error::DeviceInRuntimeMode) => DfuLibusb::open_runtime(context, vid, pid)?
+            .detach_and_reopen(context, intf, alt, RESET_WAIT)?,
+        res => res?,
+    };
+
+    if let Some(progress) = progress {
+        device.with_progress_ex(progress);
+    }
+    if let Some(address) = override_address {
+        device.override_address(address);
+    }
+
+    let report = device.download_file(file)?;
+
+    if reset && device.manifestation_tolerant() {
+        match device.leave() {
+            Err(Error::DfuseUnsupported) => device.usb_reset()?,
+            res => res?,
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,93 @@
+use crate::{DfuSeFile, DfuSuffix, SUFFIX_LENGTH};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Number of bytes read from the start of a file to detect its format: the longest HEX record.
+const SNIFF_LENGTH: usize = 1 + 2 * (255 + 5);
+
+/// Format of a firmware file, see [`detect_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareFormat {
+    /// Raw binary written as is, see [`crate::DfuFile`].
+    Raw,
+    /// Raw binary followed by a DFU suffix, see [`crate::DfuFile`].
+    Dfu,
+    /// DfuSe container with images for several alt-settings, see [`DfuSeFile`].
+    DfuSe,
+    /// Intel HEX records, see [`crate::HexFile`].
+    Hex,
+    /// ELF file produced by the linker, which requires the `elf` feature to be downloaded.
+    Elf,
+}
+
+impl fmt::Display for FirmwareFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Raw => "raw binary",
+            Self::Dfu => "DFU",
+            Self::DfuSe => "DfuSe",
+            Self::Hex => "Intel HEX",
+            Self::Elf => "ELF",
+        })
+    }
+}
+
+/// Detect the format of a firmware file from its content.
+///
+/// The ELF magic, the DfuSe prefix, HEX records and the DFU suffix are looked for, in this
+/// order, falling back to [`FirmwareFormat::Raw`]. The extension of the file name, if given,
+/// only breaks the tie between a HEX file and a binary starting with what looks like a record:
+/// the latter is assumed with the `bin` and `dfu` extensions. The reader is rewound to where it
+/// was afterward.
+pub fn detect_format<R: Read + Seek>(
+    mut reader: R,
+    extension: Option<&str>,
+) -> io::Result<FirmwareFormat> {
+    let start = reader.stream_position()?;
+    let mut prefix = Vec::with_capacity(SNIFF_LENGTH);
+    (&mut reader)
+        .take(SNIFF_LENGTH as u64)
+        .read_to_end(&mut prefix)?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    let suffix = if end - start >= SUFFIX_LENGTH as u64 {
+        let mut bytes = [0; SUFFIX_LENGTH];
+        reader.seek(SeekFrom::End(-(SUFFIX_LENGTH as i64)))?;
+        reader.read_exact(&mut bytes)?;
+        DfuSuffix::from_bytes(&bytes)
+    } else {
+        None
+    };
+    reader.seek(SeekFrom::Start(start))?;
+
+    let binary = extension
+        .filter(|x| x.eq_ignore_ascii_case("bin") || x.eq_ignore_ascii_case("dfu"))
+        .is_some();
+    let format = if prefix.starts_with(b"\x7fELF") {
+        FirmwareFormat::Elf
+    } else if DfuSeFile::is_dfuse(&prefix) {
+        FirmwareFormat::DfuSe
+    } else if is_hex_record(&prefix) && !binary {
+        FirmwareFormat::Hex
+    } else if suffix.is_some() {
+        FirmwareFormat::Dfu
+    } else {
+        FirmwareFormat::Raw
+    };
+    log::info!("Detected the {} format", format);
+    Ok(format)
+}
+
+/// Returns whether the bytes start with a HEX record: a colon followed by an even number of hex
+/// digits, at least the 5 bytes of an empty record, up to the end of the line.
+fn is_hex_record(bytes: &[u8]) -> bool {
+    let line = match bytes.strip_prefix(b":") {
+        Some(x) => x,
+        None => return false,
+    };
+    let digits = line
+        .iter()
+        .position(|x| *x == b'\r' || *x == b'\n')
+        .unwrap_or(line.len());
+    let line = &line[..digits];
+    line.len() >= 10 && line.len() % 2 == 0 && line.iter().all(u8::is_ascii_hexdigit)
+}
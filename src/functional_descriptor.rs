@@ -0,0 +1,178 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Length of a DFU functional descriptor (bLength).
+const LENGTH: u8 = 9;
+
+/// Type of a DFU functional descriptor (bDescriptorType).
+const DESCRIPTOR_TYPE: u8 = 0x21;
+
+/// Error while parsing a DFU functional descriptor.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("the descriptor is truncated (got {got} bytes, expected {expected})")]
+    Truncated { expected: usize, got: usize },
+    #[error("invalid descriptor length {0} (expected {LENGTH})")]
+    InvalidLength(u8),
+    #[error("invalid descriptor type {0:#04x} (expected {DESCRIPTOR_TYPE:#04x})")]
+    InvalidDescriptorType(u8),
+}
+
+/// DFU functional descriptor of a device.
+#[derive(Debug, Clone, Copy)]
+pub struct FunctionalDescriptor(dfu_core::functional_descriptor::FunctionalDescriptor);
+
+impl FunctionalDescriptor {
+    /// Returns whether the device supports download (bitCanDnload).
+    pub fn can_download(&self) -> bool {
+        self.0.can_download
+    }
+
+    /// Returns whether the device supports upload (bitCanUpload).
+    pub fn can_upload(&self) -> bool {
+        self.0.can_upload
+    }
+
+    /// Returns whether the device is still responsive after manifestation
+    /// (bitManifestationTolerant).
+    pub fn manifestation_tolerant(&self) -> bool {
+        self.0.manifestation_tolerant
+    }
+
+    /// Returns whether the device detaches by itself on DFU_DETACH, in which case the host must
+    /// not reset it (bitWillDetach).
+    pub fn will_detach(&self) -> bool {
+        self.0.will_detach
+    }
+
+    /// Returns how long the device waits for a USB reset after DFU_DETACH (wDetachTimeOut).
+    pub fn detach_timeout(&self) -> Duration {
+        Duration::from_millis(self.0.detach_timeout as u64)
+    }
+
+    /// Returns the maximum number of bytes per control transfer (wTransferSize).
+    pub fn transfer_size(&self) -> u16 {
+        self.0.transfer_size
+    }
+
+    /// Returns the version of the DFU specification implemented by the device (bcdDFUVersion).
+    ///
+    /// DfuSe devices report `(1, 0x1a)`.
+    pub fn dfu_version(&self) -> (u8, u8) {
+        self.0.dfu_version
+    }
+}
+
+impl From<dfu_core::functional_descriptor::FunctionalDescriptor> for FunctionalDescriptor {
+    fn from(descriptor: dfu_core::functional_descriptor::FunctionalDescriptor) -> Self {
+        Self(descriptor)
+    }
+}
+
+/// Fields of the functional descriptor to override, see
+/// [`crate::DfuSync::set_functional_descriptor_overrides`].
+///
+/// The fields left to `None` keep the values overridden before, if any, or else the values read
+/// from the device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionalDescriptorOverrides {
+    /// Replaces wTransferSize.
+    pub transfer_size: Option<u16>,
+    /// Replaces bitManifestationTolerant.
+    pub manifestation_tolerant: Option<bool>,
+    /// Replaces bitWillDetach.
+    pub will_detach: Option<bool>,
+}
+
+impl FunctionalDescriptorOverrides {
+    /// Returns these overrides, with the fields left to `None` taken from `other`.
+    pub(crate) fn or(self, other: Self) -> Self {
+        Self {
+            transfer_size: self.transfer_size.or(other.transfer_size),
+            manifestation_tolerant: self.manifestation_tolerant.or(other.manifestation_tolerant),
+            will_detach: self.will_detach.or(other.will_detach),
+        }
+    }
+
+    pub(crate) fn apply(
+        &self,
+        mut descriptor: dfu_core::functional_descriptor::FunctionalDescriptor,
+    ) -> dfu_core::functional_descriptor::FunctionalDescriptor {
+        if let Some(size) = self.transfer_size {
+            descriptor.transfer_size = size;
+        }
+        if let Some(tolerant) = self.manifestation_tolerant {
+            descriptor.manifestation_tolerant = tolerant;
+        }
+        if let Some(will_detach) = self.will_detach {
+            descriptor.will_detach = will_detach;
+        }
+        descriptor
+    }
+}
+
+/// Parse the raw bytes of a functional descriptor, e.g. from a dump of the configuration
+/// descriptor of a device.
+///
+/// The bytes must start with the descriptor, a longer slice is accepted.
+impl TryFrom<&[u8]> for FunctionalDescriptor {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let header = bytes.get(..2).ok_or(Error::Truncated {
+            expected: LENGTH as usize,
+            got: bytes.len(),
+        })?;
+        let (length, descriptor_type) = (header[0], header[1]);
+        if descriptor_type != DESCRIPTOR_TYPE {
+            return Err(Error::InvalidDescriptorType(descriptor_type));
+        }
+        if length < LENGTH {
+            return Err(Error::InvalidLength(length));
+        }
+        let bytes = bytes.get(..length as usize).ok_or(Error::Truncated {
+            expected: length as usize,
+            got: bytes.len(),
+        })?;
+
+        let attributes = bytes[2];
+        Ok(Self(
+            dfu_core::functional_descriptor::FunctionalDescriptor {
+                can_download: attributes & (1 << 0) != 0,
+                can_upload: attributes & (1 << 1) != 0,
+                manifestation_tolerant: attributes & (1 << 2) != 0,
+                will_detach: attributes & (1 << 3) != 0,
+                detach_timeout: u16::from_le_bytes([bytes[3], bytes[4]]),
+                transfer_size: u16::from_le_bytes([bytes[5], bytes[6]]),
+                dfu_version: (bytes[8], bytes[7]),
+            },
+        ))
+    }
+}
+
+impl fmt::Display for FunctionalDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn yes_no(x: bool) -> &'static str {
+            if x {
+                "yes"
+            } else {
+                "no"
+            }
+        }
+
+        let (major, minor) = self.dfu_version();
+        writeln!(f, "DFU version: {:x}.{:02x}", major, minor)?;
+        writeln!(f, "Download: {}", yes_no(self.can_download()))?;
+        writeln!(f, "Upload: {}", yes_no(self.can_upload()))?;
+        writeln!(
+            f,
+            "Manifestation tolerant: {}",
+            yes_no(self.manifestation_tolerant())
+        )?;
+        writeln!(f, "Will detach: {}", yes_no(self.will_detach()))?;
+        writeln!(f, "Detach timeout: {} ms", self.0.detach_timeout)?;
+        write!(f, "Transfer size: {} bytes", self.transfer_size())
+    }
+}
@@ -0,0 +1,158 @@
+use std::io::{BufRead, BufReader, Read};
+use thiserror::Error;
+
+/// Error while parsing an Intel HEX file.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("line {0}: missing start code")]
+    MissingStartCode(usize),
+    #[error("line {0}: invalid hexadecimal digits")]
+    InvalidHex(usize),
+    #[error("line {0}: invalid record length")]
+    InvalidLength(usize),
+    #[error("line {line}: checksum mismatch (expected {expected:#04x}, got {got:#04x})")]
+    ChecksumMismatch { line: usize, expected: u8, got: u8 },
+    #[error("line {line}: unknown record type {record_type:#04x}")]
+    UnknownRecordType { line: usize, record_type: u8 },
+    #[error("line {0}: the data overflows the address space")]
+    AddressOverflow(usize),
+    #[error("the data at {0:#010x} overlaps with previous data")]
+    Overlap(u32),
+}
+
+/// A firmware in the Intel HEX format.
+///
+/// The data records are gathered into contiguous segments sorted by address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexFile {
+    segments: Vec<(u32, Vec<u8>)>,
+}
+
+impl HexFile {
+    /// Parse an Intel HEX file.
+    ///
+    /// Extended segment address (02) and extended linear address (04) records are supported.
+    /// Start address records (03 and 05) are ignored.
+    pub fn parse<R: Read>(reader: R) -> Result<Self, crate::Error> {
+        let mut records: Vec<(u32, Vec<u8>)> = Vec::new();
+        let mut base = 0u32;
+
+        for (index, line) in BufReader::new(reader).lines().enumerate() {
+            let line_number = index + 1;
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let bytes = Self::parse_record(line_number, line)?;
+            let (length, offset, record_type) = (
+                bytes[0] as usize,
+                u16::from_be_bytes([bytes[1], bytes[2]]),
+                bytes[3],
+            );
+            let data = &bytes[4..bytes.len() - 1];
+            if data.len() != length {
+                return Err(Error::InvalidLength(line_number).into());
+            }
+
+            match record_type {
+                0x00 => {
+                    let address = base
+                        .checked_add(offset as u32)
+                        .filter(|x| x.checked_add(length as u32).is_some())
+                        .ok_or(Error::AddressOverflow(line_number))?;
+                    records.push((address, data.to_vec()));
+                }
+                0x01 => break,
+                0x02 | 0x04 if length == 2 => {
+                    let value = u16::from_be_bytes([data[0], data[1]]) as u32;
+                    base = if record_type == 0x02 {
+                        value << 4
+                    } else {
+                        value << 16
+                    };
+                }
+                0x02 | 0x04 => return Err(Error::InvalidLength(line_number).into()),
+                0x03 | 0x05 => {}
+                record_type => {
+                    return Err(Error::UnknownRecordType {
+                        line: line_number,
+                        record_type,
+                    }
+                    .into())
+                }
+            }
+        }
+
+        records.sort_by_key(|(address, _)| *address);
+        let mut segments: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (address, data) in records {
+            if let Some((start, last)) = segments.last_mut() {
+                let end = *start + last.len() as u32;
+                if address < end {
+                    return Err(Error::Overlap(address).into());
+                }
+                if address == end {
+                    last.extend(data);
+                    continue;
+                }
+            }
+            segments.push((address, data));
+        }
+
+        Ok(Self { segments })
+    }
+
+    fn parse_record(line_number: usize, line: &str) -> Result<Vec<u8>, Error> {
+        let digits = line
+            .strip_prefix(':')
+            .ok_or(Error::MissingStartCode(line_number))?;
+        if digits.len() % 2 != 0 || !digits.is_ascii() {
+            return Err(Error::InvalidHex(line_number));
+        }
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| Error::InvalidHex(line_number))?;
+        if bytes.len() < 5 {
+            return Err(Error::InvalidLength(line_number));
+        }
+
+        let (record, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = record
+            .iter()
+            .fold(0u8, |sum, x| sum.wrapping_add(*x))
+            .wrapping_neg();
+        if expected != checksum[0] {
+            return Err(Error::ChecksumMismatch {
+                line: line_number,
+                expected,
+                got: checksum[0],
+            });
+        }
+
+        Ok(bytes)
+    }
+
+    /// Returns the contiguous segments of the file as `(address, data)` pairs.
+    pub fn segments(&self) -> &[(u32, Vec<u8>)] {
+        &self.segments
+    }
+
+    /// Returns the segments of the file.
+    pub fn into_segments(self) -> Vec<(u32, Vec<u8>)> {
+        self.segments
+    }
+
+    /// Returns the total number of bytes in the segments.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|(_, data)| data.len()).sum()
+    }
+
+    /// Returns whether the file has no data.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
@@ -1,32 +1,219 @@
-use std::cell::RefCell;
+mod builder;
+mod dfu_file;
+pub mod dfuse_file;
+mod digest;
+#[cfg(feature = "elf")]
+pub mod elf;
+mod flash;
+mod format;
+pub mod functional_descriptor;
+pub mod hex_file;
+pub mod memory_layout;
+#[cfg(feature = "testing")]
+pub mod mock;
+mod option_bytes;
+mod quirks;
+mod sync;
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::marker;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+pub use builder::DfuBuilder;
+pub use dfu_core::{self, DfuIo, DfuProtocol, State as DfuState, Status as DfuStatusCode};
+pub use dfu_file::{DfuFile, DfuSuffix, SUFFIX_LENGTH};
+pub use dfuse_file::{DfuSeFile, DfuSeTarget};
+pub use digest::{Digest, DigestAlgorithm};
+#[cfg(feature = "elf")]
+pub use elf::ElfImage;
+pub use flash::{flash, FlashOptions};
+pub use format::{detect_format, FirmwareFormat};
+pub use functional_descriptor::{FunctionalDescriptor, FunctionalDescriptorOverrides};
+pub use hex_file::HexFile;
+pub use memory_layout::{MemoryLayout, Segment};
+#[cfg(feature = "testing")]
+pub use mock::MockDfuIo;
+pub use option_bytes::{OptionBytes, ReadProtection};
+pub use quirks::{find_quirk, register_quirk, Quirk};
+pub use sync::{
+    DfuSync, DownloadReport, ProgressEvent, ProgressStats, ReopenRequired, PAYLOAD_LOG_TARGET,
+};
+
+/// Reset an application built with libmaple into its bootloader, see [`ResetConfig::MAPLE`].
 pub fn reset_mcu(port_name : &str) -> serialport::Result<()> {
-    let port = serialport::new(port_name, 115200);
-    let pa = &mut port.open()?;
-    let _ =pa.write_request_to_send(false)?;
-    let _ =pa.write_data_terminal_ready(false)?;
-    let _ =pa.write_data_terminal_ready(true)?;
-    std::thread::sleep(std::time::Duration::from_millis(50));
-    let _ = pa.write_data_terminal_ready(false)?;
-
-    let _ =pa.write_request_to_send(true)?;
-    let _ =pa.write_data_terminal_ready(true)?;
-    std::thread::sleep(std::time::Duration::from_millis(50));
-    let _ =pa.write_data_terminal_ready(false)?;
-    std::thread::sleep(std::time::Duration::from_millis(50));
-    let _ = pa.write(b"1EAF")?;
-    std::thread::sleep(std::time::Duration::from_millis(50));
+    reset_mcu_with(port_name, ResetConfig::MAPLE)
+}
+
+/// Serial line sequence making an application reset into its bootloader, see
+/// [`reset_mcu_with`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResetConfig {
+    /// Baud rate the port is opened at.
+    pub baud_rate: u32,
+    /// Level of RTS while DTR is pulsed.
+    pub rts: bool,
+    /// How long DTR is asserted before being cleared, or `None` to leave DTR alone.
+    pub dtr_pulse: Option<Duration>,
+    /// Bytes written to the port after the DTR pulse.
+    pub magic: Option<Cow<'static, [u8]>>,
+    /// How long to wait after the sequence before closing the port.
+    pub delay: Duration,
+    /// How long the port may take to open and to accept each write, on top of the pulse and the
+    /// delay above, before the reset fails instead of hanging on a wedged port.
+    pub timeout: Duration,
+}
+
+/// The default [`ResetConfig::timeout`].
+const RESET_TIMEOUT: Duration = Duration::from_secs(2);
+
+impl ResetConfig {
+    /// Applications built with libmaple: DTR is cleared while RTS is asserted, then `1EAF` is
+    /// written.
+    pub const MAPLE: Self = Self {
+        baud_rate: 115200,
+        rts: true,
+        dtr_pulse: Some(Duration::from_millis(50)),
+        magic: Some(Cow::Borrowed(b"1EAF")),
+        delay: Duration::from_millis(50),
+        timeout: RESET_TIMEOUT,
+    };
+}
+
+impl From<SerialReset> for ResetConfig {
+    fn from(reset: SerialReset) -> Self {
+        Self {
+            baud_rate: reset.baud_rate,
+            rts: reset.rts,
+            dtr_pulse: Some(reset.dtr_pulse),
+            magic: None,
+            delay: Duration::ZERO,
+            timeout: RESET_TIMEOUT,
+        }
+    }
+}
+
+/// Open a serial port and play `config` on it to make the MCU reset into its bootloader.
+///
+/// An error is returned if the port doesn't complete the sequence within
+/// [`ResetConfig::timeout`]. The sequence then keeps going on its own thread, which ends once the
+/// port gives up.
+pub fn reset_mcu_with(port_name: &str, config: ResetConfig) -> serialport::Result<()> {
+    let timeout = config.timeout;
+    let sequence = config.dtr_pulse.map_or(Duration::ZERO, |x| x * 2) + config.delay;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn({
+        let port_name = port_name.to_string();
+        move || {
+            let _ = sender.send(play_reset(&port_name, config));
+        }
+    });
+
+    match receiver.recv_timeout(sequence + timeout) {
+        Ok(res) => res,
+        Err(_) => Err(serialport::Error::new(
+            serialport::ErrorKind::Io(std::io::ErrorKind::TimedOut),
+            format!(
+                "{} did not complete the reset within {:?}",
+                port_name, timeout
+            ),
+        )),
+    }
+}
+
+fn play_reset(port_name: &str, config: ResetConfig) -> serialport::Result<()> {
+    let mut port = serialport::new(port_name, config.baud_rate)
+        .timeout(config.timeout)
+        .open()?;
+    port.write_request_to_send(config.rts)?;
+    if let Some(pulse) = config.dtr_pulse {
+        port.write_data_terminal_ready(true)?;
+        std::thread::sleep(pulse);
+        port.write_data_terminal_ready(false)?;
+    }
+    if let Some(magic) = &config.magic {
+        if let Some(pulse) = config.dtr_pulse {
+            std::thread::sleep(pulse);
+        }
+        port.write_all(magic)?;
+    }
+    std::thread::sleep(config.delay);
     Ok(())
 }
 
-pub type Dfu<C> = dfu_core::sync::DfuSync<DfuLibusb<C>, Error>;
+/// Serial line sequence making a bootloader enter DFU mode, see [`reset_serial`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialReset {
+    /// Baud rate the port is opened at.
+    pub baud_rate: u32,
+    /// Level of RTS while DTR is pulsed.
+    pub rts: bool,
+    /// How long DTR is asserted before being cleared.
+    pub dtr_pulse: Duration,
+}
+
+impl SerialReset {
+    /// LeafLabs Maple bootloader: the port is opened at 1200 baud and DTR is asserted then
+    /// cleared.
+    pub const MAPLE: Self = Self {
+        baud_rate: 1200,
+        rts: false,
+        dtr_pulse: Duration::from_millis(50),
+    };
+}
+
+/// Open a serial port at the baud rate of `reset`, set RTS, assert then clear DTR and close the
+/// port.
+pub fn reset_serial(port_name: &str, reset: SerialReset) -> serialport::Result<()> {
+    reset_mcu_with(port_name, reset.into())
+}
+
+/// Make a LeafLabs Maple board (1EAF:0003) enter its DFU bootloader.
+pub fn reset_maple(port_name: &str) -> serialport::Result<()> {
+    reset_serial(port_name, SerialReset::MAPLE)
+}
+
+/// Reset the MCU with [`reset_mcu`], then wait for its serial port to disappear.
+///
+/// The port disappearing means the application has exited, so the bootloader can be looked for.
+/// An error is returned if the port is still there after `timeout`.
+pub fn reset_mcu_and_wait(port_name: &str, timeout: Duration) -> serialport::Result<()> {
+    reset_mcu(port_name)?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let ports = serialport::available_ports()?;
+        if !ports.iter().any(|x| x.port_name == port_name) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(serialport::Error::new(
+                serialport::ErrorKind::Io(std::io::ErrorKind::TimedOut),
+                format!("{} is still present after the reset", port_name),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+pub type Dfu<C> = DfuSync<DfuLibusb<C>>;
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[deprecated(
+        note = "split into `DeviceNotFound`, `AccessDenied` and `InterfaceUnavailable`, match \
+            them all with `Error::is_could_not_open`"
+    )]
     #[error("Could not find device or an error occurred.")]
     CouldNotOpenDevice,
+    #[error("Could not find the device.")]
+    DeviceNotFound,
+    #[error("Permission denied to open the device, run as root or add a udev rule.")]
+    AccessDenied,
+    #[error("The device has no interface {intf}.")]
+    InterfaceUnavailable { intf: u8 },
     #[error(transparent)]
     Dfu(#[from] dfu_core::Error),
     #[error(transparent)]
@@ -37,23 +224,237 @@ pub enum Error {
     MissingLanguage,
     #[error("Could not find interface.")]
     InvalidInterface,
+    #[error(
+        "The interface is claimed by {}, unbind it or add a udev rule.",
+        .driver.as_deref().map_or("another driver".into(), |x| format!("the {} driver", x))
+    )]
+    InterfaceBusy { driver: Option<String> },
     #[error("Could not find alt interface.")]
     InvalidAlt,
     #[error("Could not parse functional descriptor: {0}")]
     FunctionalDescriptor(#[from] dfu_core::functional_descriptor::Error),
+    #[error("Invalid functional descriptor bytes given to FunctionalDescriptor::try_from: {0}")]
+    InvalidFunctionalDescriptor(#[from] functional_descriptor::Error),
     #[error("No DFU capable device found.")]
     NoDfuCapableDeviceFound,
+    #[error("Invalid DFU suffix CRC (expected: {expected:#010x}, got: {got:#010x}).")]
+    SuffixCrcMismatch { expected: u32, got: u32 },
+    #[error(
+        "The firmware file targets {:04x}:{:04x} but the device is {:04x}:{:04x}.",
+        .expected.0, .expected.1, .got.0, .got.1
+    )]
+    SuffixMismatch {
+        expected: (u16, u16),
+        got: (u16, u16),
+    },
+    #[error("The device does not support the DfuSe extensions.")]
+    DfuseUnsupported,
+    #[error("The device writes the firmware from its start and does not support addresses.")]
+    AddressingUnsupported,
+    #[error("The operation timed out.")]
+    Timeout,
+    #[error("The device was still busy after {0} status requests.")]
+    PollLimitExceeded(u32),
+    #[error("The operation was cancelled.")]
+    Cancelled,
+    #[error("The address {0:#010x} is outside of the memory layout of the device.")]
+    AddressOutOfRange(u32),
+    #[error("The memory segment at {0:#010x} is not writable.")]
+    SegmentNotWritable(u32),
+    #[error("The memory segment at {0:#010x} is not erasable.")]
+    SegmentNotErasable(u32),
+    #[error("The flash sector {sector} is write-protected by the option bytes.")]
+    SectorWriteProtected { sector: u32 },
+    #[error("The one-time-programmable byte at {0:#010x} is already programmed.")]
+    OtpAlreadyProgrammed(u32),
+    #[error(
+        "The firmware ends at {end:#010x}, past the end of its memory segment at \
+        {segment_end:#010x}."
+    )]
+    ImageExceedsSegment { end: u32, segment_end: u32 },
+    #[error("The address {address:#010x} is not aligned to the page size ({page_size} bytes).")]
+    UnalignedAddress { address: u32, page_size: u32 },
+    #[error("Could not parse memory layout: {0}")]
+    MemoryLayout(#[from] memory_layout::Error),
+    #[error("Could not parse DfuSe file: {0}")]
+    DfuSe(#[from] dfuse_file::Error),
+    #[error("Could not parse Intel HEX file: {0}")]
+    Hex(#[from] hex_file::Error),
+    #[cfg(feature = "elf")]
+    #[error("Could not parse ELF file: {0}")]
+    Elf(#[from] elf::Error),
+    #[error("The device is running its application, it must be detached first.")]
+    DeviceInRuntimeMode,
+    #[error("Refusing to read {length} bytes, the limit is {limit}.")]
+    LengthTooLarge { length: u32, limit: u32 },
+    #[error("The firmware has more blocks than DFU can number.")]
+    ImageTooLarge,
+    #[error("The firmware is empty, see `DfuSync::manifest` to only manifest.")]
+    EmptyFirmware,
+    #[error("The device reports an invalid transfer size of {0} bytes.")]
+    InvalidTransferSize(u16),
+    #[error("The device does not support upload.")]
+    UploadUnsupported,
+    #[error(
+        "The device reported an error: {status} ({status:?} in state {state:?}).{}",
+        status_details(.status, .description)
+    )]
+    DfuStatus {
+        status: DfuStatusCode,
+        state: DfuState,
+        /// The status string given by the device, if any, see
+        /// [`DfuSync::set_read_status_string`].
+        description: Option<String>,
+    },
+    #[error("Interface {intf} has no DFU alt-setting {alt} (available: {available:?}).")]
+    NoSuchAltSetting {
+        intf: u8,
+        alt: u8,
+        available: Vec<u8>,
+    },
+    #[error("{} devices match, select one by its serial number.", .0.len())]
+    MultipleDevices(Vec<DfuDeviceInfo>),
+    #[error(
+        "Flashing failed {} times: {}",
+        .0.len(),
+        .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(" / ")
+    )]
+    FlashAttemptsFailed(Vec<VerifyError>),
+    #[error("No DFU alt-setting is named {name:?} (available: {available:?}).")]
+    NoSuchAltName {
+        name: String,
+        available: Vec<String>,
+    },
+    #[error("Support for {0} files is not enabled.")]
+    FormatUnsupported(FirmwareFormat),
+}
+
+/// The description given by the device and a likely cause of a status it reported, to append to
+/// its error message.
+fn status_details(status: &DfuStatusCode, description: &Option<String>) -> String {
+    let mut details = match description {
+        Some(description) => format!(" The device says: {:?}.", description),
+        None => String::new(),
+    };
+    if let DfuStatusCode::ErrWrite = status {
+        details.push_str(" The sectors written may be write-protected by the option bytes.");
+    }
+    details
+}
+
+impl Error {
+    /// Returns whether the device couldn't be found or opened, which
+    /// [`Error::CouldNotOpenDevice`] used to cover.
+    #[allow(deprecated)]
+    pub fn is_could_not_open(&self) -> bool {
+        matches!(
+            self,
+            Error::CouldNotOpenDevice
+                | Error::DeviceNotFound
+                | Error::AccessDenied
+                | Error::InterfaceUnavailable { .. }
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("The device does not support upload, verification unsupported.")]
+    Unsupported,
+    #[error("Mismatch at offset {offset:#x} (expected: {expected:#04x}, got: {actual:#04x}).")]
+    Mismatch {
+        offset: u32,
+        expected: u8,
+        actual: u8,
+    },
+    #[error("The device returned {got} bytes out of {expected}.")]
+    Truncated { expected: u32, got: u32 },
+    #[error(transparent)]
+    Error(#[from] Error),
 }
 
+/// The timeout of a single USB control transfer, see [`Dfu::set_usb_timeout`].
+pub const DEFAULT_USB_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct DfuLibusb<C: rusb::UsbContext> {
     usb: RefCell<rusb::DeviceHandle<C>>,
     protocol: dfu_core::DfuProtocol<dfu_core::memory_layout::MemoryLayout>,
     timeout: std::time::Duration,
     iface: u16,
+    alt: u8,
     functional_descriptor: dfu_core::functional_descriptor::FunctionalDescriptor,
+    /// Whether a transfer was left unfinished or the device reported an error, see `Drop`.
+    unfinished: Cell<bool>,
     marker: marker::PhantomData<C>,
 }
 
+impl<C: rusb::UsbContext> DfuLibusb<C> {
+    /// Read the string descriptor pointed to by the iString of a status, in the first language of
+    /// the device. Failures are only logged since the string is not essential.
+    fn read_status_string(&self, index: u8) -> Option<String> {
+        let handle = self.usb.borrow();
+        let res =
+            handle
+                .read_languages(self.timeout)
+                .and_then(|languages| match languages.first() {
+                    Some(lang) => handle.read_string_descriptor(*lang, index, self.timeout),
+                    None => Err(rusb::Error::NotFound),
+                });
+        match res {
+            Ok(string) => Some(string),
+            Err(err) => {
+                log::debug!("Could not read the status string {}: {}", index, err);
+                None
+            }
+        }
+    }
+
+    /// Keep track of whether the device is left in the middle of a transfer, from the requests
+    /// sent and the states reported.
+    fn track(&self, request: u8, data: &[u8], requested: usize) {
+        use sync::{DFU_ABORT, DFU_CLRSTATUS, DFU_DETACH, DFU_DNLOAD, DFU_GETSTATUS, DFU_UPLOAD};
+        let unfinished = match request {
+            // The zero-length download starts the manifestation, which must not be disturbed.
+            DFU_DNLOAD => !data.is_empty(),
+            // The upload ends with a short block.
+            DFU_UPLOAD => data.len() == requested,
+            DFU_GETSTATUS if data.len() >= 5 => matches!(
+                dfu_core::State::from(data[4]),
+                dfu_core::State::DfuDnloadSync
+                    | dfu_core::State::DfuDnbusy
+                    | dfu_core::State::DfuDnloadIdle
+                    | dfu_core::State::DfuUploadIdle
+                    | dfu_core::State::DfuError
+            ),
+            DFU_ABORT | DFU_CLRSTATUS | DFU_DETACH => false,
+            _ => return,
+        };
+        self.unfinished.set(unfinished);
+    }
+}
+
+/// Bring the device back toward dfuIDLE, ignoring errors, if it is dropped in the middle of a
+/// transfer, e.g. after an error returned early. Nothing is sent once an operation completed.
+impl<C: rusb::UsbContext> Drop for DfuLibusb<C> {
+    fn drop(&mut self) {
+        if !self.unfinished.get() {
+            return;
+        }
+        log::debug!("Closing the device in the middle of a transfer, sending CLRSTATUS and ABORT");
+        let usb = self.usb.borrow();
+        for request in [sync::DFU_CLRSTATUS, sync::DFU_ABORT] {
+            let _ = usb.write_control(
+                sync::REQUEST_TYPE,
+                request,
+                0,
+                self.iface,
+                &[],
+                self.timeout,
+            );
+        }
+    }
+}
+
 impl<C: rusb::UsbContext> dfu_core::DfuIo for DfuLibusb<C> {
     type Read = usize;
     type Write = usize;
@@ -89,9 +490,11 @@ impl<C: rusb::UsbContext> dfu_core::DfuIo for DfuLibusb<C> {
             );
             let r = res?;
             if r > 0 {
+                self.track(request, &buffer[..r], buffer.len());
                 return Ok(r);
             }
         }
+        self.track(request, &[], buffer.len());
         Ok(0)
     }
 
@@ -117,6 +520,7 @@ impl<C: rusb::UsbContext> dfu_core::DfuIo for DfuLibusb<C> {
             request_type,
         );
         let r = res?;
+        self.track(request, buffer, buffer.len());
         if r == 0 {
             // Something may have gone wrong, assume it didn't
             return Ok(buffer.len());
@@ -137,24 +541,727 @@ impl<C: rusb::UsbContext> dfu_core::DfuIo for DfuLibusb<C> {
     }
 }
 
+impl<C: rusb::UsbContext> Dfu<C> {
+    /// Returns the USB device descriptor of the device.
+    pub fn device_descriptor(&self) -> Result<rusb::DeviceDescriptor, Error> {
+        Ok(self.io().usb.borrow().device().device_descriptor()?)
+    }
+
+    /// Returns the speed the device is connected at, to tell whether a slow download is due to a
+    /// full-speed link.
+    pub fn connection_speed(&self) -> Speed {
+        Speed(self.io().usb.borrow().device().speed())
+    }
+
+    /// Set the timeout of every USB control transfer sent to the device, [`DEFAULT_USB_TIMEOUT`]
+    /// by default.
+    ///
+    /// A transfer taking longer fails with [`rusb::Error::Timeout`], which some devices need to
+    /// exceed while erasing slow flash. This is distinct from the poll timeout the device reports
+    /// between two GETSTATUS requests, see [`DfuSync::set_poll_timeout_cap`], and from the bound
+    /// on a whole download or upload, see [`DfuSync::set_operation_timeout`].
+    pub fn set_usb_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.io_mut().timeout = timeout;
+        self
+    }
+
+    /// Returns the bcdDevice of the device descriptor, the release number of the firmware the
+    /// device runs as binary-coded decimal, e.g. `0x0201` for 2.01.
+    pub fn device_version(&self) -> Result<u16, Error> {
+        let version = self.device_descriptor()?.device_version();
+        let major = version.major() as u16;
+        Ok((major / 10) << 12
+            | (major % 10) << 8
+            | (version.minor() as u16) << 4
+            | version.sub_minor() as u16)
+    }
+
+    /// Returns the manufacturer string of the device, if it has one.
+    pub fn manufacturer_string(&self) -> Result<Option<String>, Error> {
+        self.read_string(rusb::DeviceDescriptor::manufacturer_string_index)
+    }
+
+    /// Returns the product string of the device, if it has one.
+    pub fn product_string(&self) -> Result<Option<String>, Error> {
+        self.read_string(rusb::DeviceDescriptor::product_string_index)
+    }
+
+    /// Returns the serial number string of the device, if it has one.
+    pub fn serial_string(&self) -> Result<Option<String>, Error> {
+        self.read_string(rusb::DeviceDescriptor::serial_number_string_index)
+    }
+
+    /// Read a string descriptor of the device in its first language.
+    fn read_string(
+        &self,
+        index: impl FnOnce(&rusb::DeviceDescriptor) -> Option<u8>,
+    ) -> Result<Option<String>, Error> {
+        let io = self.io();
+        let handle = io.usb.borrow();
+        let index = match index(&handle.device().device_descriptor()?) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        // Devices without any string descriptor may stall the request of the languages.
+        let lang = match handle.read_languages(io.timeout) {
+            Ok(languages) => match languages.first() {
+                Some(x) => *x,
+                None => return Ok(None),
+            },
+            Err(rusb::Error::Pipe) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(
+            handle.read_string_descriptor(lang, index, io.timeout)?,
+        ))
+    }
+
+    /// Download a firmware file into the device.
+    ///
+    /// The DFU suffix of the file is not written to the device, unless
+    /// [`DfuSync::set_strip_suffix`] is disabled. [`Error::SuffixMismatch`] is returned if the
+    /// suffix targets another device, unless [`DfuSync::set_ignore_suffix_mismatch`] is set in
+    /// which case a warning is emitted.
+    pub fn download_file<R: std::io::Read>(
+        &mut self,
+        mut file: DfuFile<R>,
+    ) -> Result<DownloadReport, Error> {
+        if let Some(suffix) = file.suffix() {
+            let res = self.check_suffix(suffix);
+            self.report_failure(res)?;
+            if self.strip_suffix() {
+                log::info!(
+                    "Writing {} bytes, the {}-byte DFU suffix is stripped",
+                    file.len(),
+                    file.suffix_length()
+                );
+            } else {
+                file = file.keep_suffix();
+                log::info!(
+                    "Writing {} bytes, the DFU suffix is retained in the last {} bytes",
+                    file.len(),
+                    file.suffix_length()
+                );
+            }
+        }
+
+        let length = file.len();
+        self.download(file, length)
+    }
+
+    /// Download every image of a DfuSe file through the alt-setting it targets, see
+    /// [`Self::flash_targets`].
+    ///
+    /// The suffix is checked against the device like [`Self::download_file`] does. Returns a
+    /// report per element of the images.
+    pub fn download_dfuse_file(&mut self, file: &DfuSeFile) -> Result<Vec<DownloadReport>, Error> {
+        let res = self.check_suffix(file.suffix());
+        self.report_failure(res)?;
+        let targets: Vec<(u8, u32, &[u8])> = file
+            .targets()
+            .iter()
+            .flat_map(|target| {
+                target
+                    .elements
+                    .iter()
+                    .map(move |(address, data)| (target.alt, *address, data.as_slice()))
+            })
+            .collect();
+        self.flash_targets(&targets)
+    }
+
+    /// Download a firmware file whatever its format, detected with [`detect_format`] given the
+    /// extension of its name if any.
+    ///
+    /// Raw binaries and DFU files are downloaded with [`Self::download_file`], DfuSe files with
+    /// [`Self::download_dfuse_file`], HEX and ELF files with [`DfuSync::download_segments`].
+    /// Returns a report per element of a DfuSe file, a single one otherwise.
+    pub fn download_firmware<R: std::io::Read + std::io::Seek>(
+        &mut self,
+        mut reader: R,
+        extension: Option<&str>,
+    ) -> Result<Vec<DownloadReport>, Error> {
+        match detect_format(&mut reader, extension)? {
+            FirmwareFormat::Raw | FirmwareFormat::Dfu => {
+                Ok(vec![self.download_file(DfuFile::parse(reader)?)?])
+            }
+            FirmwareFormat::DfuSe => self.download_dfuse_file(&DfuSeFile::parse(reader)?),
+            FirmwareFormat::Hex => {
+                let segments = HexFile::parse(reader)?.into_segments();
+                Ok(vec![self.download_segments(&segments)?])
+            }
+            #[cfg(feature = "elf")]
+            FirmwareFormat::Elf => {
+                let segments = ElfImage::load_segments(reader)?;
+                Ok(vec![self.download_segments(&segments)?])
+            }
+            #[cfg(not(feature = "elf"))]
+            FirmwareFormat::Elf => Err(Error::FormatUnsupported(FirmwareFormat::Elf)),
+        }
+    }
+
+    /// Check that a DFU suffix targets the device, see [`DfuSync::set_ignore_suffix_mismatch`].
+    fn check_suffix(&self, suffix: &DfuSuffix) -> Result<(), Error> {
+        let device_descriptor = self.device_descriptor()?;
+        let (vid, pid) = (
+            device_descriptor.vendor_id(),
+            device_descriptor.product_id(),
+        );
+        if !suffix.matches(vid, pid) {
+            if !self.ignore_suffix_mismatch() {
+                return Err(Error::SuffixMismatch {
+                    expected: (suffix.vendor, suffix.product),
+                    got: (vid, pid),
+                });
+            }
+            log::warn!(
+                "The firmware file targets {:04x}:{:04x} but the device is {:04x}:{:04x}",
+                suffix.vendor,
+                suffix.product,
+                vid,
+                pid,
+            );
+        }
+        Ok(())
+    }
+
+    /// Detach a device running its application and open its DFU interface once it is back in DFU
+    /// mode.
+    ///
+    /// A USB reset is issued after the DETACH request unless the device detaches on its own. The
+    /// device is looked for on the same USB port since its IDs may change. [`Error::Timeout`] is
+    /// returned if it does not show up within its detach timeout plus `timeout`.
+    pub fn detach_and_reopen(
+        self,
+        context: &C,
+        iface: u8,
+        alt: u8,
+        timeout: Duration,
+    ) -> Result<Dfu<C>, Error> {
+        let device = self.io().usb.borrow().device();
+        let (bus, ports) = (device.bus_number(), device.port_numbers()?);
+        let functional_descriptor = self.functional_descriptor();
+        let deadline = Instant::now() + functional_descriptor.detach_timeout() + timeout;
+
+        match self.detach() {
+            // The device may drop off the bus before acknowledging the request.
+            Err(Error::LibUsb(rusb::Error::NoDevice | rusb::Error::Io)) => {}
+            res => res?,
+        }
+        if !functional_descriptor.will_detach() {
+            // The device re-enumerates during the reset, which libusb may report as an error.
+            let _ = self.usb_reset();
+        }
+        drop(self);
+
+        Self::reopen_at_port(context, (bus, &ports), None, (iface, alt), deadline)
+    }
+
+    /// Clear the readout protection of the device with [`DfuSync::read_unprotect`] and open it
+    /// again once it is back in DFU mode, with the same interface and alt-setting.
+    ///
+    /// The device erases its whole flash before resetting, which can take several seconds. It is
+    /// looked for on the same USB port after it dropped off the bus, [`Error::Timeout`] is
+    /// returned if it is not back within `timeout`. In dry-run mode the device is returned as is.
+    pub fn read_unprotect_and_reopen(
+        mut self,
+        context: &C,
+        timeout: Duration,
+    ) -> Result<Dfu<C>, Error> {
+        let device = self.io().usb.borrow().device();
+        let (bus, ports, address) = (
+            device.bus_number(),
+            device.port_numbers()?,
+            device.address(),
+        );
+        let (iface, alt) = (self.io().iface as u8, self.alt_setting());
+        let deadline = Instant::now() + timeout;
+
+        let ReopenRequired = self.read_unprotect()?;
+        if self.dry_run() {
+            return Ok(self);
+        }
+        drop(self);
+
+        Self::reopen_at_port(
+            context,
+            (bus, &ports),
+            Some(address),
+            (iface, alt),
+            deadline,
+        )
+    }
+
+    /// Wait for a device in DFU mode on this USB port and open it, until `deadline`.
+    ///
+    /// If `address` is given, the device with this address is skipped until it drops off the bus,
+    /// so that the device is not opened again before it resets. The errors other than those of a
+    /// device still in runtime mode or going away, e.g. [`Error::AccessDenied`] without the udev
+    /// rules, are returned right away.
+    fn reopen_at_port(
+        context: &C,
+        (bus, ports): (u8, &[u8]),
+        mut address: Option<u8>,
+        (iface, alt): (u8, u8),
+        deadline: Instant,
+    ) -> Result<Dfu<C>, Error> {
+        loop {
+            std::thread::sleep(Duration::from_millis(100));
+            let mut still_there = false;
+            for device in context.devices()?.iter() {
+                if device.bus_number() != bus
+                    || device.port_numbers().ok().as_deref() != Some(ports)
+                {
+                    continue;
+                }
+                if address == Some(device.address()) {
+                    still_there = true;
+                    continue;
+                }
+                let res = open_handle(&device)
+                    .and_then(|handle| DfuLibusb::from_usb_device(device, handle, iface, alt));
+                match res {
+                    // Still running the application, or not fully enumerated yet.
+                    Err(
+                        Error::DeviceInRuntimeMode
+                        | Error::LibUsb(rusb::Error::NoDevice | rusb::Error::NotFound),
+                    ) => {}
+                    res => return res,
+                }
+            }
+            if !still_there && address.take().is_some() {
+                log::debug!("The device dropped off the bus");
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    /// Read the write protection of the flash sectors from the option bytes, found in the first
+    /// segment of alt-setting `alt`, and refuse to download to the protected sectors from now on,
+    /// see [`DfuSync::set_write_protected_sectors`]. Returns the protected sectors.
+    ///
+    /// The option bytes are expected in the format of the STM32F2, F4 and F7, see
+    /// [`OptionBytes`]. The device goes back to the current alt-setting afterward.
+    pub fn read_write_protection(&mut self, alt: u8) -> Result<Vec<u32>, Error> {
+        let sectors = self.read_option_bytes_at(alt)?.write_protected_sectors;
+        if !sectors.is_empty() {
+            log::info!("Write-protected flash sectors: {:?}", sectors);
+        }
+        self.set_write_protected_sectors(sectors.clone());
+        Ok(sectors)
+    }
+
+    /// Read and decode the option bytes of an STM32 from the alt-setting named
+    /// `@Option Bytes`, going back to the current alt-setting afterward.
+    ///
+    /// The option bytes are expected in the format of the STM32F2, F4 and F7, see
+    /// [`OptionBytes`] for the layout. [`Error::NoSuchAltName`] is returned if the interface has
+    /// no such alt-setting.
+    pub fn read_option_bytes(&mut self) -> Result<OptionBytes, Error> {
+        let iface = self.io().iface as u8;
+        let alt_settings = DfuLibusb::alt_settings(&self.io().usb.borrow())?;
+        let alt = alt_settings
+            .iter()
+            .filter(|x| x.intf == iface)
+            .find(|x| {
+                x.name
+                    .as_deref()
+                    .filter(|name| name.starts_with(option_bytes::ALT_NAME))
+                    .is_some()
+            })
+            .map(|x| x.alt)
+            .ok_or_else(|| Error::NoSuchAltName {
+                name: option_bytes::ALT_NAME.to_string(),
+                available: alt_settings.iter().filter_map(|x| x.name.clone()).collect(),
+            })?;
+        self.read_option_bytes_at(alt)
+    }
+
+    fn read_option_bytes_at(&mut self, alt: u8) -> Result<OptionBytes, Error> {
+        let current = self.alt_setting();
+        self.set_alt_setting(alt)?;
+        let res = self
+            .memory_layout()
+            .and_then(|x| x.segments().first())
+            .map(|x| x.start)
+            .ok_or(Error::DfuseUnsupported)
+            .and_then(|start| self.read_memory(start, option_bytes::LENGTH as u32));
+        self.set_alt_setting(current)?;
+        let option_bytes = OptionBytes::parse(&res?)?;
+        log::debug!("Option bytes: {:?}", option_bytes);
+        Ok(option_bytes)
+    }
+
+    /// Returns the start address of the memory segment named `name`, e.g. `@Internal Flash`,
+    /// from the memory layouts of the DfuSe alt-settings of the interface.
+    ///
+    /// The leading `@` is optional. Returns `None` if no alt-setting has a memory layout with
+    /// this name, see [`Self::segment_names`].
+    pub fn segment_base(&self, name: &str) -> Option<u32> {
+        let name = name.trim().trim_start_matches('@').trim();
+        self.memory_layouts()
+            .into_iter()
+            .find(|x| x.name() == name)
+            .and_then(|x| x.segments().first().map(|x| x.start))
+    }
+
+    /// Returns the names of the memory segments [`Self::segment_base`] knows about.
+    pub fn segment_names(&self) -> Vec<String> {
+        self.memory_layouts()
+            .into_iter()
+            .map(|x| x.name().to_string())
+            .collect()
+    }
+
+    /// The memory layouts of the alt-settings of the interface, the current one if they can't be
+    /// read.
+    fn memory_layouts(&self) -> Vec<MemoryLayout> {
+        let iface = self.io().iface as u8;
+        match DfuLibusb::alt_settings(&self.io().usb.borrow()) {
+            Ok(alt_settings) => alt_settings
+                .iter()
+                .filter(|x| x.intf == iface)
+                .filter_map(|x| MemoryLayout::parse(x.name.as_deref()?).ok())
+                .collect(),
+            Err(err) => {
+                log::debug!("Could not read the alt-settings: {}", err);
+                self.memory_layout().cloned().into_iter().collect()
+            }
+        }
+    }
+
+    /// Returns the alt-setting of the DFU interface in use, see [`Self::set_alt_setting`].
+    pub fn alt_setting(&self) -> u8 {
+        self.io().alt
+    }
+
+    /// Switch to another alt-setting of the DFU interface, e.g. to write the option bytes of a
+    /// DfuSe device after its flash.
+    ///
+    /// The functional descriptor and the memory layout are read again for the new alt-setting,
+    /// so the next operations target its memory without reopening the device.
+    pub fn set_alt_setting(&mut self, alt: u8) -> Result<(), Error> {
+        let timeout = self.io().timeout;
+        let iface = self.io().iface as u8;
+        let (functional_descriptor, protocol, memory_layout) = {
+            let handle = self.io().usb.borrow();
+            let config_descriptor = handle.device().active_config_descriptor()?;
+            let iface_desc = find_alt_setting(&config_descriptor, iface, alt)?;
+            let lang = *handle
+                .read_languages(timeout)?
+                .first()
+                .ok_or(Error::MissingLanguage)?;
+            let interface_string = handle.read_interface_string(lang, &iface_desc, timeout)?;
+            let functional_descriptor =
+                DfuLibusb::find_functional_descriptor(&handle, &config_descriptor, timeout)
+                    .transpose()?
+                    .ok_or(Error::NoDfuCapableDeviceFound)?;
+            let (protocol, memory_layout) =
+                parse_protocol(&interface_string, functional_descriptor.dfu_version)?;
+            (functional_descriptor, protocol, memory_layout)
+        };
+
+        self.io()
+            .usb
+            .borrow_mut()
+            .set_alternate_setting(iface, alt)?;
+        let io = self.io_mut();
+        io.alt = alt;
+        io.functional_descriptor = functional_descriptor;
+        io.protocol = protocol;
+        self.replace_memory_layout(memory_layout);
+        Ok(())
+    }
+
+    /// Write several targets of a DfuSe device, each one being an `(alt, address, data)` triple,
+    /// like the elements of a multi-target DfuSe file.
+    ///
+    /// The device switches to the alt-setting of every target before it is erased and written.
+    /// Once all of them are written, the device goes back to the alt-setting of the first target
+    /// and leaves DFU mode at its address, unless in dry-run mode or if the manifestation is
+    /// disabled with [`DfuSync::set_manifest_on_download`]. Returns a report per target.
+    pub fn flash_targets(
+        &mut self,
+        targets: &[(u8, u32, &[u8])],
+    ) -> Result<Vec<DownloadReport>, Error> {
+        let res = self.flash_targets_inner(targets);
+        self.report_failure(res)
+    }
+
+    fn flash_targets_inner(
+        &mut self,
+        targets: &[(u8, u32, &[u8])],
+    ) -> Result<Vec<DownloadReport>, Error> {
+        let (first_alt, first_address) = match targets.first() {
+            Some((alt, address, _)) => (*alt, *address),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut reports = Vec::with_capacity(targets.len());
+        for (alt, address, data) in targets {
+            log::debug!(
+                "Writing {} bytes at {:#010x} of alt-setting {}",
+                data.len(),
+                address,
+                alt
+            );
+            self.set_alt_setting(*alt)?;
+            reports.push(self.write_segments(&[(*address, *data)])?);
+        }
+
+        if self.manifest_on_download() {
+            self.report(ProgressEvent::Manifesting);
+            if !self.dry_run() {
+                self.set_alt_setting(first_alt)?;
+                self.leave_at(first_address)?;
+            }
+        }
+        self.report(ProgressEvent::Done { success: true });
+        Ok(reports)
+    }
+}
+
+/// Options of [`DfuLibusb::open_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Detach the kernel driver bound to the DFU interface, if any, on the platforms supporting
+    /// it. The driver is attached again when the device is closed. Enabled by default.
+    pub detach_kernel_driver: bool,
+    /// Apply the workarounds known for the device, see [`Quirk`]. Enabled by default.
+    pub apply_quirks: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            detach_kernel_driver: true,
+            apply_quirks: true,
+        }
+    }
+}
+
+/// A DFU alt-setting of a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AltSetting {
+    /// Interface number.
+    pub intf: u8,
+    /// Alt-setting number.
+    pub alt: u8,
+    /// Interface string, which holds the memory layout on DfuSe devices.
+    pub name: Option<String>,
+}
+
+/// A step of [`DfuLibusb::probe`] and its outcome.
+#[derive(Debug)]
+pub struct ProbeStep {
+    /// What the step checks, e.g. "Open the device".
+    pub description: String,
+    /// What was found, or why the step failed.
+    pub result: Result<String, Error>,
+}
+
+/// Speed of the USB link to a device, see [`Dfu::connection_speed`].
+///
+/// It bounds the throughput of the transfers: a full-speed link can't move much more than 1 MB/s
+/// whatever the transfer size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Speed(pub rusb::Speed);
+
+impl Speed {
+    /// Returns the theoretical maximum bit rate of the link, `None` if the speed is unknown.
+    pub fn max_bit_rate(&self) -> Option<u64> {
+        match self.0 {
+            rusb::Speed::Low => Some(1_500_000),
+            rusb::Speed::Full => Some(12_000_000),
+            rusb::Speed::High => Some(480_000_000),
+            rusb::Speed::Super => Some(5_000_000_000),
+            rusb::Speed::SuperPlus => Some(10_000_000_000),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Speed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (name, max) = match self.0 {
+            rusb::Speed::Low => ("low speed", "1.5 Mbit/s"),
+            rusb::Speed::Full => ("full speed", "12 Mbit/s"),
+            rusb::Speed::High => ("high speed", "480 Mbit/s"),
+            rusb::Speed::Super => ("SuperSpeed", "5 Gbit/s"),
+            rusb::Speed::SuperPlus => ("SuperSpeed+", "10 Gbit/s"),
+            _ => return f.write_str("unknown speed"),
+        };
+        write!(f, "{} ({} max)", name, max)
+    }
+}
+
+/// A device exposing at least one DFU alt-setting, see [`DfuLibusb::enumerate`].
+///
+/// The strings are `None` if the device could not be opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DfuDeviceInfo {
+    /// Vendor ID.
+    pub vid: u16,
+    /// Product ID.
+    pub pid: u16,
+    /// Number of the bus the device is connected to.
+    pub bus: u8,
+    /// Address of the device on its bus.
+    pub address: u8,
+    /// Numbers of the ports from the root hub to the device, which identify the physical slot it
+    /// is plugged in, see [`DfuLibusb::open_by_path`].
+    pub port_numbers: Vec<u8>,
+    /// Manufacturer string.
+    pub manufacturer: Option<String>,
+    /// Product string.
+    pub product: Option<String>,
+    /// Serial number string.
+    pub serial: Option<String>,
+    /// DFU alt-settings of the active configuration.
+    pub alt_settings: Vec<AltSetting>,
+}
+
+impl DfuDeviceInfo {
+    /// Returns the USB path of the device as `bus-port.port...`, as used by udev and `dfu-util -p`.
+    pub fn path(&self) -> String {
+        usb_path(self.bus, &self.port_numbers)
+    }
+}
+
+fn usb_path(bus: u8, ports: &[u8]) -> String {
+    let ports: Vec<String> = ports.iter().map(|x| x.to_string()).collect();
+    format!("{}-{}", bus, ports.join("."))
+}
+
+/// Outcome of the download into one of the devices of [`DfuLibusb::flash_all`].
+pub type FlashResult = (DfuDeviceInfo, Result<DownloadReport, Error>);
+
+/// bInterfaceProtocol of the DFU interface of a device running its application.
+const DFU_PROTOCOL_RUNTIME: u8 = 1;
+/// bInterfaceProtocol of the DFU interface of a device in DFU mode.
+const DFU_PROTOCOL_DFU_MODE: u8 = 2;
+
+/// Returns whether an interface descriptor is a DFU one (application specific class, DFU
+/// subclass).
+fn is_dfu(iface_desc: &rusb::InterfaceDescriptor) -> bool {
+    iface_desc.class_code() == 0xfe && iface_desc.sub_class_code() == 0x01
+}
+
+/// Open a device, telling permission problems apart from the other USB errors.
+fn open_handle<C: rusb::UsbContext>(
+    device: &rusb::Device<C>,
+) -> Result<rusb::DeviceHandle<C>, Error> {
+    device.open().map_err(|err| match err {
+        rusb::Error::Access => Error::AccessDenied,
+        err => err.into(),
+    })
+}
+
+/// Find the DFU alt-setting `alt` of the interface `iface`.
+fn find_alt_setting<'a>(
+    config_descriptor: &'a rusb::ConfigDescriptor,
+    iface: u8,
+    alt: u8,
+) -> Result<rusb::InterfaceDescriptor<'a>, Error> {
+    let interface = config_descriptor
+        .interfaces()
+        .find(|x| x.number() == iface)
+        .ok_or(Error::InterfaceUnavailable { intf: iface })?;
+    let iface_desc = interface
+        .descriptors()
+        .find(|x| x.setting_number() == alt && is_dfu(x))
+        .ok_or_else(|| Error::NoSuchAltSetting {
+            intf: iface,
+            alt,
+            available: interface
+                .descriptors()
+                .filter(is_dfu)
+                .map(|x| x.setting_number())
+                .collect(),
+        })?;
+    Ok(iface_desc)
+}
+
+/// Parse the protocol of an alt-setting from its interface string, along with its memory
+/// layout on DfuSe devices.
+///
+/// This is meant for the implementations of [`DfuIo`] other than [`DfuLibusb`], to return the
+/// protocol from [`DfuIo::protocol`] and give the memory layout to
+/// [`DfuSync::set_memory_layout`].
+pub fn parse_protocol(
+    interface_string: &str,
+    dfu_version: (u8, u8),
+) -> Result<
+    (
+        dfu_core::DfuProtocol<dfu_core::memory_layout::MemoryLayout>,
+        Option<MemoryLayout>,
+    ),
+    Error,
+> {
+    let protocol = dfu_core::DfuProtocol::new(interface_string, dfu_version)?;
+    let memory_layout = match protocol {
+        dfu_core::DfuProtocol::Dfu => None,
+        dfu_core::DfuProtocol::Dfuse { .. } => Some(MemoryLayout::parse(interface_string)?),
+    };
+    Ok((protocol, memory_layout))
+}
+
+struct DeviceArrived<C: rusb::UsbContext>(mpsc::Sender<rusb::Device<C>>);
+
+impl<C: rusb::UsbContext> rusb::Hotplug<C> for DeviceArrived<C> {
+    fn device_arrived(&mut self, device: rusb::Device<C>) {
+        let _ = self.0.send(device);
+    }
+
+    fn device_left(&mut self, _device: rusb::Device<C>) {}
+}
+
 impl<C: rusb::UsbContext> DfuLibusb<C> {
     pub fn open(context: &C, vid: u16, pid: u16, iface: u8, alt: u8) -> Result<Dfu<C>, Error> {
+        Self::open_with(context, vid, pid, iface, alt, OpenOptions::default())
+    }
+
+    /// Like [`Self::open`], with options.
+    pub fn open_with(
+        context: &C,
+        vid: u16,
+        pid: u16,
+        iface: u8,
+        alt: u8,
+        options: OpenOptions,
+    ) -> Result<Dfu<C>, Error> {
         let (device, handle) = Self::open_device(context, vid, pid)?;
-        Self::from_usb_device(device, handle, iface, alt)
+        Self::from_usb_device_with(device, handle, iface, alt, options)
     }
 
+    /// Open the DFU interface `iface` of a device, using the alt-setting `alt`.
+    ///
+    /// [`Error::DeviceInRuntimeMode`] is returned if the device only exposes a DFU runtime
+    /// interface, i.e. it runs its application and must be detached first.
+    /// [`Error::InterfaceBusy`] is returned if the interface is claimed by a kernel driver that
+    /// can't be detached.
     pub fn from_usb_device(
+        device: rusb::Device<C>,
+        handle: rusb::DeviceHandle<C>,
+        iface: u8,
+        alt: u8,
+    ) -> Result<Dfu<C>, Error> {
+        Self::from_usb_device_with(device, handle, iface, alt, OpenOptions::default())
+    }
+
+    fn from_usb_device_with(
         device: rusb::Device<C>,
         mut handle: rusb::DeviceHandle<C>,
         iface: u8,
         alt: u8,
+        options: OpenOptions,
     ) -> Result<Dfu<C>, Error> {
         let timeout = std::time::Duration::from_secs(3);
-        handle.claim_interface(iface)?;
-        handle.set_alternate_setting(iface, alt)?;
         let device_descriptor = device.device_descriptor()?;
         let languages = handle.read_languages(timeout)?;
-        let lang = languages.get(0).ok_or(Error::MissingLanguage)?;
+        let lang = languages.first().ok_or(Error::MissingLanguage)?;
 
         for index in 0..device_descriptor.num_configurations() {
             let config_descriptor = device.config_descriptor(index)?;
@@ -163,42 +1270,670 @@ impl<C: rusb::UsbContext> DfuLibusb<C> {
                 Self::find_functional_descriptor(&handle, &config_descriptor, timeout)
                     .transpose()?
             {
-                let interface = config_descriptor
+                let protocols: Vec<u8> = config_descriptor
                     .interfaces()
-                    .find(|x| x.number() == iface)
-                    .ok_or(Error::InvalidInterface)?;
-                let iface_desc = interface
-                    .descriptors()
-                    .find(|x| x.setting_number() == alt)
-                    .ok_or(Error::InvalidAlt)?;
+                    .flat_map(|x| x.descriptors())
+                    .filter(is_dfu)
+                    .map(|x| x.protocol_code())
+                    .collect();
+                if protocols.contains(&DFU_PROTOCOL_RUNTIME)
+                    && !protocols.contains(&DFU_PROTOCOL_DFU_MODE)
+                {
+                    return Err(Error::DeviceInRuntimeMode);
+                }
 
+                let iface_desc = find_alt_setting(&config_descriptor, iface, alt)?;
                 let interface_string = handle.read_interface_string(*lang, &iface_desc, timeout)?;
-                let protocol = dfu_core::DfuProtocol::new(
-                    &interface_string,
-                    functional_descriptor.dfu_version,
-                )?;
+                let (protocol, memory_layout) =
+                    parse_protocol(&interface_string, functional_descriptor.dfu_version)?;
+
+                Self::claim_interface(&mut handle, iface, options)?;
+                handle.set_alternate_setting(iface, alt)?;
 
                 let io = DfuLibusb {
                     usb: RefCell::new(handle),
                     protocol,
-                    timeout,
+                    timeout: DEFAULT_USB_TIMEOUT,
+                    iface: iface as u16,
+                    alt,
+                    functional_descriptor,
+                    unfinished: Cell::new(false),
+                    marker: marker::PhantomData,
+                };
+
+                let mut dfu = DfuSync::new(io);
+                dfu.set_status_string_reader(DfuLibusb::read_status_string);
+                if let Some(memory_layout) = memory_layout {
+                    dfu.set_memory_layout(memory_layout);
+                }
+                if options.apply_quirks {
+                    let (vid, pid) = (
+                        device_descriptor.vendor_id(),
+                        device_descriptor.product_id(),
+                    );
+                    if let Some(quirk) = find_quirk(vid, pid) {
+                        quirk.apply(&mut dfu);
+                    }
+                }
+
+                return Ok(dfu);
+            }
+        }
+
+        Err(Error::NoDfuCapableDeviceFound)
+    }
+
+    /// Open the DFU runtime interface of a device running its application, e.g. to detach it with
+    /// [`Dfu::detach_and_reopen`].
+    pub fn open_runtime(context: &C, vid: u16, pid: u16) -> Result<Dfu<C>, Error> {
+        let timeout = std::time::Duration::from_secs(3);
+        let (device, mut handle) = Self::open_device(context, vid, pid)?;
+        let device_descriptor = device.device_descriptor()?;
+
+        for index in 0..device_descriptor.num_configurations() {
+            let config_descriptor = device.config_descriptor(index)?;
+            let iface = match config_descriptor
+                .interfaces()
+                .flat_map(|x| x.descriptors())
+                .find(|x| is_dfu(x) && x.protocol_code() == DFU_PROTOCOL_RUNTIME)
+            {
+                Some(iface_desc) => iface_desc.interface_number(),
+                None => continue,
+            };
+
+            if let Some(functional_descriptor) =
+                Self::find_functional_descriptor(&handle, &config_descriptor, timeout)
+                    .transpose()?
+            {
+                Self::claim_interface(&mut handle, iface, OpenOptions::default())?;
+
+                let io = DfuLibusb {
+                    usb: RefCell::new(handle),
+                    protocol: dfu_core::DfuProtocol::Dfu,
+                    timeout: DEFAULT_USB_TIMEOUT,
                     iface: iface as u16,
+                    alt: 0,
                     functional_descriptor,
+                    unfinished: Cell::new(false),
                     marker: marker::PhantomData,
                 };
 
-                return Ok(dfu_core::sync::DfuSync::new(io));
+                let mut dfu = DfuSync::new(io);
+                dfu.set_status_string_reader(DfuLibusb::read_status_string);
+                return Ok(dfu);
             }
         }
 
         Err(Error::NoDfuCapableDeviceFound)
     }
 
+    /// Block until a device with the given vendor and product IDs is connected.
+    ///
+    /// A hotplug callback is used on the platforms supporting it, the devices are polled
+    /// otherwise. [`Error::Timeout`] is returned if no device shows up within `timeout`.
+    pub fn wait_for_device(
+        context: &C,
+        vid: u16,
+        pid: u16,
+        timeout: Duration,
+    ) -> Result<rusb::Device<C>, Error>
+    where
+        C: 'static,
+    {
+        let deadline = Instant::now() + timeout;
+
+        if rusb::has_hotplug() {
+            let (sender, receiver) = mpsc::channel();
+            let _registration = rusb::HotplugBuilder::new()
+                .vendor_id(vid)
+                .product_id(pid)
+                .enumerate(true)
+                .register(context, Box::new(DeviceArrived(sender)))?;
+
+            loop {
+                if let Ok(device) = receiver.try_recv() {
+                    return Ok(device);
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(Error::Timeout);
+                }
+                context.handle_events(Some(remaining))?;
+            }
+        }
+
+        loop {
+            if let Some(device) = Self::find_device(context, vid, pid)? {
+                return Ok(device);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Timeout);
+            }
+            std::thread::sleep(remaining.min(Duration::from_millis(250)));
+        }
+    }
+
+    /// Block until a device with the given vendor and product IDs is in DFU mode and open it, e.g.
+    /// after resetting it into its bootloader with [`reset_mcu`].
+    ///
+    /// Devices still running their application or not fully enumerated yet are waited for, see
+    /// [`Self::wait_for_device`]. [`Error::Timeout`] is returned if the device can't be opened
+    /// within `timeout`, [`Duration::MAX`] waits forever.
+    pub fn wait_for_dfu_mode(
+        context: &C,
+        vid: u16,
+        pid: u16,
+        iface: u8,
+        alt: u8,
+        timeout: Duration,
+    ) -> Result<Dfu<C>, Error>
+    where
+        C: 'static,
+    {
+        Self::wait_for_dfu_mode_with(context, vid, pid, timeout, |context| {
+            Self::open(context, vid, pid, iface, alt)
+        })
+    }
+
+    /// Like [`Self::wait_for_dfu_mode`] but the device is opened with `open`, e.g. to select it
+    /// with [`Self::open_by_serial`].
+    pub fn wait_for_dfu_mode_with(
+        context: &C,
+        vid: u16,
+        pid: u16,
+        timeout: Duration,
+        mut open: impl FnMut(&C) -> Result<Dfu<C>, Error>,
+    ) -> Result<Dfu<C>, Error>
+    where
+        C: 'static,
+    {
+        let deadline = Instant::now().checked_add(timeout);
+        let poll = Duration::from_millis(250);
+
+        loop {
+            match open(context) {
+                Err(Error::DeviceNotFound | Error::DeviceInRuntimeMode | Error::LibUsb(_)) => {}
+                res => return res,
+            }
+            let remaining = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => poll,
+            };
+            if remaining.is_zero() {
+                return Err(Error::Timeout);
+            }
+            match Self::wait_for_device(context, vid, pid, remaining.min(poll)) {
+                // The device may be there already but not ready to be opened.
+                Ok(_) => std::thread::sleep(remaining.min(poll)),
+                Err(Error::Timeout) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// List the DFU alt-settings of the active configuration of a device.
+    pub fn alt_settings(handle: &rusb::DeviceHandle<C>) -> Result<Vec<AltSetting>, Error> {
+        let timeout = std::time::Duration::from_secs(3);
+        let config_descriptor = handle.device().active_config_descriptor()?;
+        let lang = handle.read_languages(timeout)?.first().copied();
+
+        Ok(Self::read_alt_settings(
+            &config_descriptor,
+            Some(handle).zip(lang),
+            timeout,
+        ))
+    }
+
+    /// List the devices exposing DFU alt-settings.
+    pub fn enumerate(context: &C) -> Result<Vec<DfuDeviceInfo>, Error> {
+        let mut devices = Vec::new();
+
+        for device in context.devices()?.iter() {
+            let has_dfu = device.active_config_descriptor().map(|x| {
+                x.interfaces()
+                    .flat_map(|x| x.descriptors())
+                    .any(|x| is_dfu(&x))
+            });
+            if let Ok(true) = has_dfu {
+                if let Ok(info) = Self::device_info(&device) {
+                    devices.push(info);
+                }
+            }
+        }
+
+        Ok(devices)
+    }
+
+    /// Check that a device can be used, step by step, without writing anything to it.
+    ///
+    /// The device is found and opened, its DFU interface is claimed then released, and the
+    /// functional descriptor and the memory layout of every DFU alt-setting are read. The steps
+    /// stop at the first failure, whose error tells what is wrong: [`Error::DeviceNotFound`] for
+    /// the cabling or the IDs, [`Error::AccessDenied`] for the permissions,
+    /// [`Error::InterfaceBusy`] for a kernel driver holding the interface, etc. The kernel driver
+    /// is not detached.
+    pub fn probe(context: &C, vid: u16, pid: u16) -> Vec<ProbeStep> {
+        let mut steps = Vec::new();
+        // Record the outcome of a step, and stop if it failed.
+        macro_rules! step {
+            ($description:expr, $result:expr) => {{
+                match $result {
+                    Ok((value, found)) => {
+                        steps.push(ProbeStep {
+                            description: $description.into(),
+                            result: Ok(found),
+                        });
+                        value
+                    }
+                    Err(err) => {
+                        steps.push(ProbeStep {
+                            description: $description.into(),
+                            result: Err(err),
+                        });
+                        return steps;
+                    }
+                }
+            }};
+        }
+        let timeout = std::time::Duration::from_secs(3);
+
+        let device = step!(
+            format!("Find the device {:04x}:{:04x}", vid, pid),
+            Self::find_devices(context, vid, pid).and_then(|mut devices| match devices.len() {
+                0 => Err(Error::DeviceNotFound),
+                1 => {
+                    let device = devices.remove(0);
+                    let found = format!(
+                        "found on bus {} at address {}",
+                        device.bus_number(),
+                        device.address()
+                    );
+                    Ok((device, found))
+                }
+                _ => Err(Error::MultipleDevices(
+                    devices
+                        .iter()
+                        .filter_map(|x| Self::device_info(x).ok())
+                        .collect(),
+                )),
+            })
+        );
+        let mut handle = step!(
+            "Open the device",
+            open_handle(&device).map(|x| (x, "opened".to_string()))
+        );
+        let lang = step!(
+            "Read the string descriptors",
+            handle
+                .read_languages(timeout)
+                .map_err(Error::from)
+                .and_then(|x| x.first().copied().ok_or(Error::MissingLanguage))
+                .map(|lang| (lang, format!("language {:#06x}", lang.lang_id())))
+        );
+        let config_descriptor = step!(
+            "Read the configuration descriptor",
+            device
+                .active_config_descriptor()
+                .map_err(Error::from)
+                .map(|x| {
+                    let found = format!("configuration {}", x.number());
+                    (x, found)
+                })
+        );
+        let functional_descriptor = step!(
+            "Read the DFU functional descriptor",
+            Self::find_functional_descriptor(&handle, &config_descriptor, timeout)
+                .unwrap_or(Err(Error::NoDfuCapableDeviceFound))
+                .map(|x| {
+                    let found = format!(
+                        "DFU {}.{:02x}, transfer size {}",
+                        x.dfu_version.0, x.dfu_version.1, x.transfer_size
+                    );
+                    (x, found)
+                })
+        );
+        let ifaces: Vec<rusb::InterfaceDescriptor> = config_descriptor
+            .interfaces()
+            .flat_map(|x| x.descriptors())
+            .filter(is_dfu)
+            .collect();
+        step!(
+            "Check that the device is in DFU mode",
+            if ifaces
+                .iter()
+                .any(|x| x.protocol_code() == DFU_PROTOCOL_DFU_MODE)
+            {
+                Ok(((), "in DFU mode".to_string()))
+            } else {
+                Err(Error::DeviceInRuntimeMode)
+            }
+        );
+        let iface = ifaces[0].interface_number();
+        let options = OpenOptions {
+            detach_kernel_driver: false,
+            ..OpenOptions::default()
+        };
+        step!(
+            format!("Claim interface {}", iface),
+            Self::claim_interface(&mut handle, iface, options)
+                .and_then(|()| Ok(handle.release_interface(iface)?))
+                .map(|()| ((), "claimed and released".to_string()))
+        );
+        for iface_desc in &ifaces {
+            step!(
+                format!(
+                    "Read the memory layout of alt-setting {}",
+                    iface_desc.setting_number()
+                ),
+                handle
+                    .read_interface_string(lang, iface_desc, timeout)
+                    .map_err(Error::from)
+                    .and_then(|name| {
+                        let found = match parse_protocol(&name, functional_descriptor.dfu_version)?
+                        {
+                            (_, Some(memory_layout)) => format!(
+                                "{:?}, DfuSe with {} segments",
+                                name,
+                                memory_layout.segments().len()
+                            ),
+                            (_, None) => format!("{:?}, plain DFU", name),
+                        };
+                        Ok(((), found))
+                    })
+            );
+        }
+
+        steps
+    }
+
+    /// Download the same firmware into every DFU device with the given vendor and product IDs.
+    ///
+    /// Up to `concurrency` devices are flashed at the same time, each one is opened and flashed
+    /// on its own thread. `progress` is called from these threads with the device the event is
+    /// about. The results are in the order of [`Self::enumerate`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn flash_all(
+        context: &C,
+        vid: u16,
+        pid: u16,
+        iface: u8,
+        alt: u8,
+        firmware: &[u8],
+        concurrency: usize,
+        progress: impl Fn(&DfuDeviceInfo, ProgressEvent) + Send + Sync + 'static,
+    ) -> Result<Vec<FlashResult>, Error> {
+        let devices: Vec<DfuDeviceInfo> = Self::enumerate(context)?
+            .into_iter()
+            .filter(|x| x.vid == vid && x.pid == pid)
+            .collect();
+        let count = devices.len();
+        let queue = Mutex::new(devices.into_iter().enumerate());
+        let results = Mutex::new(Vec::with_capacity(count));
+        let progress = Arc::new(progress);
+
+        std::thread::scope(|scope| {
+            for _ in 0..concurrency.max(1).min(count) {
+                scope.spawn(|| loop {
+                    let (index, info) = match queue.lock().unwrap().next() {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    let res = Self::open_by_location(context, info.bus, info.address, iface, alt)
+                        .and_then(|mut dfu| {
+                            let progress = progress.clone();
+                            let device = info.clone();
+                            dfu.with_progress_ex(move |event| progress(&device, event));
+                            dfu.download_from_slice(firmware)
+                        });
+                    results.lock().unwrap().push((index, info, res));
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, _, _)| *index);
+        Ok(results
+            .into_iter()
+            .map(|(_, info, res)| (info, res))
+            .collect())
+    }
+
+    fn open_by_location(
+        context: &C,
+        bus: u8,
+        address: u8,
+        iface: u8,
+        alt: u8,
+    ) -> Result<Dfu<C>, Error> {
+        let device = context
+            .devices()?
+            .iter()
+            .find(|x| x.bus_number() == bus && x.address() == address)
+            .ok_or(Error::DeviceNotFound)?;
+        let handle = open_handle(&device)?;
+        Self::from_usb_device(device, handle, iface, alt)
+    }
+
+    /// Open a device by its serial number, for when several devices have the same vendor and
+    /// product IDs.
+    pub fn open_by_serial(
+        context: &C,
+        vid: u16,
+        pid: u16,
+        serial: &str,
+        iface: u8,
+        alt: u8,
+    ) -> Result<Dfu<C>, Error> {
+        let timeout = std::time::Duration::from_secs(3);
+        let mut denied = false;
+        for device in Self::find_devices(context, vid, pid)? {
+            let handle = match open_handle(&device) {
+                Ok(x) => x,
+                Err(err) => {
+                    denied |= matches!(err, Error::AccessDenied);
+                    continue;
+                }
+            };
+            let device_desc = device.device_descriptor()?;
+            // A device without string descriptors can't be the one looked for.
+            let languages = match handle.read_languages(timeout) {
+                Ok(x) => x,
+                Err(err) => {
+                    log::debug!(
+                        "Skipping the device at bus {} address {} without languages: {}",
+                        device.bus_number(),
+                        device.address(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            let device_serial = languages.first().and_then(|&lang| {
+                handle
+                    .read_serial_number_string(lang, &device_desc, timeout)
+                    .ok()
+            });
+            if device_serial.as_deref() == Some(serial) {
+                return Self::from_usb_device(device, handle, iface, alt);
+            }
+        }
+
+        // The device may be one of those that couldn't be opened.
+        Err(if denied {
+            Error::AccessDenied
+        } else {
+            Error::DeviceNotFound
+        })
+    }
+
+    /// Open the DFU alt-setting of a device whose interface string matches `name`, like the `-a`
+    /// option of dfu-util accepts a name.
+    ///
+    /// The comparison is case sensitive. An alt-setting named exactly `name` is preferred,
+    /// otherwise the first one whose name starts with `name` is chosen, so `@Internal Flash`
+    /// matches the full DfuSe memory layout string. [`Error::NoSuchAltName`] lists the names
+    /// available if none matches.
+    pub fn open_by_alt_name(context: &C, vid: u16, pid: u16, name: &str) -> Result<Dfu<C>, Error> {
+        let (device, handle) = Self::open_device(context, vid, pid)?;
+        let alt_settings = Self::alt_settings(&handle)?;
+        let find = |matches: &dyn Fn(&str) -> bool| {
+            alt_settings
+                .iter()
+                .find(|x| x.name.as_deref().filter(|x| matches(x)).is_some())
+        };
+
+        match find(&|x| x == name).or_else(|| find(&|x| x.starts_with(name))) {
+            Some(alt_setting) => {
+                Self::from_usb_device(device, handle, alt_setting.intf, alt_setting.alt)
+            }
+            None => Err(Error::NoSuchAltName {
+                name: name.to_string(),
+                available: alt_settings.iter().filter_map(|x| x.name.clone()).collect(),
+            }),
+        }
+    }
+
+    /// Open the device plugged in a given physical USB port, for when the serial numbers of the
+    /// devices can't tell them apart.
+    ///
+    /// `ports` are the numbers of the ports from the root hub of the bus to the device, i.e. the
+    /// path `1-4.2` is bus 1 and ports `[4, 2]`.
+    pub fn open_by_path(
+        context: &C,
+        bus: u8,
+        ports: &[u8],
+        iface: u8,
+        alt: u8,
+    ) -> Result<Dfu<C>, Error> {
+        for device in context.devices()?.iter() {
+            if device.bus_number() == bus && device.port_numbers().ok().as_deref() == Some(ports) {
+                let handle = open_handle(&device)?;
+                return Self::from_usb_device(device, handle, iface, alt);
+            }
+        }
+
+        Err(Error::DeviceNotFound)
+    }
+
+    fn claim_interface(
+        handle: &mut rusb::DeviceHandle<C>,
+        iface: u8,
+        options: OpenOptions,
+    ) -> Result<(), Error> {
+        if options.detach_kernel_driver {
+            match handle.set_auto_detach_kernel_driver(true) {
+                Ok(()) | Err(rusb::Error::NotSupported) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        match handle.claim_interface(iface) {
+            Err(rusb::Error::Busy) => Err(Error::InterfaceBusy {
+                driver: Self::kernel_driver(&handle.device(), iface),
+            }),
+            res => Ok(res?),
+        }
+    }
+
+    /// Returns the name of the kernel driver bound to an interface, as found in sysfs.
+    #[cfg(target_os = "linux")]
+    fn kernel_driver(device: &rusb::Device<C>, iface: u8) -> Option<String> {
+        let path = format!(
+            "/sys/bus/usb/devices/{}:{}.{}/driver",
+            usb_path(device.bus_number(), &device.port_numbers().ok()?),
+            device.active_config_descriptor().ok()?.number(),
+            iface,
+        );
+        let driver = std::fs::read_link(path).ok()?;
+        Some(driver.file_name()?.to_string_lossy().into_owned())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn kernel_driver(_device: &rusb::Device<C>, _iface: u8) -> Option<String> {
+        None
+    }
+
+    fn device_info(device: &rusb::Device<C>) -> Result<DfuDeviceInfo, Error> {
+        let timeout = std::time::Duration::from_secs(3);
+        let device_desc = device.device_descriptor()?;
+        let config_descriptor = device.active_config_descriptor()?;
+
+        let handle = device.open().ok();
+        let strings = handle.as_ref().and_then(|handle| {
+            let lang = *handle.read_languages(timeout).ok()?.first()?;
+            Some((handle, lang))
+        });
+
+        Ok(DfuDeviceInfo {
+            vid: device_desc.vendor_id(),
+            pid: device_desc.product_id(),
+            bus: device.bus_number(),
+            address: device.address(),
+            port_numbers: device.port_numbers().unwrap_or_default(),
+            manufacturer: strings.and_then(|(handle, lang)| {
+                handle
+                    .read_manufacturer_string(lang, &device_desc, timeout)
+                    .ok()
+            }),
+            product: strings.and_then(|(handle, lang)| {
+                handle.read_product_string(lang, &device_desc, timeout).ok()
+            }),
+            serial: strings.and_then(|(handle, lang)| {
+                handle
+                    .read_serial_number_string(lang, &device_desc, timeout)
+                    .ok()
+            }),
+            alt_settings: Self::read_alt_settings(&config_descriptor, strings, timeout),
+        })
+    }
+
+    fn read_alt_settings(
+        config_descriptor: &rusb::ConfigDescriptor,
+        strings: Option<(&rusb::DeviceHandle<C>, rusb::Language)>,
+        timeout: std::time::Duration,
+    ) -> Vec<AltSetting> {
+        config_descriptor
+            .interfaces()
+            .flat_map(|x| x.descriptors())
+            .filter(is_dfu)
+            .map(|iface_desc| AltSetting {
+                intf: iface_desc.interface_number(),
+                alt: iface_desc.setting_number(),
+                name: strings.and_then(|(handle, lang)| {
+                    handle
+                        .read_interface_string(lang, &iface_desc, timeout)
+                        .ok()
+                }),
+            })
+            .collect()
+    }
+
     fn open_device(
         context: &C,
         vid: u16,
         pid: u16,
     ) -> Result<(rusb::Device<C>, rusb::DeviceHandle<C>), Error> {
+        let mut devices = Self::find_devices(context, vid, pid)?;
+        if devices.len() > 1 {
+            return Err(Error::MultipleDevices(
+                devices
+                    .iter()
+                    .filter_map(|x| Self::device_info(x).ok())
+                    .collect(),
+            ));
+        }
+        let device = devices.pop().ok_or(Error::DeviceNotFound)?;
+        let handle = open_handle(&device)?;
+        Ok((device, handle))
+    }
+
+    fn find_device(context: &C, vid: u16, pid: u16) -> Result<Option<rusb::Device<C>>, Error> {
+        Ok(Self::find_devices(context, vid, pid)?.into_iter().next())
+    }
+
+    fn find_devices(context: &C, vid: u16, pid: u16) -> Result<Vec<rusb::Device<C>>, Error> {
+        let mut devices = Vec::new();
         for device in context.devices()?.iter() {
             let device_desc = match device.device_descriptor() {
                 Ok(x) => x,
@@ -206,12 +1941,11 @@ impl<C: rusb::UsbContext> DfuLibusb<C> {
             };
 
             if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-                let handle = device.open()?;
-                return Ok((device, handle));
+                devices.push(device);
             }
         }
 
-        Err(Error::CouldNotOpenDevice)
+        Ok(devices)
     }
 
     fn find_functional_descriptor(
@@ -0,0 +1,228 @@
+use std::convert::TryFrom;
+use std::fmt;
+use thiserror::Error;
+
+/// Error while parsing a DfuSe memory layout.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("missing segments in memory layout: {0}")]
+    MissingSegments(String),
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
+    #[error("invalid segment format: {0}")]
+    InvalidSegment(String),
+    #[error("could not parse page count: {0}")]
+    InvalidPageCount(String),
+    #[error("could not parse page size: {0}")]
+    InvalidPageSize(String),
+    #[error("invalid size unit: {0}")]
+    InvalidUnit(String),
+    #[error("invalid permissions: {0}")]
+    InvalidPermissions(String),
+    #[error("the segment at {0:#010x} overflows the address space")]
+    Overflow(u32),
+}
+
+/// A contiguous region of memory made of pages of the same size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Address of the first page.
+    pub start: u32,
+    /// Size of a page in bytes.
+    pub page_size: u32,
+    /// Number of pages.
+    pub page_count: u32,
+    /// The segment can be read (uploaded).
+    pub readable: bool,
+    /// The segment can be erased.
+    pub erasable: bool,
+    /// The segment can be written (downloaded).
+    pub writable: bool,
+}
+
+impl Segment {
+    /// Returns the address right after the last page of the segment.
+    pub fn end(&self) -> u32 {
+        self.start + self.page_size * self.page_count
+    }
+
+    /// Returns whether the address is inside the segment.
+    pub fn contains(&self, address: u32) -> bool {
+        (self.start..self.end()).contains(&address)
+    }
+
+    /// Returns the bounds of the page that contains the address.
+    pub fn page_at(&self, address: u32) -> Option<std::ops::Range<u32>> {
+        if !self.contains(address) {
+            return None;
+        }
+        let start = self.start + (address - self.start) / self.page_size * self.page_size;
+        Some(start..start + self.page_size)
+    }
+}
+
+impl fmt::Display for Segment {
+    /// Formats the segment on one line, e.g.
+    /// `0x08000000-0x08010000: 4 pages of 16 KiB (64 KiB), readable, erasable, writable`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let permissions: Vec<&str> = [
+            (self.readable, "readable"),
+            (self.erasable, "erasable"),
+            (self.writable, "writable"),
+        ]
+        .iter()
+        .filter(|(x, _)| *x)
+        .map(|(_, x)| *x)
+        .collect();
+
+        write!(
+            f,
+            "{:#010x}-{:#010x}: {} page{} of {} ({}), {}",
+            self.start,
+            self.end(),
+            self.page_count,
+            if self.page_count == 1 { "" } else { "s" },
+            HumanSize(self.page_size as u64),
+            HumanSize(self.page_size as u64 * self.page_count as u64),
+            if permissions.is_empty() {
+                "no access".to_string()
+            } else {
+                permissions.join(", ")
+            },
+        )
+    }
+}
+
+/// A size in bytes, formatted with the largest binary unit it is a multiple of.
+struct HumanSize(u64);
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            x if x >= 1 << 20 && x % (1 << 20) == 0 => write!(f, "{} MiB", x >> 20),
+            x if x >= 1 << 10 && x % (1 << 10) == 0 => write!(f, "{} KiB", x >> 10),
+            x => write!(f, "{} B", x),
+        }
+    }
+}
+
+/// Memory layout of a DfuSe alt-setting.
+///
+/// It is parsed from the interface string of the alt-setting, e.g.
+/// `@Internal Flash /0x08000000/04*016Kg,01*064Kg,07*128Kg`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryLayout {
+    name: String,
+    segments: Vec<Segment>,
+}
+
+impl MemoryLayout {
+    /// Parse a DfuSe memory layout string.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut parts = s.split('/');
+        let name = parts
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_start_matches('@')
+            .trim()
+            .to_string();
+
+        let mut segments = Vec::new();
+        while let Some(address) = parts.next() {
+            let address = address.trim();
+            if address.is_empty() {
+                continue;
+            }
+            let mut start = address
+                .strip_prefix("0x")
+                .or_else(|| address.strip_prefix("0X"))
+                .and_then(|x| u32::from_str_radix(x, 16).ok())
+                .ok_or_else(|| Error::InvalidAddress(address.into()))?;
+            let pages = parts
+                .next()
+                .ok_or_else(|| Error::MissingSegments(s.into()))?;
+
+            for segment in pages.split(',') {
+                let segment = Self::parse_segment(start, segment)?;
+                start = u32::try_from(
+                    segment.start as u64 + segment.page_size as u64 * segment.page_count as u64,
+                )
+                .map_err(|_| Error::Overflow(segment.start))?;
+                segments.push(segment);
+            }
+        }
+
+        if segments.is_empty() {
+            return Err(Error::MissingSegments(s.into()));
+        }
+
+        Ok(Self { name, segments })
+    }
+
+    fn parse_segment(start: u32, s: &str) -> Result<Segment, Error> {
+        let (count, size) = s
+            .trim()
+            .split_once('*')
+            .ok_or_else(|| Error::InvalidSegment(s.into()))?;
+        let page_count = count
+            .trim()
+            .parse()
+            .map_err(|_| Error::InvalidPageCount(count.into()))?;
+
+        let digits = size
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(|| Error::InvalidPermissions(s.into()))?;
+        let (size, rest) = size.split_at(digits);
+        let size: u32 = size
+            .parse()
+            .map_err(|_| Error::InvalidPageSize(size.into()))?;
+
+        let rest: Vec<char> = rest.chars().filter(|c| !c.is_whitespace()).collect();
+        let (unit, permissions) = match rest.as_slice() {
+            [permissions] => ('B', *permissions),
+            [unit, permissions] => (*unit, *permissions),
+            _ => return Err(Error::InvalidPermissions(rest.into_iter().collect())),
+        };
+        let multiplier = match unit {
+            'B' => 1,
+            'K' => 1024,
+            'M' => 1024 * 1024,
+            other => return Err(Error::InvalidUnit(other.into())),
+        };
+        let page_size = size
+            .checked_mul(multiplier)
+            .filter(|&x| x > 0)
+            .ok_or_else(|| Error::InvalidPageSize(s.into()))?;
+
+        // 'a' to 'g' encode the bits readable (1), erasable (2) and writable (4).
+        let bits = match permissions {
+            c @ 'a'..='g' => c as u8 - b'a' + 1,
+            other => return Err(Error::InvalidPermissions(other.into())),
+        };
+
+        Ok(Segment {
+            start,
+            page_size,
+            page_count,
+            readable: bits & 1 != 0,
+            erasable: bits & 2 != 0,
+            writable: bits & 4 != 0,
+        })
+    }
+
+    /// Returns the name of the alt-setting, without the leading `@`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the segments of the layout.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Returns the segment that contains the address.
+    pub fn segment_at(&self, address: u32) -> Option<&Segment> {
+        self.segments.iter().find(|x| x.contains(address))
+    }
+}
@@ -0,0 +1,383 @@
+//! A [`DfuIo`] without USB to test flashing flows, enabled by the `testing` feature.
+//!
+//! [`MockDfuIo`] records every control transfer and behaves like a well-behaved device unless
+//! GETSTATUS responses are scripted with [`MockDfuIo::push_status`], or failing transfers with
+//! [`MockDfuIo::push_error`]. It is also a reference for
+//! the implementations of [`DfuIo`] on other transports, see [`DfuSync::new`].
+
+use crate::{parse_protocol, DfuSync, Error, MemoryLayout};
+use dfu_core::functional_descriptor::FunctionalDescriptor;
+use dfu_core::{DfuIo, DfuProtocol, State, Status};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+/// A control transfer received by a [`MockDfuIo`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlTransfer {
+    /// A device-to-host transfer, with the length of the buffer given.
+    In {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        length: usize,
+    },
+    /// A host-to-device transfer, with its payload.
+    Out {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        data: Vec<u8>,
+    },
+}
+
+/// A response to GETSTATUS scripted with [`MockDfuIo::push_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockStatus {
+    /// bStatus.
+    pub status: Status,
+    /// bState, the device stays in this state afterwards.
+    pub state: State,
+    /// bwPollTimeout in milliseconds.
+    pub poll_timeout: u32,
+}
+
+struct Device {
+    state: State,
+    transfers: Vec<ControlTransfer>,
+    statuses: VecDeque<Result<MockStatus, Error>>,
+    upload: VecDeque<u8>,
+    resets: usize,
+    status_string: Option<(u8, String)>,
+    string_reads: usize,
+    errors: VecDeque<(u8, u16, Error)>,
+}
+
+/// A [`DfuIo`] recording the control transfers it gets instead of sending them.
+///
+/// Without scripted responses the device succeeds at everything and never asks to wait: the
+/// downloads go through dfuDNLOAD-SYNC to dfuDNLOAD-IDLE, the manifestation ends in dfuIDLE, or in
+/// dfuMANIFEST-WAIT-RESET if the device is not manifestation tolerant, and the uploads return the
+/// bytes given to [`MockDfuIo::set_upload_data`].
+pub struct MockDfuIo {
+    device: RefCell<Device>,
+    protocol: DfuProtocol<dfu_core::memory_layout::MemoryLayout>,
+    memory_layout: Option<MemoryLayout>,
+    functional_descriptor: FunctionalDescriptor,
+}
+
+impl MockDfuIo {
+    /// Create a plain DFU device with this transfer size.
+    pub fn dfu(transfer_size: u16) -> Self {
+        Self {
+            device: RefCell::new(Device {
+                state: State::DfuIdle,
+                transfers: Vec::new(),
+                statuses: VecDeque::new(),
+                upload: VecDeque::new(),
+                resets: 0,
+                status_string: None,
+                string_reads: 0,
+                errors: VecDeque::new(),
+            }),
+            protocol: DfuProtocol::Dfu,
+            memory_layout: None,
+            functional_descriptor: FunctionalDescriptor {
+                can_download: true,
+                can_upload: true,
+                manifestation_tolerant: true,
+                will_detach: false,
+                detach_timeout: 0,
+                transfer_size,
+                dfu_version: (1, 0x10),
+            },
+        }
+    }
+
+    /// Create a DfuSe device from the interface string describing its memory, e.g.
+    /// `@Internal Flash /0x08000000/64*001Kg`.
+    pub fn dfuse(interface_string: &str, transfer_size: u16) -> Result<Self, Error> {
+        let mut mock = Self::dfu(transfer_size);
+        mock.functional_descriptor.dfu_version = (1, 0x1a);
+        let (protocol, memory_layout) =
+            parse_protocol(interface_string, mock.functional_descriptor.dfu_version)?;
+        mock.protocol = protocol;
+        mock.memory_layout = memory_layout;
+        Ok(mock)
+    }
+
+    /// Replace the functional descriptor of the device, e.g. to make it not manifestation
+    /// tolerant.
+    pub fn with_functional_descriptor(
+        mut self,
+        functional_descriptor: FunctionalDescriptor,
+    ) -> Self {
+        self.functional_descriptor = functional_descriptor;
+        self
+    }
+
+    /// Wrap the device in a [`DfuSync`], with the memory layout of DfuSe devices set like
+    /// [`crate::DfuLibusb::open`] does.
+    pub fn into_dfu(self) -> DfuSync<Self> {
+        let memory_layout = self.memory_layout.clone();
+        let mut dfu = DfuSync::new(self);
+        dfu.set_status_string_reader(Self::read_status_string);
+        if let Some(memory_layout) = memory_layout {
+            dfu.set_memory_layout(memory_layout);
+        }
+        dfu
+    }
+
+    /// Queue a response to the next GETSTATUS that isn't answered by an earlier one.
+    pub fn push_status(&self, status: Status, state: State, poll_timeout: u32) -> &Self {
+        self.device.borrow_mut().statuses.push_back(Ok(MockStatus {
+            status,
+            state,
+            poll_timeout,
+        }));
+        self
+    }
+
+    /// Queue a GETSTATUS failing with `error`, in turn with the responses of
+    /// [`Self::push_status`], e.g. for a device that stalls the status request following a
+    /// command.
+    pub fn push_status_error(&self, error: Error) -> &Self {
+        self.device.borrow_mut().statuses.push_back(Err(error));
+        self
+    }
+
+    /// Make the next transfer with this request and wValue fail with `error`, e.g. a
+    /// `rusb::Error::Pipe` for a stall. The transfer is still recorded but doesn't change the state
+    /// of the device.
+    pub fn push_error(&self, request: u8, value: u16, error: Error) -> &Self {
+        self.device
+            .borrow_mut()
+            .errors
+            .push_back((request, value, error));
+        self
+    }
+
+    /// Point to this string in the iString of the statuses reporting an error.
+    pub fn set_status_string(&self, index: u8, string: &str) -> &Self {
+        self.device.borrow_mut().status_string = Some((index, string.to_string()));
+        self
+    }
+
+    /// Set the bytes returned by the next uploads.
+    pub fn set_upload_data(&self, data: &[u8]) -> &Self {
+        self.device.borrow_mut().upload = data.iter().copied().collect();
+        self
+    }
+
+    /// Set the state of the device.
+    pub fn set_state(&self, state: State) -> &Self {
+        self.device.borrow_mut().state = state;
+        self
+    }
+
+    /// Returns the state of the device.
+    pub fn state(&self) -> State {
+        self.device.borrow().state
+    }
+
+    /// Returns every control transfer received so far, in order.
+    pub fn transfers(&self) -> Vec<ControlTransfer> {
+        self.device.borrow().transfers.clone()
+    }
+
+    /// Returns the `(wBlockNum, payload)` of the DNLOAD requests sending firmware, which excludes
+    /// the DfuSe commands and the zero-length download ending the transfers.
+    pub fn downloaded_blocks(&self) -> Vec<(u16, Vec<u8>)> {
+        let dfuse = matches!(self.protocol, DfuProtocol::Dfuse { .. });
+        self.device
+            .borrow()
+            .transfers
+            .iter()
+            .filter_map(|transfer| match transfer {
+                ControlTransfer::Out {
+                    request: DFU_DNLOAD,
+                    value,
+                    data,
+                    ..
+                } if !data.is_empty() && (!dfuse || *value != 0) => Some((*value, data.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the concatenated payloads of [`Self::downloaded_blocks`].
+    pub fn downloaded(&self) -> Vec<u8> {
+        self.downloaded_blocks()
+            .into_iter()
+            .flat_map(|(_, data)| data)
+            .collect()
+    }
+
+    /// Returns the number of USB resets requested.
+    pub fn resets(&self) -> usize {
+        self.device.borrow().resets
+    }
+
+    /// Returns the number of string descriptors read, see [`Self::set_status_string`].
+    pub fn string_reads(&self) -> usize {
+        self.device.borrow().string_reads
+    }
+
+    /// Forget the control transfers received so far.
+    pub fn clear_transfers(&self) {
+        self.device.borrow_mut().transfers.clear();
+    }
+}
+
+impl MockDfuIo {
+    fn read_status_string(&self, index: u8) -> Option<String> {
+        let mut device = self.device.borrow_mut();
+        device.string_reads += 1;
+        device
+            .status_string
+            .clone()
+            .filter(|(x, _)| *x == index)
+            .map(|(_, string)| string)
+    }
+}
+
+impl Device {
+    /// Take the error pushed for this transfer, if any.
+    fn take_error(&mut self, request: u8, value: u16) -> Result<(), Error> {
+        match self
+            .errors
+            .iter()
+            .position(|(x, y, _)| *x == request && *y == value)
+        {
+            Some(i) => Err(self.errors.remove(i).unwrap().2),
+            None => Ok(()),
+        }
+    }
+
+    /// The status a well-behaved device reports, moving on to its next state.
+    fn next_status(&mut self, manifestation_tolerant: bool) -> MockStatus {
+        self.state = match self.state {
+            State::DfuDnloadSync | State::DfuDnbusy => State::DfuDnloadIdle,
+            State::DfuManifestSync | State::DfuManifest if manifestation_tolerant => State::DfuIdle,
+            State::DfuManifestSync | State::DfuManifest => State::DfuManifestWaitReset,
+            state => state,
+        };
+        MockStatus {
+            status: Status::Ok,
+            state: self.state,
+            poll_timeout: 0,
+        }
+    }
+}
+
+impl DfuIo for MockDfuIo {
+    type Read = usize;
+    type Write = usize;
+    type Reset = ();
+    type Error = Error;
+    type MemoryLayout = dfu_core::memory_layout::MemoryLayout;
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut device = self.device.borrow_mut();
+        device.transfers.push(ControlTransfer::In {
+            request_type,
+            request,
+            value,
+            length: buffer.len(),
+        });
+        device.take_error(request, value)?;
+        match request {
+            DFU_GETSTATUS => {
+                let status = match device.statuses.pop_front().transpose()? {
+                    Some(status) => {
+                        device.state = status.state;
+                        status
+                    }
+                    None => device.next_status(self.functional_descriptor.manifestation_tolerant),
+                };
+                let poll_timeout = status.poll_timeout.to_le_bytes();
+                let string_index = match &device.status_string {
+                    Some((index, _)) if status.status != Status::Ok => *index,
+                    _ => 0,
+                };
+                let response = [
+                    status.status.into(),
+                    poll_timeout[0],
+                    poll_timeout[1],
+                    poll_timeout[2],
+                    status.state.into(),
+                    string_index,
+                ];
+                let n = buffer.len().min(response.len());
+                buffer[..n].copy_from_slice(&response[..n]);
+                Ok(n)
+            }
+            DFU_GETSTATE => {
+                buffer[0] = device.state.into();
+                Ok(1)
+            }
+            DFU_UPLOAD => {
+                let n = buffer.len().min(device.upload.len());
+                for (dst, src) in buffer.iter_mut().zip(device.upload.drain(..n)) {
+                    *dst = src;
+                }
+                device.state = if n < buffer.len() {
+                    State::DfuIdle
+                } else {
+                    State::DfuUploadIdle
+                };
+                Ok(n)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &[u8],
+    ) -> Result<usize, Error> {
+        let mut device = self.device.borrow_mut();
+        device.transfers.push(ControlTransfer::Out {
+            request_type,
+            request,
+            value,
+            data: buffer.to_vec(),
+        });
+        device.take_error(request, value)?;
+        match request {
+            DFU_DNLOAD if buffer.is_empty() => device.state = State::DfuManifestSync,
+            DFU_DNLOAD => device.state = State::DfuDnloadSync,
+            DFU_CLRSTATUS | DFU_ABORT => device.state = State::DfuIdle,
+            _ => {}
+        }
+        Ok(buffer.len())
+    }
+
+    fn usb_reset(&self) -> Result<(), Error> {
+        self.device.borrow_mut().resets += 1;
+        Ok(())
+    }
+
+    fn protocol(&self) -> &DfuProtocol<Self::MemoryLayout> {
+        &self.protocol
+    }
+
+    fn functional_descriptor(&self) -> &FunctionalDescriptor {
+        &self.functional_descriptor
+    }
+}
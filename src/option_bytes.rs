@@ -0,0 +1,91 @@
+use crate::Error;
+
+/// Name the DfuSe bootloaders of the STM32 give to the alt-setting of the option bytes.
+pub(crate) const ALT_NAME: &str = "@Option Bytes";
+
+/// Number of bytes of the option bytes region decoded by [`OptionBytes::parse`].
+pub(crate) const LENGTH: usize = 16;
+
+/// Read protection level of the flash, from the RDP option byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadProtection {
+    /// No protection (RDP is 0xAA).
+    Level0,
+    /// The flash can't be read through the debug interface nor the bootloader, going back to
+    /// level 0 mass erases it (any RDP value but 0xAA and 0xCC).
+    Level1,
+    /// The debug interface and the bootloader are disabled for good (RDP is 0xCC).
+    Level2,
+}
+
+/// Option bytes of an STM32, see [`crate::Dfu::read_option_bytes`].
+///
+/// The layout decoded is the one of the STM32F2, F4 and F7 (sectors 0 to 11 only), as found at
+/// the start of their option bytes alt-setting:
+///
+/// - offset 0: USER, with BOR_LEV in bits 2-3, WDG_SW, nRST_STOP and nRST_STDBY in bits 5-7;
+/// - offset 1: RDP;
+/// - offset 8-9: nWRP of sectors 0 to 11 in bits 0-11, SPRMOD in bit 15.
+///
+/// The other families lay out their option bytes differently, [`OptionBytes::raw`] keeps the
+/// bytes as read to decode them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionBytes {
+    /// Read protection level.
+    pub read_protection: ReadProtection,
+    /// Raw RDP option byte.
+    pub rdp: u8,
+    /// Brown-out reset threshold level, from 0 (off) to 3 (highest threshold).
+    pub bor_level: u8,
+    /// The watchdog is started by software rather than by hardware (WDG_SW).
+    pub software_watchdog: bool,
+    /// Entering the Stop mode doesn't reset the device (nRST_STOP).
+    pub no_reset_on_stop: bool,
+    /// Entering the Standby mode doesn't reset the device (nRST_STDBY).
+    pub no_reset_on_standby: bool,
+    /// The nWRP bits select the sectors protected against reading (PCROP) rather than against
+    /// writing (SPRMOD).
+    pub pcrop: bool,
+    /// Flash sectors protected against writing, which includes the PCROP ones.
+    pub write_protected_sectors: Vec<u32>,
+    /// The bytes decoded.
+    pub raw: Vec<u8>,
+}
+
+impl OptionBytes {
+    /// Decode the option bytes read at the start of the option bytes alt-setting.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 10 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let user = bytes[0];
+        let rdp = bytes[1];
+        let nwrp = u16::from_le_bytes([bytes[8], bytes[9]]);
+
+        let read_protection = match rdp {
+            0xaa => ReadProtection::Level0,
+            0xcc => ReadProtection::Level2,
+            _ => ReadProtection::Level1,
+        };
+        // BOR_LEV is 0b11 when the brown-out reset is off and 0b00 for the level 3.
+        let bor_level = 3 - ((user >> 2) & 0b11);
+        // With SPRMOD set the bits select the sectors protected against reading (PCROP), which
+        // are protected against writing too.
+        let pcrop = nwrp & 0x8000 != 0;
+        let write_protected_sectors = (0..12)
+            .filter(|i| (nwrp & (1 << i) != 0) == pcrop)
+            .collect();
+
+        Ok(Self {
+            read_protection,
+            rdp,
+            bor_level,
+            software_watchdog: user & 0x20 != 0,
+            no_reset_on_stop: user & 0x40 != 0,
+            no_reset_on_standby: user & 0x80 != 0,
+            pcrop,
+            write_protected_sectors,
+            raw: bytes.to_vec(),
+        })
+    }
+}
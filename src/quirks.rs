@@ -0,0 +1,97 @@
+use crate::{DfuSync, Error, FunctionalDescriptorOverrides};
+use dfu_core::DfuIo;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Workarounds known to be needed by the devices with these IDs, see [`register_quirk`].
+///
+/// They are applied when the device is opened, unless disabled with
+/// [`crate::OpenOptions::apply_quirks`]. Every workaround is only a default: the setters of
+/// [`DfuSync`] called afterwards take precedence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quirk {
+    /// Vendor ID of the device.
+    pub vid: u16,
+    /// Product ID of the device, `None` for every product of the vendor.
+    pub pid: Option<u16>,
+    /// Transfer size to use whatever the device reports, see
+    /// [`FunctionalDescriptorOverrides::transfer_size`].
+    pub transfer_size: Option<u16>,
+    /// See [`DfuSync::set_inter_block_delay`].
+    pub inter_block_delay: Option<Duration>,
+    /// Whether the device stays responsive after the manifestation, whatever it reports, see
+    /// [`FunctionalDescriptorOverrides::manifestation_tolerant`].
+    pub manifestation_tolerant: Option<bool>,
+}
+
+impl Quirk {
+    /// LeafLabs Maple bootloader (1EAF:0003): it handles blocks of 1 KiB at most and jumps to the
+    /// application once the firmware is manifested.
+    pub const MAPLE: Self = Self {
+        vid: 0x1eaf,
+        pid: Some(0x0003),
+        transfer_size: Some(1024),
+        inter_block_delay: None,
+        manifestation_tolerant: Some(false),
+    };
+
+    /// Returns whether the workarounds are meant for the device with these IDs.
+    pub fn matches(&self, vid: u16, pid: u16) -> bool {
+        self.vid == vid && self.pid.filter(|x| *x != pid).is_none()
+    }
+
+    /// Apply the workarounds to an opened device.
+    ///
+    /// The fields of the functional descriptor overridden are only logged at the debug level,
+    /// unlike with [`DfuSync::set_functional_descriptor_overrides`].
+    pub fn apply<IO>(&self, dfu: &mut DfuSync<IO>)
+    where
+        IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = Error>,
+    {
+        log::info!(
+            "Applying the workarounds known for {:04x}:{}",
+            self.vid,
+            self.pid
+                .map_or_else(|| "*".to_string(), |x| format!("{:04x}", x))
+        );
+        if self.transfer_size.is_some() || self.manifestation_tolerant.is_some() {
+            dfu.apply_quirk_overrides(FunctionalDescriptorOverrides {
+                transfer_size: self.transfer_size,
+                manifestation_tolerant: self.manifestation_tolerant,
+                will_detach: None,
+            });
+        }
+        if let Some(delay) = self.inter_block_delay {
+            dfu.set_inter_block_delay(delay);
+        }
+    }
+}
+
+/// The workarounds shipped with the crate.
+const BUILTIN_QUIRKS: &[Quirk] = &[Quirk::MAPLE];
+
+/// The workarounds registered with [`register_quirk`].
+static REGISTERED_QUIRKS: Mutex<Vec<Quirk>> = Mutex::new(Vec::new());
+
+/// Register workarounds for a device, applied when it is opened from now on.
+///
+/// They replace the built-in ones and those registered before for the same device. Register a
+/// [`Quirk`] without any workaround to disable the built-in ones.
+pub fn register_quirk(quirk: Quirk) {
+    REGISTERED_QUIRKS
+        .lock()
+        .unwrap_or_else(|x| x.into_inner())
+        .push(quirk);
+}
+
+/// Returns the workarounds applied to the device with these IDs, if any: the last registered
+/// with [`register_quirk`], or else the built-in ones.
+pub fn find_quirk(vid: u16, pid: u16) -> Option<Quirk> {
+    let registered = REGISTERED_QUIRKS.lock().unwrap_or_else(|x| x.into_inner());
+    registered
+        .iter()
+        .rev()
+        .chain(BUILTIN_QUIRKS)
+        .find(|x| x.matches(vid, pid))
+        .cloned()
+}
@@ -0,0 +1,2563 @@
+use crate::digest::{Digest, DigestAlgorithm, Hasher};
+use crate::memory_layout::{MemoryLayout, Segment};
+use crate::{Error, VerifyError};
+use crate::{FunctionalDescriptor, FunctionalDescriptorOverrides};
+use dfu_core::get_status::GetStatusMessage;
+use dfu_core::{DfuIo, DfuProtocol, State};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Cursor;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub(crate) const REQUEST_TYPE: u8 = 0b00100001;
+pub(crate) const DFU_DETACH: u8 = 0;
+pub(crate) const DFU_DNLOAD: u8 = 1;
+pub(crate) const DFU_UPLOAD: u8 = 2;
+pub(crate) const DFU_GETSTATUS: u8 = 3;
+pub(crate) const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+pub(crate) const DFU_ABORT: u8 = 6;
+
+/// Largest buffer [`DfuSync::read_memory`] allocates unless told otherwise.
+const DEFAULT_READ_MEMORY_LIMIT: u32 = 16 * 1024 * 1024;
+
+/// Transfer sizes a device can sensibly report, see [`DfuSync::set_transfer_size_fallback`].
+const VALID_TRANSFER_SIZES: std::ops::RangeInclusive<u16> = 1..=4096;
+
+/// Transfer size used by default when the one of the device is not valid.
+const DEFAULT_TRANSFER_SIZE_FALLBACK: u16 = 1024;
+
+/// Longest sleep between two checks of the cancel flag while waiting for a poll timeout.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of blocks wBlockNum can address.
+const BLOCK_COUNT: u64 = u16::MAX as u64 + 1;
+
+/// Log target of the payloads of the control transfers.
+///
+/// They are logged at the trace level but only with this target, so that they can be filtered out
+/// of traces of real firmware downloads.
+pub const PAYLOAD_LOG_TARGET: &str = "dfu_libusb::payload";
+
+/// Borrow a [`DfuIo`] so the state machines of `dfu_core` can be driven without giving away the
+/// IO.
+///
+/// Control transfers failing with a recoverable error are retried.
+struct IoRef<'io, IO> {
+    io: &'io IO,
+    retries: u32,
+    functional_descriptor: dfu_core::functional_descriptor::FunctionalDescriptor,
+}
+
+impl<'io, IO: DfuIo<Read = usize, Write = usize, Error = Error>> IoRef<'io, IO> {
+    fn new(
+        io: &'io IO,
+        retries: u32,
+        functional_descriptor: dfu_core::functional_descriptor::FunctionalDescriptor,
+    ) -> Self {
+        Self {
+            io,
+            retries,
+            functional_descriptor,
+        }
+    }
+
+    fn retry<T>(&self, mut transfer: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            match transfer() {
+                Err(Error::LibUsb(
+                    err @ (rusb::Error::Pipe | rusb::Error::Timeout | rusb::Error::Io),
+                )) if attempt < self.retries => {
+                    attempt += 1;
+                    log::trace!(
+                        "Control transfer failed: {}, retrying ({}/{})",
+                        err,
+                        attempt,
+                        self.retries,
+                    );
+                    std::thread::sleep(Duration::from_millis(10 << attempt.min(8)));
+                    let _ = self.io.write_control(REQUEST_TYPE, DFU_CLRSTATUS, 0, &[]);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Log a control transfer at the trace level, the way `dfu-util -v -v` would.
+    ///
+    /// The payload is logged with the target [`PAYLOAD_LOG_TARGET`] only.
+    fn log_transfer(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        length: usize,
+        data: &[u8],
+        res: &Result<usize, Error>,
+    ) {
+        let name = match request {
+            DFU_DETACH => "DFU_DETACH",
+            DFU_DNLOAD => "DFU_DNLOAD",
+            DFU_UPLOAD => "DFU_UPLOAD",
+            DFU_GETSTATUS => "DFU_GETSTATUS",
+            DFU_CLRSTATUS => "DFU_CLRSTATUS",
+            DFU_GETSTATE => "DFU_GETSTATE",
+            DFU_ABORT => "DFU_ABORT",
+            _ => "unknown request",
+        };
+        let details = match (request, data) {
+            _ if res.is_err() => String::new(),
+            (DFU_GETSTATUS, &[status, t0, t1, t2, state, _]) => format!(
+                ": bStatus={:?} bState={:?} bwPollTimeout={}ms",
+                dfu_core::Status::from(status),
+                State::from(state),
+                u32::from_le_bytes([t0, t1, t2, 0]),
+            ),
+            (DFU_GETSTATE, &[state]) => format!(": bState={:?}", State::from(state)),
+            (DFU_DNLOAD, &[command, a0, a1, a2, a3]) if value == 0 && self.is_dfuse() => {
+                let address = u32::from_le_bytes([a0, a1, a2, a3]);
+                match command {
+                    0x21 => format!(": DfuSe set address pointer to {:#010x}", address),
+                    0x41 => format!(": DfuSe erase page at {:#010x}", address),
+                    _ => format!(": DfuSe command {:#04x} at {:#010x}", command, address),
+                }
+            }
+            (DFU_DNLOAD, &[0x41]) if value == 0 && self.is_dfuse() => ": DfuSe mass erase".into(),
+            (DFU_DNLOAD, &[0x92]) if value == 0 && self.is_dfuse() => {
+                ": DfuSe read unprotect".into()
+            }
+            _ => String::new(),
+        };
+        match res {
+            Ok(n) => log::trace!(
+                "{} bmRequestType={:#04x} wValue={} wLength={} -> {} bytes{}",
+                name,
+                request_type,
+                value,
+                length,
+                n,
+                details,
+            ),
+            Err(err) => log::trace!(
+                "{} bmRequestType={:#04x} wValue={} wLength={} failed: {}",
+                name,
+                request_type,
+                value,
+                length,
+                err,
+            ),
+        }
+        if !data.is_empty() {
+            log::trace!(target: PAYLOAD_LOG_TARGET, "{} payload: {:02x?}", name, data);
+        }
+    }
+
+    fn is_dfuse(&self) -> bool {
+        matches!(self.io.protocol(), DfuProtocol::Dfuse { .. })
+    }
+}
+
+impl<'io, IO: DfuIo<Read = usize, Write = usize, Error = Error>> DfuIo for IoRef<'io, IO> {
+    type Read = IO::Read;
+    type Write = IO::Write;
+    type Reset = IO::Reset;
+    type Error = IO::Error;
+    type MemoryLayout = IO::MemoryLayout;
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &mut [u8],
+    ) -> Result<Self::Read, Self::Error> {
+        let res = self.retry(|| self.io.read_control(request_type, request, value, buffer));
+        if log::log_enabled!(log::Level::Trace) {
+            let data = res
+                .as_ref()
+                .map_or(&[][..], |&n| &buffer[..n.min(buffer.len())]);
+            self.log_transfer(
+                request_type | 0x80,
+                request,
+                value,
+                buffer.len(),
+                data,
+                &res,
+            );
+        }
+        res
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        buffer: &[u8],
+    ) -> Result<Self::Write, Self::Error> {
+        let res = self.retry(|| self.io.write_control(request_type, request, value, buffer));
+        if log::log_enabled!(log::Level::Trace) {
+            self.log_transfer(request_type, request, value, buffer.len(), buffer, &res);
+        }
+        res
+    }
+
+    fn usb_reset(&self) -> Result<Self::Reset, Self::Error> {
+        self.io.usb_reset()
+    }
+
+    fn protocol(&self) -> &DfuProtocol<Self::MemoryLayout> {
+        self.io.protocol()
+    }
+
+    fn functional_descriptor(&self) -> &dfu_core::functional_descriptor::FunctionalDescriptor {
+        &self.functional_descriptor
+    }
+}
+
+struct Buffer<R: std::io::Read> {
+    reader: R,
+    buf: Box<[u8]>,
+    level: usize,
+}
+
+impl<R: std::io::Read> Buffer<R> {
+    fn new(size: usize, reader: R) -> Self {
+        Self {
+            reader,
+            buf: vec![0; size].into_boxed_slice(),
+            level: 0,
+        }
+    }
+
+    fn fill_buf(&mut self) -> Result<&[u8], std::io::Error> {
+        while self.level < self.buf.len() {
+            let dst = &mut self.buf[self.level..];
+            let r = self.reader.read(dst)?;
+            if r == 0 {
+                break;
+            } else {
+                self.level += r;
+            }
+        }
+        Ok(&self.buf[0..self.level])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if amt >= self.level {
+            self.level = 0;
+        } else {
+            self.buf.copy_within(amt..self.level, 0);
+            self.level -= amt;
+        }
+    }
+}
+
+/// Reader failing with [`std::io::ErrorKind::UnexpectedEof`] if it ends before `remaining`
+/// bytes instead of letting the download end early.
+struct ExactReader<R: std::io::Read> {
+    reader: R,
+    remaining: u32,
+}
+
+impl<R: std::io::Read> std::io::Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let max = buf.len().min(self.remaining as usize);
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.reader.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("the firmware ended {} bytes early", self.remaining),
+            ));
+        }
+        self.remaining -= n as u32;
+        Ok(n)
+    }
+}
+
+/// Progress of an operation, passed to the closure given to [`DfuSync::with_progress_ex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A page is being erased before the download (DfuSe only). `page` starts at 0.
+    Erasing { page: u32, total_pages: u32 },
+    /// `bytes` more bytes have been downloaded.
+    Downloading { bytes: usize },
+    /// `bytes` more bytes have been uploaded, e.g. by [`DfuSync::upload`] or [`DfuSync::verify`].
+    Uploading { bytes: usize },
+    /// The whole firmware has been downloaded and the device is manifesting it.
+    Manifesting,
+    /// The download or upload ended, successfully or not: this is the last event of every
+    /// operation. When `success` is false the error is returned by the operation. An upload may
+    /// end before the length requested if the device has fewer bytes to give.
+    Done { success: bool },
+}
+
+/// Cumulative progress of a transfer, passed to the closure given to
+/// [`DfuSync::with_progress_stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressStats {
+    /// Number of bytes transferred so far.
+    pub bytes: u64,
+    /// Time elapsed since the first bytes were transferred.
+    pub elapsed: Duration,
+    /// Throughput of the last chunk, in bytes per second.
+    pub rate: f64,
+    /// Throughput since the first bytes were transferred, in bytes per second.
+    pub average_rate: f64,
+}
+
+impl ProgressStats {
+    /// Estimate the time left to transfer `total` bytes at the average rate.
+    pub fn eta(&self, total: u64) -> Option<Duration> {
+        if self.average_rate <= 0.0 {
+            return None;
+        }
+        let left = total.saturating_sub(self.bytes) as f64;
+        Some(Duration::from_secs_f64(left / self.average_rate))
+    }
+
+    /// Turn a closure receiving stats into one receiving [`ProgressEvent`]s, e.g. to give it to
+    /// [`crate::DfuBuilder::progress`].
+    ///
+    /// The stats are computed from the bytes downloaded or uploaded and start over after
+    /// [`ProgressEvent::Done`]. The first chunk only starts the clock: the rates are zero until
+    /// the second one.
+    pub fn track(mut progress: impl FnMut(ProgressStats)) -> impl FnMut(ProgressEvent) {
+        // Time and bytes of the first chunk, then of the last one.
+        let mut first: Option<(Instant, u64)> = None;
+        let mut last = Instant::now();
+        let mut total = 0;
+        move |event| {
+            let bytes = match event {
+                ProgressEvent::Downloading { bytes } | ProgressEvent::Uploading { bytes } => {
+                    bytes as u64
+                }
+                ProgressEvent::Done { .. } => {
+                    first = None;
+                    total = 0;
+                    return;
+                }
+                _ => return,
+            };
+            let now = Instant::now();
+            total += bytes;
+            let (start, skipped) = *first.get_or_insert((now, total));
+            let rate = |bytes: u64, duration: Duration| match duration.as_secs_f64() {
+                x if x > 0.0 => bytes as f64 / x,
+                _ => 0.0,
+            };
+            let stats = ProgressStats {
+                bytes: total,
+                elapsed: now - start,
+                rate: if now == start {
+                    0.0
+                } else {
+                    rate(bytes, now - last)
+                },
+                average_rate: rate(total - skipped, now - start),
+            };
+            last = now;
+            progress(stats);
+        }
+    }
+}
+
+/// Summary of a download, returned by [`DfuSync::download`] and friends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadReport {
+    /// Number of bytes of firmware sent to the device.
+    pub bytes_written: u32,
+    /// Time taken by the whole download, including erasing and manifestation.
+    pub duration: Duration,
+    /// Digest of the bytes sent, if enabled with [`DfuSync::set_digest`].
+    pub digest: Option<Digest>,
+    /// Time spent waiting for the poll timeouts requested by the device in its status, while
+    /// erasing, writing and manifesting.
+    pub total_poll_wait: Duration,
+    /// Time spent in the control transfers sending the firmware.
+    pub transfer_time: Duration,
+    /// Time spent sleeping between the blocks, see [`DfuSync::set_inter_block_delay`].
+    pub total_inter_block_delay: Duration,
+    /// Number of pages left untouched because they already held the firmware, see
+    /// [`DfuSync::download_diff`].
+    pub pages_skipped: u32,
+}
+
+/// Returned by the operations after which the device resets and re-enumerates, see
+/// [`DfuSync::read_unprotect`].
+///
+/// The device can't be talked to through this instance anymore: it must be opened again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use = "the device must be opened again"]
+pub struct ReopenRequired;
+
+/// Bytes sent by a download so far.
+struct Transfer {
+    start: Instant,
+    bytes: u32,
+    hasher: Option<Hasher>,
+    transfer_time: Duration,
+    inter_block_delay: Duration,
+}
+
+impl Transfer {
+    fn new(algorithm: Option<DigestAlgorithm>) -> Self {
+        Self {
+            start: Instant::now(),
+            bytes: 0,
+            hasher: algorithm.map(Hasher::new),
+            transfer_time: Duration::ZERO,
+            inter_block_delay: Duration::ZERO,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u32;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(data);
+        }
+    }
+
+    fn finish(self, total_poll_wait: Duration) -> DownloadReport {
+        DownloadReport {
+            bytes_written: self.bytes,
+            duration: self.start.elapsed(),
+            digest: self.hasher.map(Hasher::finalize),
+            total_poll_wait,
+            transfer_time: self.transfer_time,
+            total_inter_block_delay: self.inter_block_delay,
+            pages_skipped: 0,
+        }
+    }
+}
+
+/// The progress callback, with the bytes transferred coalesced when an interval is set, see
+/// [`DfuSync::set_progress_interval`].
+#[derive(Default)]
+struct Progress {
+    callback: Option<Box<dyn FnMut(ProgressEvent)>>,
+    interval: Option<Duration>,
+    /// Bytes transferred but not reported yet.
+    pending: Option<ProgressEvent>,
+    last: Option<Instant>,
+}
+
+impl Progress {
+    fn report(&mut self, event: ProgressEvent) {
+        let interval = match self.interval {
+            Some(x) if self.callback.is_some() => x,
+            _ => return self.send(event),
+        };
+        let pending = match (self.pending.take(), event) {
+            (
+                Some(ProgressEvent::Downloading { bytes: a }),
+                ProgressEvent::Downloading { bytes: b },
+            ) => ProgressEvent::Downloading { bytes: a + b },
+            (
+                Some(ProgressEvent::Uploading { bytes: a }),
+                ProgressEvent::Uploading { bytes: b },
+            ) => ProgressEvent::Uploading { bytes: a + b },
+            (pending, ProgressEvent::Downloading { .. } | ProgressEvent::Uploading { .. }) => {
+                if let Some(pending) = pending {
+                    self.send(pending);
+                }
+                event
+            }
+            (pending, event) => {
+                // Anything else, and the end of the transfer in particular, is never held back.
+                if let Some(pending) = pending {
+                    self.send(pending);
+                }
+                if let ProgressEvent::Done { .. } = event {
+                    self.last = None;
+                }
+                return self.send(event);
+            }
+        };
+        let now = Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < interval => self.pending = Some(pending),
+            _ => {
+                self.last = Some(now);
+                self.send(pending);
+            }
+        }
+    }
+
+    /// Report the bytes held back, at the end of an operation.
+    fn flush(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.send(pending);
+        }
+    }
+
+    fn send(&mut self, event: ProgressEvent) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(event);
+        }
+    }
+}
+
+/// Synchronous implementation of DFU on top of a [`DfuIo`].
+///
+/// The device is usually opened through libusb with [`crate::DfuLibusb`], whose own operations
+/// are those of [`crate::Dfu`]. Any other transport, e.g. a WebUSB bridge, can be used by
+/// implementing [`DfuIo`] for it, see [`Self::new`].
+pub struct DfuSync<IO>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = Error>,
+{
+    io: IO,
+    override_address: Option<u32>,
+    progress: Progress,
+    erase_progress: Option<Box<dyn FnMut(u32, u32)>>,
+    busy_timeout_factor: u32,
+    max_poll_iterations: Option<u32>,
+    memory_layout: Option<MemoryLayout>,
+    poll_timeout_min: Duration,
+    poll_timeout_cap: Option<Duration>,
+    inter_block_delay: Duration,
+    retries: u32,
+    transfer_size: Option<u16>,
+    transfer_size_fallback: Option<u16>,
+    dry_run: bool,
+    manifest_on_download: bool,
+    recover_state: bool,
+    operation_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    digest: Option<DigestAlgorithm>,
+    read_memory_limit: u32,
+    ignore_segment_bounds: bool,
+    ignore_suffix_mismatch: bool,
+    strip_suffix: bool,
+    allow_unaligned_address: bool,
+    write_protected_sectors: Vec<u32>,
+    descriptor_overrides: FunctionalDescriptorOverrides,
+    cancel: Option<Arc<AtomicBool>>,
+    poll_wait: Cell<Duration>,
+    read_status_string: bool,
+    status_string_reader: Option<fn(&IO, u8) -> Option<String>>,
+    /// The status strings read so far by their index, see [`Self::status_string`].
+    status_strings: RefCell<HashMap<u8, Option<String>>>,
+}
+
+impl<IO> DfuSync<IO>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = Error>,
+{
+    /// Create a new instance of a synchronous implementation of DFU.
+    ///
+    /// The transport implements [`DfuIo`] for the DFU interface of the device:
+    ///
+    /// - `read_control` sends a device-to-host class request: `request_type` is the one of the
+    ///   host-to-device requests (0x21), the transport sets the direction bit, `value` is
+    ///   wValue, the interface number wIndex and the length of `buffer` wLength. It returns the
+    ///   number of bytes received, fewer than asked on a short packet, which ends an upload.
+    /// - `write_control` sends a host-to-device class request with `buffer` as its data, empty
+    ///   for the zero-length download. It returns the number of bytes sent.
+    /// - `usb_reset` resets the device.
+    /// - `protocol` tells plain DFU from DfuSe, see [`crate::parse_protocol`], and
+    ///   `functional_descriptor` returns the DFU functional descriptor of the device.
+    ///
+    /// The requests block until they are done and time out on their own. The failed transfers are
+    /// returned as [`Error::LibUsb`] with the [`rusb::Error`] libusb would give:
+    ///
+    /// - `Pipe` when the device stalls the request, `Timeout` when it doesn't answer in time and
+    ///   `Io` for the other transfer errors, which are retried, see [`Self::set_retries`];
+    /// - `NoDevice` once the device went away, which is expected from the devices that are not
+    ///   manifestation tolerant in the middle of the manifestation, like `Io`.
+    ///
+    /// The other errors of the transport, e.g. as [`Error::Io`], are returned as they are. The
+    /// memory layout of DfuSe devices is given with [`Self::set_memory_layout`]. The `MockDfuIo`
+    /// of the `testing` feature is a complete example.
+    pub fn new(io: IO) -> Self {
+        Self {
+            io,
+            override_address: None,
+            progress: Progress::default(),
+            erase_progress: None,
+            busy_timeout_factor: 10,
+            max_poll_iterations: None,
+            memory_layout: None,
+            poll_timeout_min: Duration::ZERO,
+            poll_timeout_cap: None,
+            inter_block_delay: Duration::ZERO,
+            retries: 0,
+            transfer_size: None,
+            transfer_size_fallback: Some(DEFAULT_TRANSFER_SIZE_FALLBACK),
+            dry_run: false,
+            manifest_on_download: true,
+            recover_state: true,
+            operation_timeout: None,
+            deadline: None,
+            digest: None,
+            read_memory_limit: DEFAULT_READ_MEMORY_LIMIT,
+            ignore_segment_bounds: false,
+            ignore_suffix_mismatch: false,
+            strip_suffix: true,
+            allow_unaligned_address: false,
+            write_protected_sectors: Vec::new(),
+            descriptor_overrides: FunctionalDescriptorOverrides::default(),
+            cancel: None,
+            poll_wait: Cell::new(Duration::ZERO),
+            read_status_string: true,
+            status_string_reader: None,
+            status_strings: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Override the address onto which the firmware is downloaded.
+    ///
+    /// This address is only used if the device uses the DfuSe protocol. If the memory layout is
+    /// known, the downloads fail with [`Error::UnalignedAddress`] before sending anything when
+    /// the address is not at the start of a page, see [`Self::set_allow_unaligned_address`].
+    ///
+    /// Plain DFU devices write the firmware sequentially from its start, so the downloads and
+    /// uploads fail with [`Error::AddressingUnsupported`] when an address is set.
+    pub fn override_address(&mut self, address: u32) -> &mut Self {
+        self.override_address = Some(address);
+        self
+    }
+
+    /// Set the DfuSe memory layout of the device.
+    ///
+    /// It is used to check that the firmware fits in writable memory before downloading it.
+    pub fn set_memory_layout(&mut self, memory_layout: MemoryLayout) -> &mut Self {
+        self.memory_layout = Some(memory_layout);
+        self
+    }
+
+    /// Skip the check that the firmware fits in the writable segments of the memory layout, for
+    /// devices whose layout is incomplete or wrong.
+    ///
+    /// The check is done by default so that a firmware too large for its segment is rejected
+    /// with [`Error::ImageExceedsSegment`] before anything is erased.
+    pub fn set_ignore_segment_bounds(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_segment_bounds = ignore;
+        self
+    }
+
+    /// Download to an override address that is not at the start of a page, for devices that
+    /// tolerate unaligned writes.
+    pub fn set_allow_unaligned_address(&mut self, allow: bool) -> &mut Self {
+        self.allow_unaligned_address = allow;
+        self
+    }
+
+    /// Fail the downloads touching these sectors with [`Error::SectorWriteProtected`] before
+    /// anything is erased, instead of an errWRITE partway through.
+    ///
+    /// The sectors are numbered like the STM32 number the sectors of their flash: from 0 for the
+    /// first page of the memory layout, which must be known. See
+    /// [`crate::Dfu::read_write_protection`] to get them from the option bytes.
+    pub fn set_write_protected_sectors(&mut self, sectors: Vec<u32>) -> &mut Self {
+        self.write_protected_sectors = sectors;
+        self
+    }
+
+    /// Download a `.dfu` file with [`crate::Dfu::download_file`] even if its suffix targets
+    /// another device, e.g. a bootloader with its own IDs.
+    ///
+    /// The mismatch is then only logged as a warning instead of failing with
+    /// [`Error::SuffixMismatch`]. The CRC of the file is checked nonetheless.
+    pub fn set_ignore_suffix_mismatch(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_suffix_mismatch = ignore;
+        self
+    }
+
+    pub(crate) fn ignore_suffix_mismatch(&self) -> bool {
+        self.ignore_suffix_mismatch
+    }
+
+    /// Strip the DFU suffix of the files downloaded with [`crate::Dfu::download_file`], which is
+    /// enabled by default like dfu-util does.
+    ///
+    /// Disabling it writes the file verbatim, suffix included, for the rare bootloaders expecting
+    /// it. The suffix is still checked against the device either way.
+    pub fn set_strip_suffix(&mut self, strip: bool) -> &mut Self {
+        self.strip_suffix = strip;
+        self
+    }
+
+    pub(crate) fn strip_suffix(&self) -> bool {
+        self.strip_suffix
+    }
+
+    /// Returns the DfuSe memory layout of the device if it is known.
+    pub fn memory_layout(&self) -> Option<&MemoryLayout> {
+        self.memory_layout.as_ref()
+    }
+
+    /// Bound how long the device may stay busy while processing a DfuSe command (e.g. an erase) to
+    /// the poll timeout it reports multiplied by this factor.
+    ///
+    /// [`Error::Timeout`] is returned when this is exceeded. The default is 10.
+    pub fn set_busy_timeout_factor(&mut self, factor: u32) -> &mut Self {
+        self.busy_timeout_factor = factor;
+        self
+    }
+
+    /// Bound the number of GETSTATUS requests sent while the device is busy, or manifesting, to
+    /// `count`.
+    ///
+    /// [`Error::PollLimitExceeded`] is returned when the device still isn't done after that many
+    /// requests, e.g. to make sure tests with a device that never leaves dfuDNBUSY terminate. This
+    /// applies along with the time limits, [`Self::set_busy_timeout_factor`] and
+    /// [`Self::set_operation_timeout`], and the first one reached fails the operation. There is no
+    /// limit by default.
+    pub fn set_max_poll_iterations(&mut self, count: u32) -> &mut Self {
+        self.max_poll_iterations = Some(count);
+        self
+    }
+
+    /// Never wait longer than this between two GET_STATUS requests, whatever the poll timeout
+    /// reported by the device.
+    ///
+    /// By default the poll timeout of the device is honored. Note that during the manifestation
+    /// phase the device may legitimately need a long pause and not all devices are able to answer
+    /// GET_STATUS in the meantime: a cap that is too low can make the manifestation fail.
+    pub fn set_poll_timeout_cap(&mut self, max: Duration) -> &mut Self {
+        self.poll_timeout_cap = Some(max);
+        self
+    }
+
+    /// Always wait at least this long between two GET_STATUS requests, for devices that report a
+    /// poll timeout of zero and would be busy-polled otherwise.
+    ///
+    /// The default is zero.
+    pub fn set_poll_timeout_min(&mut self, min: Duration) -> &mut Self {
+        self.poll_timeout_min = min;
+        self
+    }
+
+    /// Sleep this long between the blocks of the downloads, none by default.
+    ///
+    /// This is a last resort for USB stacks dropping back-to-back transfers: it slows every
+    /// download down. The time spent sleeping is given in the [`DownloadReport`].
+    pub fn set_inter_block_delay(&mut self, delay: Duration) -> &mut Self {
+        self.inter_block_delay = delay;
+        self
+    }
+
+    /// Retry control transfers failing with a recoverable USB error (pipe, timeout or I/O) up to
+    /// `count` times.
+    ///
+    /// The status of the device is cleared between the attempts, which are spaced with an
+    /// exponential backoff. The default is 0.
+    pub fn set_retries(&mut self, count: u32) -> &mut Self {
+        self.retries = count;
+        self
+    }
+
+    /// Override the transfer size reported by the device (wTransferSize).
+    ///
+    /// The size can't be zero and can't exceed the transfer size reported by the device, or 4096
+    /// if the device reports an invalid one.
+    pub fn set_transfer_size(&mut self, size: u16) -> Result<&mut Self, Error> {
+        let max = match self.descriptor().transfer_size {
+            x if VALID_TRANSFER_SIZES.contains(&x) => x,
+            _ => *VALID_TRANSFER_SIZES.end(),
+        };
+        if size == 0 || size > max {
+            return Err(dfu_core::Error::MaximumTransferSizeExceeded.into());
+        }
+        log::debug!("Transfer size: {} (reported by the device: {})", size, max);
+        self.transfer_size = Some(size);
+        Ok(self)
+    }
+
+    /// Use this transfer size if the device reports one that is zero or above 4096, as some
+    /// counterfeit bootloaders do, instead of failing with [`Error::InvalidTransferSize`].
+    ///
+    /// A warning is logged when the fallback is used. The default is `Some(1024)`, the fallback
+    /// is never used if the transfer size is set with [`Self::set_transfer_size`].
+    pub fn set_transfer_size_fallback(&mut self, fallback: Option<u16>) -> &mut Self {
+        self.transfer_size_fallback = fallback;
+        self
+    }
+
+    /// Bound the time a download or an upload may take as a whole.
+    ///
+    /// The timeout is checked between two transfers, [`Error::Timeout`] is returned when it is
+    /// exceeded after trying to bring the device back to dfuIDLE with CLRSTATUS and ABORT. There
+    /// is no timeout by default.
+    pub fn set_operation_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    /// Make [`Self::download`] do all its checks and report its progress but skip every request
+    /// that would erase or write the device. [`Self::mass_erase`], [`Self::erase_page`] and
+    /// [`Self::read_unprotect`] do nothing either.
+    ///
+    /// A summary of what would have been done is logged instead.
+    pub fn set_dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Choose whether the downloads end with the manifestation of the firmware, which is the
+    /// default.
+    ///
+    /// When disabled, the downloads send ABORT instead of the final zero-length download: the
+    /// device is left in dfuIDLE and can keep being used, e.g. to [`Self::upload`] or
+    /// [`Self::verify`] the firmware. This is mainly useful with DfuSe devices, like the STM32
+    /// bootloaders, which program every block as it is received: the firmware is already in flash
+    /// and [`Self::leave`] starts it later on. Plain DFU devices may only commit the firmware
+    /// during the manifestation and discard it on ABORT.
+    ///
+    /// Devices that are manifestation tolerant go back to dfuIDLE after the manifestation anyway,
+    /// see [`Self::manifestation_tolerant`]. The other ones reset themselves, or wait for a reset,
+    /// and can't be talked to anymore.
+    pub fn set_manifest_on_download(&mut self, manifest: bool) -> &mut Self {
+        self.manifest_on_download = manifest;
+        self
+    }
+
+    /// Choose whether the operations bring the device back to dfuIDLE when they find it in another
+    /// state, e.g. left in dfuERROR or dfuDNLOAD-IDLE by a previous run that failed. This is the
+    /// default: CLRSTATUS is sent in dfuERROR and ABORT in dfuDNLOAD-IDLE and dfuUPLOAD-IDLE.
+    ///
+    /// When disabled, the operations fail instead, with [`Error::DfuStatus`] in dfuERROR.
+    pub fn set_recover_state(&mut self, recover: bool) -> &mut Self {
+        self.recover_state = recover;
+        self
+    }
+
+    /// Replace some fields of the functional descriptor of the device, for the bootloaders
+    /// reporting wrong values, e.g. clones claiming to be manifestation tolerant.
+    ///
+    /// The fields left to `None` keep the values overridden before, e.g. by the workarounds of a
+    /// [`crate::Quirk`], or else the values read from the device. A warning is logged for every
+    /// field overridden. [`Self::set_transfer_size`] still takes precedence over the
+    /// transfer size given here.
+    pub fn set_functional_descriptor_overrides(
+        &mut self,
+        overrides: FunctionalDescriptorOverrides,
+    ) -> &mut Self {
+        self.override_functional_descriptor(overrides, log::Level::Warn)
+    }
+
+    /// Replace some fields of the functional descriptor for the workarounds of a known device,
+    /// which are only logged at the debug level since they are expected.
+    pub(crate) fn apply_quirk_overrides(
+        &mut self,
+        overrides: FunctionalDescriptorOverrides,
+    ) -> &mut Self {
+        self.override_functional_descriptor(overrides, log::Level::Debug)
+    }
+
+    fn override_functional_descriptor(
+        &mut self,
+        overrides: FunctionalDescriptorOverrides,
+        level: log::Level,
+    ) -> &mut Self {
+        let reported = *self.io.functional_descriptor();
+        if let Some(size) = overrides.transfer_size {
+            log::log!(
+                level,
+                "Overriding the transfer size of the device: {} bytes instead of {}",
+                size,
+                reported.transfer_size
+            );
+        }
+        if let Some(tolerant) = overrides.manifestation_tolerant {
+            log::log!(
+                level,
+                "Overriding the manifestation tolerance of the device: {} instead of {}",
+                tolerant,
+                reported.manifestation_tolerant
+            );
+        }
+        if let Some(will_detach) = overrides.will_detach {
+            log::log!(
+                level,
+                "Overriding the will-detach bit of the device: {} instead of {}",
+                will_detach,
+                reported.will_detach
+            );
+        }
+        self.descriptor_overrides = overrides.or(self.descriptor_overrides);
+        self
+    }
+
+    /// Choose whether the string descriptor the device points to in its status (iString) is read
+    /// to describe the errors it reports, in [`Error::DfuStatus`]. This is the default.
+    ///
+    /// Most devices don't give any, in which case nothing is read. The strings are read once per
+    /// index.
+    pub fn set_read_status_string(&mut self, read: bool) -> &mut Self {
+        self.read_status_string = read;
+        self
+    }
+
+    /// Set how the status strings are read from the device, see [`Self::set_read_status_string`].
+    pub(crate) fn set_status_string_reader(
+        &mut self,
+        reader: fn(&IO, u8) -> Option<String>,
+    ) -> &mut Self {
+        self.status_string_reader = Some(reader);
+        self
+    }
+
+    /// Use this closure to show progress.
+    ///
+    /// It receives the number of bytes transferred. Use [`Self::with_progress_ex`] to be notified
+    /// of the erase and manifestation phases too.
+    pub fn with_progress(&mut self, mut progress: impl FnMut(usize) + 'static) -> &mut Self {
+        self.with_progress_ex(move |event| match event {
+            ProgressEvent::Downloading { bytes } | ProgressEvent::Uploading { bytes } => {
+                progress(bytes)
+            }
+            _ => {}
+        })
+    }
+
+    /// Use this closure to show progress with the throughput computed.
+    ///
+    /// It receives the cumulative [`ProgressStats`] of the current transfer every time bytes are
+    /// transferred, see [`ProgressStats::track`].
+    pub fn with_progress_stats(
+        &mut self,
+        progress: impl FnMut(ProgressStats) + 'static,
+    ) -> &mut Self {
+        self.with_progress_ex(ProgressStats::track(progress))
+    }
+
+    /// Compute a digest of the firmware while it is downloaded, it is then part of the
+    /// [`DownloadReport`].
+    ///
+    /// No digest is computed by default.
+    pub fn set_digest(&mut self, algorithm: Option<DigestAlgorithm>) -> &mut Self {
+        self.digest = algorithm;
+        self
+    }
+
+    /// Set the largest length [`Self::read_memory`] accepts, which is 16 MiB by default.
+    pub fn set_read_memory_limit(&mut self, limit: u32) -> &mut Self {
+        self.read_memory_limit = limit;
+        self
+    }
+
+    /// Check this flag between two transfers, and while polling the status of a busy device, to
+    /// cancel a download or an upload from another thread, e.g. from a Ctrl-C handler.
+    ///
+    /// The flag is checked at least every 100 ms while waiting for the poll timeout requested by
+    /// the device, so a long erase can be cancelled without waiting for it to end.
+    ///
+    /// Once the flag is set the device is sent CLRSTATUS and ABORT to bring it back to dfuIDLE,
+    /// then [`Error::Cancelled`] is returned. The flag is not cleared.
+    pub fn set_cancel_flag(&mut self, cancel: Arc<AtomicBool>) -> &mut Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Use this closure to show the progress of every phase of an operation.
+    pub fn with_progress_ex(&mut self, progress: impl FnMut(ProgressEvent) + 'static) -> &mut Self {
+        self.progress.callback = Some(Box::new(progress));
+        self
+    }
+
+    /// Call the progress closure at most once per `interval` with the bytes transferred in the
+    /// meantime, instead of after every block.
+    ///
+    /// This spares the consumers that log or send the progress somewhere from thousands of calls a
+    /// second with a small transfer size. The other events, [`ProgressEvent::Done`] in
+    /// particular, are never held back and the bytes pending are reported before them and at the
+    /// end of every operation. By default every block is reported.
+    pub fn set_progress_interval(&mut self, interval: Duration) -> &mut Self {
+        self.progress.interval = Some(interval);
+        self
+    }
+
+    /// Use this closure to show the progress of the erase phase of a DfuSe download.
+    ///
+    /// It receives the index of the sector (page) that has just been erased and the number of
+    /// sectors to erase. It is called once the device reports the erase complete, unlike
+    /// [`ProgressEvent::Erasing`] which is reported when the erase starts.
+    pub fn with_erase_progress(&mut self, progress: impl FnMut(u32, u32) + 'static) -> &mut Self {
+        self.erase_progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Consume the object and return its [`DfuIo`]
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+
+    pub(crate) fn io(&self) -> &IO {
+        &self.io
+    }
+
+    pub(crate) fn report(&mut self, event: ProgressEvent) {
+        report(&mut self.progress, event);
+    }
+
+    /// Report the end of a download that failed.
+    pub(crate) fn report_failure<T>(&mut self, res: Result<T, Error>) -> Result<T, Error> {
+        if res.is_err() {
+            self.report(ProgressEvent::Done { success: false });
+        }
+        res
+    }
+
+    pub(crate) fn manifest_on_download(&self) -> bool {
+        self.manifest_on_download
+    }
+
+    pub(crate) fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub(crate) fn io_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    /// Replace the memory layout, e.g. when switching to another alt-setting.
+    pub(crate) fn replace_memory_layout(&mut self, memory_layout: Option<MemoryLayout>) {
+        self.memory_layout = memory_layout;
+    }
+}
+
+impl<IO> DfuSync<IO>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = Error>,
+{
+    /// Download a firmware into the device from a slice.
+    ///
+    /// The length is taken from the slice. Otherwise this behaves exactly like [`Self::download`],
+    /// including the progress callback and the override address.
+    pub fn download_from_slice(&mut self, slice: &[u8]) -> Result<DownloadReport, Error> {
+        let length = slice.len();
+        let cursor = Cursor::new(slice);
+
+        self.download(
+            cursor,
+            u32::try_from(length).map_err(|_| dfu_core::Error::OutOfCapabilities)?,
+        )
+    }
+
+    /// Download a firmware into the device from a reader.
+    ///
+    /// In dry-run mode the report describes what would have been written. The segments of the
+    /// memory layout that are writable but not erasable, like the OTP area of an STM32, are not
+    /// erased: they are read back first and [`Error::OtpAlreadyProgrammed`] is returned if they
+    /// aren't blank.
+    pub fn download<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        length: u32,
+    ) -> Result<DownloadReport, Error> {
+        let res = self.with_deadline(|dfu| dfu.download_inner(reader, length, 0));
+        self.report_failure(res)
+    }
+
+    /// Download exactly `length` bytes of a firmware from a reader that can't seek, e.g. a
+    /// `flate2::read::GzDecoder` decompressing it on the fly.
+    ///
+    /// The download fails with an [`std::io::ErrorKind::UnexpectedEof`] error, before the
+    /// truncated block is sent, if the reader ends early. The progress callback is given the
+    /// bytes read from the reader, so the decompressed ones.
+    pub fn download_reader<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        length: u32,
+    ) -> Result<DownloadReport, Error> {
+        let reader = ExactReader {
+            reader,
+            remaining: length,
+        };
+        self.download(reader, length)
+    }
+
+    /// Resume an interrupted download into a DfuSe device, `start_offset` bytes into the firmware.
+    ///
+    /// This is opt-in since the bytes before the offset are assumed to be correctly written
+    /// already. The offset is rounded down to the start of its page as that page is erased again.
+    /// The reader is seeked to the offset and the progress callback is first told about the bytes
+    /// skipped. Plain DFU devices can't resume: their block numbers always start from the
+    /// beginning of the firmware, so [`Error::DfuseUnsupported`] is returned.
+    pub fn download_from<R: std::io::Read + std::io::Seek>(
+        &mut self,
+        reader: R,
+        length: u32,
+        start_offset: u32,
+    ) -> Result<DownloadReport, Error> {
+        let res = self.download_from_inner(reader, length, start_offset);
+        self.report_failure(res)
+    }
+
+    fn download_from_inner<R: std::io::Read + std::io::Seek>(
+        &mut self,
+        mut reader: R,
+        length: u32,
+        start_offset: u32,
+    ) -> Result<DownloadReport, Error> {
+        let base = match self.io.protocol() {
+            DfuProtocol::Dfu => return Err(Error::DfuseUnsupported),
+            DfuProtocol::Dfuse { address, .. } => self.override_address.unwrap_or(*address),
+        };
+        let start_offset = start_offset.min(length);
+        let address = base
+            .checked_add(start_offset)
+            .ok_or(Error::AddressOutOfRange(base))?;
+        let start_offset = match self.page_at(address) {
+            Ok(page) => start_offset - (address - page.start.max(base)),
+            Err(_) => start_offset,
+        };
+
+        reader.seek(std::io::SeekFrom::Start(start_offset as u64))?;
+        report(
+            &mut self.progress,
+            ProgressEvent::Downloading {
+                bytes: start_offset as usize,
+            },
+        );
+        self.with_deadline(|dfu| dfu.download_inner(reader, length, start_offset))
+    }
+
+    /// Download `length - skip` bytes of the reader, `skip` bytes into the firmware.
+    fn download_inner<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        length: u32,
+        skip: u32,
+    ) -> Result<DownloadReport, Error> {
+        if length == 0 {
+            return Err(Error::EmptyFirmware);
+        }
+        self.check_transfer_size()?;
+        self.poll_wait.set(Duration::ZERO);
+        let mut transfer = Transfer::new(self.digest);
+        let transfer_size = self.transfer_size();
+        log::debug!(
+            "Transfer size: {} (reported by the device: {})",
+            transfer_size,
+            self.io.functional_descriptor().transfer_size,
+        );
+        let mut reader = Buffer::new(transfer_size as usize, reader);
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            // Nothing left to write when resuming, but there must be something to start with.
+            if skip == 0 {
+                return Err(Error::EmptyFirmware);
+            }
+            return Ok(transfer.finish(self.poll_wait.get()));
+        }
+
+        let (address, pages) = match self.io.protocol() {
+            DfuProtocol::Dfu if self.override_address.is_some() => {
+                return Err(Error::AddressingUnsupported)
+            }
+            // The zero-length download terminating the transfer takes a block number too.
+            DfuProtocol::Dfu if length as u64 > (BLOCK_COUNT - 1) * transfer_size as u64 => {
+                return Err(Error::ImageTooLarge)
+            }
+            DfuProtocol::Dfu => (None, Vec::new()),
+            DfuProtocol::Dfuse { address, .. } => {
+                if let Some(address) = self.override_address {
+                    self.check_aligned(address)?;
+                }
+                let address = self.override_address.unwrap_or(*address) + skip;
+                self.check_writable(address, length - skip)?;
+                if !self.dry_run {
+                    self.check_blank(address, length - skip)?;
+                }
+                (Some(address), self.pages(address, length - skip))
+            }
+        };
+        self.check_write_protection(&pages)?;
+        let length = length - skip;
+
+        if self.dry_run {
+            self.dry_run_erase(&pages);
+            self.dry_run_write(reader, address, length, &mut transfer)?;
+            if self.manifest_on_download {
+                self.dry_run_manifest();
+            } else {
+                report(&mut self.progress, ProgressEvent::Done { success: true });
+            }
+            return Ok(transfer.finish(self.poll_wait.get()));
+        }
+
+        self.wait_idle()?;
+        self.erase_pages(&pages)?;
+        let block_num = self.write_segment(&mut reader, address, length, &mut transfer)?;
+        if !self.manifest_on_download {
+            self.abort()?;
+            report(&mut self.progress, ProgressEvent::Done { success: true });
+            return Ok(transfer.finish(self.poll_wait.get()));
+        }
+        if let Some(address) = address {
+            // Leaving DFU mode jumps to the address pointer, which moved if the download was
+            // resumed or had more blocks than wBlockNum can number.
+            self.dfuse_command(&special_command(0x21, address - skip))?;
+        }
+        self.send_manifest(block_num)?;
+
+        report(&mut self.progress, ProgressEvent::Done { success: true });
+        Ok(transfer.finish(self.poll_wait.get()))
+    }
+
+    /// Download a firmware into a DfuSe device, only erasing and writing the pages that differ
+    /// from what the device holds.
+    ///
+    /// Every page is read back first, which requires the device to support upload, see
+    /// [`Error::UploadUnsupported`]. The pages already holding the firmware are skipped, their
+    /// number is given in [`DownloadReport::pages_skipped`]. The other pages of the segments that
+    /// are not erasable, like OTP, are written as [`Self::download`] does. The device then leaves
+    /// DFU mode at the start of the firmware like [`Self::download`], even if nothing changed.
+    pub fn download_diff<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        length: u32,
+    ) -> Result<DownloadReport, Error> {
+        let res = self.with_deadline(|dfu| dfu.download_diff_inner(reader, length));
+        self.report_failure(res)
+    }
+
+    fn download_diff_inner<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        length: u32,
+    ) -> Result<DownloadReport, Error> {
+        use std::io::Read;
+
+        let base = match self.io.protocol() {
+            DfuProtocol::Dfu => return Err(Error::DfuseUnsupported),
+            DfuProtocol::Dfuse { address, .. } => self.override_address.unwrap_or(*address),
+        };
+        if !self.descriptor().can_upload {
+            return Err(Error::UploadUnsupported);
+        }
+        let mut firmware = Vec::with_capacity(length as usize);
+        reader.take(length as u64).read_to_end(&mut firmware)?;
+        if firmware.is_empty() {
+            return Err(Error::EmptyFirmware);
+        }
+        let length = firmware.len() as u32;
+        if let Some(address) = self.override_address {
+            self.check_aligned(address)?;
+        }
+        self.check_writable(base, length)?;
+
+        // Each page is compared with the part of the firmware it holds, and the consecutive pages
+        // that differ are written as one segment. The pages that can't be erased are compared too:
+        // they are written without being erased, if blank, like by a full download.
+        let mut segments: Vec<(u32, &[u8])> = Vec::new();
+        let mut pages_skipped = 0;
+        for page in self.touched_pages(base, length) {
+            let start = page.start.max(base);
+            let data =
+                &firmware[(start - base) as usize..(page.end.min(base + length) - base) as usize];
+            if self.read_quietly(start, data.len() as u32)? == data {
+                pages_skipped += 1;
+                continue;
+            }
+            match segments.last_mut() {
+                Some((address, segment)) if *address + segment.len() as u32 == start => {
+                    let offset = (*address - base) as usize;
+                    *segment = &firmware[offset..offset + segment.len() + data.len()];
+                }
+                _ => segments.push((start, data)),
+            }
+        }
+        log::info!(
+            "Writing {} segments, {} pages are unchanged",
+            segments.len(),
+            pages_skipped
+        );
+
+        let mut summary = self.download_segments_inner(&segments, false)?;
+        summary.pages_skipped = pages_skipped;
+        if !self.manifest_on_download {
+            report(&mut self.progress, ProgressEvent::Done { success: true });
+            return Ok(summary);
+        }
+        if self.dry_run {
+            self.dry_run_manifest();
+            return Ok(summary);
+        }
+        self.wait_idle()?;
+        self.dfuse_command(&special_command(0x21, base))?;
+        self.send_manifest(2)?;
+        report(&mut self.progress, ProgressEvent::Done { success: true });
+        Ok(summary)
+    }
+
+    /// Send the zero-length download that makes the device manifest the firmware it holds,
+    /// without downloading anything, and poll its status until the manifestation completes, like
+    /// at the end of a download. The downloads refuse empty firmwares with
+    /// [`Error::EmptyFirmware`] rather than doing this.
+    ///
+    /// DfuSe devices, like the STM32 system bootloader, are first given the address the firmware
+    /// is downloaded to and jump to it, which is what [`Self::leave`] does without waiting for the
+    /// manifestation. Plain DFU devices may only manifest after a download: the DFU specification
+    /// makes the zero-length download an error in dfuIDLE, which is what the Maple bootloader and
+    /// u-boot do, so [`Error::DfuseUnsupported`] is returned for them and they need
+    /// [`Self::usb_reset`] instead to start their firmware.
+    pub fn manifest(&mut self) -> Result<(), Error> {
+        let res = self.with_deadline(|dfu| {
+            let address = match dfu.io.protocol() {
+                DfuProtocol::Dfu => return Err(Error::DfuseUnsupported),
+                DfuProtocol::Dfuse { address, .. } => dfu.override_address.unwrap_or(*address),
+            };
+            if dfu.dry_run {
+                dfu.dry_run_manifest();
+                return Ok(());
+            }
+            dfu.wait_idle()?;
+            dfu.dfuse_command(&special_command(0x21, address))?;
+            dfu.send_manifest(2)?;
+            report(&mut dfu.progress, ProgressEvent::Done { success: true });
+            Ok(())
+        });
+        self.report_failure(res)
+    }
+
+    /// Download several segments of firmware, each at its own address, into a DfuSe device.
+    ///
+    /// All the pages touched by the segments are erased first. The address pointer is then set to
+    /// the lowest address before the final zero-length download, so this is where the device
+    /// starts.
+    pub fn download_segments(
+        &mut self,
+        segments: &[(u32, Vec<u8>)],
+    ) -> Result<DownloadReport, Error> {
+        let segments: Vec<(u32, &[u8])> = segments
+            .iter()
+            .map(|(address, data)| (*address, data.as_slice()))
+            .collect();
+        let manifest = self.manifest_on_download;
+        let res = self.with_deadline(|dfu| dfu.download_segments_inner(&segments, manifest));
+        let res = self.report_failure(res)?;
+        if !manifest {
+            report(&mut self.progress, ProgressEvent::Done { success: true });
+        }
+        Ok(res)
+    }
+
+    /// Erase and write segments like [`Self::download_segments`] but stay in DFU mode: the device
+    /// is brought back to dfuIDLE instead of manifesting the firmware.
+    pub(crate) fn write_segments(
+        &mut self,
+        segments: &[(u32, &[u8])],
+    ) -> Result<DownloadReport, Error> {
+        self.with_deadline(|dfu| dfu.download_segments_inner(segments, false))
+    }
+
+    fn download_segments_inner(
+        &mut self,
+        segments: &[(u32, &[u8])],
+        manifest: bool,
+    ) -> Result<DownloadReport, Error> {
+        self.require_dfuse()?;
+        self.check_transfer_size()?;
+        self.poll_wait.set(Duration::ZERO);
+        let mut transfer = Transfer::new(self.digest);
+        let base = match segments.iter().map(|(address, _)| *address).min() {
+            Some(x) => x,
+            None => return Ok(transfer.finish(self.poll_wait.get())),
+        };
+
+        let mut pages = Vec::new();
+        for (address, data) in segments {
+            let length =
+                u32::try_from(data.len()).map_err(|_| dfu_core::Error::OutOfCapabilities)?;
+            self.check_writable(*address, length)?;
+            if !self.dry_run {
+                self.check_blank(*address, length)?;
+            }
+            pages.extend(self.pages(*address, length));
+        }
+        pages.sort_by_key(|x| x.start);
+        pages.dedup();
+        self.check_write_protection(&pages)?;
+
+        let transfer_size = self.transfer_size() as usize;
+        if self.dry_run {
+            self.dry_run_erase(&pages);
+            for (address, data) in segments {
+                let reader = Buffer::new(transfer_size, *data);
+                self.dry_run_write(reader, Some(*address), data.len() as u32, &mut transfer)?;
+            }
+            if manifest {
+                self.dry_run_manifest();
+            }
+            return Ok(transfer.finish(self.poll_wait.get()));
+        }
+
+        self.wait_idle()?;
+        self.erase_pages(&pages)?;
+        let mut block_num = 2;
+        for (address, data) in segments {
+            let mut reader = Buffer::new(transfer_size, *data);
+            block_num = self.write_segment(
+                &mut reader,
+                Some(*address),
+                data.len() as u32,
+                &mut transfer,
+            )?;
+        }
+        if !manifest {
+            self.abort()?;
+            return Ok(transfer.finish(self.poll_wait.get()));
+        }
+        self.dfuse_command(&special_command(0x21, base))?;
+        self.send_manifest(block_num)?;
+
+        report(&mut self.progress, ProgressEvent::Done { success: true });
+        Ok(transfer.finish(self.poll_wait.get()))
+    }
+
+    /// Erase DfuSe pages before writing to them.
+    fn erase_pages(&mut self, pages: &[Range<u32>]) -> Result<(), Error> {
+        for (page, range) in pages.iter().enumerate() {
+            self.check_interrupted()?;
+            report(
+                &mut self.progress,
+                ProgressEvent::Erasing {
+                    page: page as u32,
+                    total_pages: pages.len() as u32,
+                },
+            );
+            self.dfuse_command(&special_command(0x41, range.start))?;
+            if let Some(progress) = self.erase_progress.as_mut() {
+                progress(page as u32, pages.len() as u32);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `length` bytes of the reader into the device, at `address` on DfuSe devices.
+    ///
+    /// The device is left in dfuDNLOAD-IDLE. Returns the next block number.
+    fn write_segment<R: std::io::Read>(
+        &mut self,
+        reader: &mut Buffer<R>,
+        address: Option<u32>,
+        length: u32,
+        transfer: &mut Transfer,
+    ) -> Result<u16, Error> {
+        let mut block_num = match address {
+            Some(address) => {
+                self.dfuse_command(&special_command(0x21, address))?;
+                2
+            }
+            None => 0,
+        };
+
+        let mut copied = 0;
+        while copied < length {
+            self.check_interrupted()?;
+            let chunk = reader.fill_buf()?;
+            if chunk.is_empty() {
+                break;
+            }
+            // The last block is short unless the length is a multiple of the transfer size. The
+            // zero-length download ending the transfer is numbered after it.
+            let n = chunk.len().min((length - copied) as usize);
+            if copied > 0 && !self.inter_block_delay.is_zero() {
+                std::thread::sleep(self.inter_block_delay);
+                transfer.inter_block_delay += self.inter_block_delay;
+            }
+            let start = Instant::now();
+            self.io_ref()
+                .write_control(REQUEST_TYPE, DFU_DNLOAD, block_num, &chunk[..n])?;
+            transfer.transfer_time += start.elapsed();
+            transfer.update(&chunk[..n]);
+            reader.consume(n);
+            self.wait_dnload_idle()?;
+            copied += n as u32;
+            report(&mut self.progress, ProgressEvent::Downloading { bytes: n });
+            block_num = match (block_num.checked_add(1), address) {
+                (Some(block_num), _) => block_num,
+                // DfuSe blocks are relative to the address pointer: move it and start over.
+                (None, Some(address)) if copied < length => {
+                    self.dfuse_command(&special_command(0x21, address + copied))?;
+                    2
+                }
+                (None, Some(_)) => 2,
+                (None, None) => return Err(Error::ImageTooLarge),
+            };
+        }
+
+        Ok(block_num)
+    }
+
+    /// Send the zero-length download terminating a transfer and wait for the device to manifest
+    /// the firmware.
+    ///
+    /// Manifestation tolerant devices must go back to dfuIDLE. The other ones are expected to
+    /// reach dfuMANIFEST-WAIT-RESET, or to drop off the bus, and are then reset unless they detach
+    /// by themselves: the device going away, reported as a `NoDevice` or an I/O error of libusb,
+    /// is not an error here. Any other error, e.g. a stall, is returned.
+    fn send_manifest(&mut self, block_num: u16) -> Result<(), Error> {
+        report(&mut self.progress, ProgressEvent::Manifesting);
+        let descriptor = self.descriptor();
+        let res = self
+            .io_ref()
+            .write_control(REQUEST_TYPE, DFU_DNLOAD, block_num, &[]);
+        match res {
+            Err(Error::LibUsb(rusb::Error::NoDevice | rusb::Error::Io))
+                if !descriptor.manifestation_tolerant =>
+            {
+                return Ok(())
+            }
+            res => res?,
+        };
+
+        let mut polls = 0;
+        loop {
+            self.check_interrupted()?;
+            self.check_poll_limit(polls)?;
+            polls += 1;
+            let status = match self.get_status() {
+                Ok(status) => status,
+                Err(Error::LibUsb(rusb::Error::NoDevice | rusb::Error::Io))
+                    if !descriptor.manifestation_tolerant =>
+                {
+                    return Ok(())
+                }
+                Err(err) => return Err(err),
+            };
+            match status.state {
+                State::DfuIdle => return Ok(()),
+                State::DfuManifestWaitReset if !descriptor.manifestation_tolerant => break,
+                State::DfuManifestSync | State::DfuManifest => {
+                    self.wait_poll_timeout(self.poll_timeout(status.poll_timeout))?
+                }
+                State::DfuError => return Err(self.status_error(&status)),
+                got => {
+                    return Err(dfu_core::Error::InvalidState {
+                        got,
+                        expected: State::DfuIdle,
+                    }
+                    .into())
+                }
+            }
+        }
+
+        if !descriptor.will_detach {
+            // The device may already be gone, or go away before completing the reset.
+            let _ = self.io.usb_reset();
+        }
+        Ok(())
+    }
+
+    fn dry_run_erase(&mut self, pages: &[Range<u32>]) {
+        for (page, range) in pages.iter().enumerate() {
+            log::info!(
+                "Dry run: would erase {:#010x}..{:#010x}",
+                range.start,
+                range.end
+            );
+            report(
+                &mut self.progress,
+                ProgressEvent::Erasing {
+                    page: page as u32,
+                    total_pages: pages.len() as u32,
+                },
+            );
+            if let Some(progress) = self.erase_progress.as_mut() {
+                progress(page as u32, pages.len() as u32);
+            }
+        }
+    }
+
+    fn dry_run_write<R: std::io::Read>(
+        &mut self,
+        mut reader: Buffer<R>,
+        address: Option<u32>,
+        length: u32,
+        transfer: &mut Transfer,
+    ) -> Result<(), Error> {
+        let mut copied = 0;
+        let mut chunks = 0;
+        while copied < length {
+            let chunk = reader.fill_buf()?;
+            if chunk.is_empty() {
+                break;
+            }
+            let n = chunk.len().min((length - copied) as usize);
+            transfer.update(&chunk[..n]);
+            reader.consume(n);
+            copied += n as u32;
+            chunks += 1;
+            report(&mut self.progress, ProgressEvent::Downloading { bytes: n });
+        }
+
+        match address {
+            Some(address) => log::info!(
+                "Dry run: would write {} bytes in {} chunks at {:#010x}",
+                copied,
+                chunks,
+                address
+            ),
+            None => log::info!("Dry run: would write {} bytes in {} chunks", copied, chunks),
+        }
+        Ok(())
+    }
+
+    fn dry_run_manifest(&mut self) {
+        report(&mut self.progress, ProgressEvent::Manifesting);
+        let state = if self.descriptor().manifestation_tolerant {
+            State::DfuIdle
+        } else {
+            State::DfuManifestWaitReset
+        };
+        log::info!("Dry run: the device would end in state {:?}", state);
+        report(&mut self.progress, ProgressEvent::Done { success: true });
+    }
+
+    /// Download a firmware into the device.
+    ///
+    /// The length is guessed from the reader.
+    pub fn download_all<R: std::io::Read + std::io::Seek>(
+        &mut self,
+        mut reader: R,
+    ) -> Result<DownloadReport, Error> {
+        let length = u32::try_from(reader.seek(std::io::SeekFrom::End(0))?)
+            .map_err(|_| dfu_core::Error::MaximumTransferSizeExceeded)?;
+        reader.seek(std::io::SeekFrom::Start(0))?;
+        self.download(reader, length)
+    }
+
+    /// Read `length` bytes of the memory of a DfuSe device from `address`, e.g. the option bytes.
+    ///
+    /// Fewer bytes are returned if the device ends the upload early. [`Error::LengthTooLarge`] is
+    /// returned if `length` is above the limit set with [`Self::set_read_memory_limit`].
+    pub fn read_memory(&mut self, address: u32, length: u32) -> Result<Vec<u8>, Error> {
+        self.require_dfuse()?;
+        if length > self.read_memory_limit {
+            return Err(Error::LengthTooLarge {
+                length,
+                limit: self.read_memory_limit,
+            });
+        }
+
+        let mut memory = Vec::with_capacity(length as usize);
+        self.upload_with(Some(address), length, |chunk| {
+            memory.extend_from_slice(chunk);
+            Ok::<_, Error>(())
+        })?;
+        Ok(memory)
+    }
+
+    /// Upload the firmware of the device into a writer.
+    ///
+    /// The firmware is read in chunks of `wTransferSize` bytes until the device returns a short
+    /// packet or `length` bytes have been read. Returns the number of bytes read.
+    ///
+    /// On DfuSe devices the upload starts at the address pointer of the device. If an address has
+    /// been set with [`Self::override_address`], the address pointer is set to it first.
+    pub fn upload<W: std::io::Write>(&mut self, mut writer: W, length: u32) -> Result<u32, Error> {
+        self.upload_with(self.override_address, length, |chunk| {
+            Ok::<_, Error>(writer.write_all(chunk)?)
+        })
+    }
+
+    /// Read back the firmware of the device and compare it against a reader.
+    ///
+    /// On DfuSe devices the comparison starts at the address the firmware is downloaded to.
+    pub fn verify<R: std::io::Read>(
+        &mut self,
+        mut reader: R,
+        length: u32,
+    ) -> Result<(), VerifyError> {
+        if !self.descriptor().can_upload {
+            return Err(VerifyError::Unsupported);
+        }
+
+        let address = match self.io.protocol() {
+            DfuProtocol::Dfu => None,
+            DfuProtocol::Dfuse { address, .. } => Some(self.override_address.unwrap_or(*address)),
+        };
+        let mut expected = vec![0x00; self.transfer_size() as usize];
+        let mut offset = 0;
+        let read = self.upload_with(address, length, |chunk| {
+            let expected = &mut expected[..chunk.len()];
+            reader.read_exact(expected).map_err(Error::from)?;
+            if let Some(i) = chunk.iter().zip(expected.iter()).position(|(a, b)| a != b) {
+                return Err(VerifyError::Mismatch {
+                    offset: offset + i as u32,
+                    expected: expected[i],
+                    actual: chunk[i],
+                });
+            }
+            offset += chunk.len() as u32;
+            Ok(())
+        })?;
+
+        if read < length {
+            return Err(VerifyError::Truncated {
+                expected: length,
+                got: read,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Upload `length` bytes from the device and return their digest, without keeping them, e.g.
+    /// to check a large firmware against [`DigestAlgorithm::digest`] of its file.
+    ///
+    /// The upload starts at `address` on DfuSe devices, if given, like [`Self::upload`]. The
+    /// digest covers the bytes returned, which are fewer if the device ends the upload early.
+    pub fn upload_digest(
+        &mut self,
+        address: Option<u32>,
+        length: u32,
+        algorithm: DigestAlgorithm,
+    ) -> Result<Digest, Error> {
+        let mut hasher = Hasher::new(algorithm);
+        let read = self.upload_with(address, length, |chunk| {
+            hasher.update(chunk);
+            Ok::<_, Error>(())
+        })?;
+        if read < length {
+            log::warn!("The device returned {} bytes out of {}", read, length);
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Download a firmware and verify it, downloading it again, pages erased included, up to
+    /// `attempts` times in total until the verification succeeds.
+    ///
+    /// The reader is rewound to its current position before every download and verification. The
+    /// device must still answer after the download: it must be manifestation tolerant or the
+    /// manifestation disabled with [`Self::set_manifest_on_download`].
+    /// [`Error::FlashAttemptsFailed`] lists the error of every attempt if none succeeds.
+    pub fn flash_verified<R: std::io::Read + std::io::Seek>(
+        &mut self,
+        mut reader: R,
+        length: u32,
+        attempts: u32,
+    ) -> Result<DownloadReport, Error> {
+        let start = reader.stream_position()?;
+        let mut errors = Vec::new();
+
+        for attempt in 1..=attempts.max(1) {
+            reader.seek(std::io::SeekFrom::Start(start))?;
+            let res = self
+                .download(&mut reader, length)
+                .map_err(VerifyError::from);
+            let res = res.and_then(|report| {
+                reader
+                    .seek(std::io::SeekFrom::Start(start))
+                    .map_err(Error::from)?;
+                self.verify(&mut reader, length)?;
+                Ok(report)
+            });
+            match res {
+                Ok(report) => return Ok(report),
+                Err(err) => {
+                    log::warn!("Attempt {} to flash the firmware failed: {}", attempt, err);
+                    errors.push(err);
+                }
+            }
+        }
+
+        Err(Error::FlashAttemptsFailed(errors))
+    }
+
+    /// Upload `length` bytes from the device and pass every chunk to `f`.
+    ///
+    /// On DfuSe devices the address pointer is set to `address` first if any. Every chunk is
+    /// reported as [`ProgressEvent::Uploading`], then [`ProgressEvent::Done`] ends the upload,
+    /// however short.
+    fn upload_with<E: From<Error>>(
+        &mut self,
+        address: Option<u32>,
+        length: u32,
+        f: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<u32, E> {
+        let res = self.with_deadline(|dfu| dfu.upload_inner(address, length, f));
+        report(
+            &mut self.progress,
+            ProgressEvent::Done {
+                success: res.is_ok(),
+            },
+        );
+        res
+    }
+
+    fn upload_inner<E: From<Error>>(
+        &mut self,
+        address: Option<u32>,
+        length: u32,
+        mut f: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<u32, E> {
+        self.check_transfer_size()?;
+        let transfer_size = self.transfer_size() as u32;
+        if !self.descriptor().can_upload {
+            return Err(Error::UploadUnsupported.into());
+        }
+
+        if address.is_some() && matches!(self.io.protocol(), DfuProtocol::Dfu) {
+            return Err(Error::AddressingUnsupported.into());
+        }
+
+        self.wait_idle()?;
+        let mut block_num = match self.io.protocol() {
+            DfuProtocol::Dfu => 0,
+            DfuProtocol::Dfuse { .. } => {
+                if let Some(address) = address {
+                    self.set_address(address)?;
+                }
+                2
+            }
+        };
+
+        let mut buffer = vec![0x00; transfer_size as usize];
+        let mut copied = 0;
+        while copied < length {
+            self.check_interrupted()?;
+            // Always ask for a full block: DfuSe devices derive the address from wLength.
+            let n = self
+                .io_ref()
+                .read_control(REQUEST_TYPE, DFU_UPLOAD, block_num, &mut buffer)?;
+            let wanted = (length - copied).min(n as u32) as usize;
+            f(&buffer[..wanted])?;
+            copied += wanted as u32;
+            report(
+                &mut self.progress,
+                ProgressEvent::Uploading { bytes: wanted },
+            );
+            if n < buffer.len() {
+                // A short packet terminates the upload and the device is back to dfuIDLE.
+                return Ok(copied);
+            }
+            block_num = block_num
+                .checked_add(1)
+                .ok_or(dfu_core::Error::MaximumChunksExceeded)
+                .map_err(Error::from)?;
+        }
+
+        // The device is still in dfuUPLOAD-IDLE because it did not send a short packet.
+        self.abort()?;
+
+        Ok(copied)
+    }
+
+    /// Set the address pointer of a DfuSe device, e.g. to upload from a specific address.
+    ///
+    /// A warning is logged if the address is outside of the memory layout of the device.
+    pub fn set_address(&mut self, address: u32) -> Result<(), Error> {
+        self.require_dfuse()?;
+        if let Some(memory_layout) = self.memory_layout.as_ref() {
+            if memory_layout.segment_at(address).is_none() {
+                log::warn!(
+                    "The address {:#010x} is outside of the memory layout of the device",
+                    address
+                );
+            }
+        }
+
+        self.wait_idle()?;
+        self.dfuse_command(&special_command(0x21, address))?;
+        self.abort()
+    }
+
+    /// Erase the whole flash of a DfuSe device.
+    ///
+    /// The device is polled until the erase is complete, see [`Self::set_busy_timeout_factor`].
+    pub fn mass_erase(&mut self) -> Result<(), Error> {
+        self.require_dfuse()?;
+        if self.dry_run {
+            log::info!("Dry run: would erase the whole flash");
+            return Ok(());
+        }
+        self.wait_idle()?;
+        self.dfuse_command(&[0x41])?;
+        self.abort()
+    }
+
+    /// Clear the readout protection of a DfuSe device with the Read Unprotect command.
+    ///
+    /// This is a last resort to recover a locked STM32: the device erases the whole flash, clears
+    /// the protection and resets. The device leaving the bus, reported as a `NoDevice` or an I/O
+    /// error of libusb, is not an error here, unlike a stall of a device refusing the command. [`ReopenRequired`] is returned since the device must then be opened again.
+    pub fn read_unprotect(&mut self) -> Result<ReopenRequired, Error> {
+        self.require_dfuse()?;
+        if self.dry_run {
+            log::info!("Dry run: would clear the readout protection and erase the whole flash");
+            return Ok(ReopenRequired);
+        }
+        self.wait_idle()?;
+        self.io_ref()
+            .write_control(REQUEST_TYPE, DFU_DNLOAD, 0, &[0x92])?;
+        match self.poll_status() {
+            Ok(status) if status.state == State::DfuError => Err(self.status_error(&status)),
+            Ok(_) | Err(Error::LibUsb(rusb::Error::NoDevice | rusb::Error::Io)) => {
+                Ok(ReopenRequired)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Ask a DfuSe device for the special commands it supports (Get Commands), e.g. to check that
+    /// it implements Read Unprotect before sending it.
+    ///
+    /// The opcodes are returned as sent by the device: 0x00 (Get Commands), 0x21 (Set Address
+    /// Pointer), 0x41 (Erase), 0x92 (Read Unprotect) are the standard ones.
+    pub fn get_dfuse_commands(&mut self) -> Result<Vec<u8>, Error> {
+        self.require_dfuse()?;
+        self.wait_idle()?;
+        let mut buffer = vec![0x00; self.transfer_size() as usize];
+        let n = self
+            .io_ref()
+            .read_control(REQUEST_TYPE, DFU_UPLOAD, 0, &mut buffer)?;
+        buffer.truncate(n);
+        // A short answer brings the device back to dfuIDLE, where ABORT is harmless.
+        self.abort()?;
+        Ok(buffer)
+    }
+
+    /// Erase the page of a DfuSe device that contains the given address.
+    ///
+    /// Returns the bounds of the erased page. Note that [`Self::download`] already erases the
+    /// pages it writes to. [`Error::SegmentNotErasable`] is returned for the pages of segments
+    /// that can only be written once, like OTP.
+    pub fn erase_page(&mut self, address: u32) -> Result<Range<u32>, Error> {
+        let page = self.page_at(address)?;
+        if let Some(segment) = self.segment(address).filter(|x| !x.erasable) {
+            return Err(Error::SegmentNotErasable(segment.start));
+        }
+        if self.dry_run {
+            log::info!(
+                "Dry run: would erase {:#010x}..{:#010x}",
+                page.start,
+                page.end
+            );
+            return Ok(page);
+        }
+        self.wait_idle()?;
+        self.dfuse_command(&special_command(0x41, page.start))?;
+        self.abort()?;
+        Ok(page)
+    }
+
+    /// Leave DFU mode and start the firmware of a DfuSe device.
+    ///
+    /// The address pointer is set to the address the firmware is downloaded to, then a zero-length
+    /// download makes the device jump to it. Plain DFU devices need a USB reset instead.
+    pub fn leave(&mut self) -> Result<(), Error> {
+        let address = match self.io.protocol() {
+            DfuProtocol::Dfu => return Err(Error::DfuseUnsupported),
+            DfuProtocol::Dfuse { address, .. } => self.override_address.unwrap_or(*address),
+        };
+        self.leave_at(address)
+    }
+
+    /// Leave DFU mode and jump to `address` on a DfuSe device.
+    pub(crate) fn leave_at(&mut self, address: u32) -> Result<(), Error> {
+        self.wait_idle()?;
+        self.dfuse_command(&special_command(0x21, address))?;
+        self.io_ref()
+            .write_control(REQUEST_TYPE, DFU_DNLOAD, 2, &[])?;
+        // The device may leave the bus before answering, but a stall means it refused to leave.
+        match self.get_status() {
+            Ok(_) | Err(Error::LibUsb(rusb::Error::NoDevice | rusb::Error::Io)) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Send a DFU_ABORT request to bring the device back to dfuIDLE, e.g. when a download
+    /// failed midway.
+    ///
+    /// The status of the device is queried afterwards to make sure it is idle.
+    pub fn abort(&self) -> Result<(), Error> {
+        self.io_ref()
+            .write_control(REQUEST_TYPE, DFU_ABORT, 0, &[])?;
+        self.expect_idle()
+    }
+
+    /// Send a DFU_CLRSTATUS request to get the device out of dfuERROR.
+    ///
+    /// The status of the device is queried afterwards to make sure it is idle.
+    pub fn clear_status(&self) -> Result<(), Error> {
+        self.io_ref()
+            .write_control(REQUEST_TYPE, DFU_CLRSTATUS, 0, &[])?;
+        self.expect_idle()
+    }
+
+    /// Query the state of the device with a DFU_GETSTATE request.
+    ///
+    /// Unlike GET_STATUS this does not make the device move on to the next state, which makes it
+    /// suitable to check whether a device runs its application or its bootloader.
+    pub fn get_state(&self) -> Result<State, Error> {
+        let mut buffer = [0x00; 1];
+        let n = self
+            .io_ref()
+            .read_control(REQUEST_TYPE, DFU_GETSTATE, 0, &mut buffer)?;
+        if n < buffer.len() {
+            return Err(dfu_core::Error::ResponseTooShort {
+                got: n,
+                expected: buffer.len(),
+            }
+            .into());
+        }
+        Ok(buffer[0].into())
+    }
+
+    /// Send a Detach request to the device
+    ///
+    /// The device is asked to wait for a USB reset for as long as its wDetachTimeOut allows.
+    pub fn detach(&self) -> Result<(), Error> {
+        let detach_timeout = self.descriptor().detach_timeout;
+        self.io_ref()
+            .write_control(REQUEST_TYPE, DFU_DETACH, detach_timeout, &[])?;
+        Ok(())
+    }
+
+    /// Reset the USB device
+    pub fn usb_reset(&self) -> Result<(), Error> {
+        self.io.usb_reset()
+    }
+
+    /// Returns the functional descriptor of the device, with the overrides applied, see
+    /// [`Self::set_functional_descriptor_overrides`].
+    pub fn functional_descriptor(&self) -> FunctionalDescriptor {
+        self.descriptor().into()
+    }
+
+    /// The functional descriptor of the device with the overrides applied.
+    fn descriptor(&self) -> dfu_core::functional_descriptor::FunctionalDescriptor {
+        self.descriptor_overrides
+            .apply(*self.io.functional_descriptor())
+    }
+
+    /// Returns whether the device is will detach if requested
+    pub fn will_detach(&self) -> bool {
+        self.descriptor().will_detach
+    }
+
+    /// Returns whether the device is manifestation tolerant
+    pub fn manifestation_tolerant(&self) -> bool {
+        self.descriptor().manifestation_tolerant
+    }
+
+    fn io_ref(&self) -> IoRef<'_, IO> {
+        let mut functional_descriptor = self.descriptor();
+        functional_descriptor.transfer_size = self.transfer_size();
+        IoRef::new(&self.io, self.retries, functional_descriptor)
+    }
+
+    /// Returns the transfer size in use, see [`Self::set_transfer_size`] and
+    /// [`Self::set_transfer_size_fallback`].
+    fn transfer_size(&self) -> u16 {
+        match (self.transfer_size, self.descriptor().transfer_size) {
+            (Some(size), _) => size,
+            (None, size) if VALID_TRANSFER_SIZES.contains(&size) => size,
+            (None, size) => self.transfer_size_fallback.unwrap_or(size),
+        }
+    }
+
+    /// Fail if the transfer size reported by the device is invalid and there is no fallback.
+    fn check_transfer_size(&self) -> Result<(), Error> {
+        let reported = self.descriptor().transfer_size;
+        if self.transfer_size.is_some() || VALID_TRANSFER_SIZES.contains(&reported) {
+            return Ok(());
+        }
+        match self.transfer_size_fallback {
+            Some(fallback) => {
+                log::warn!(
+                    "The device reports a transfer size of {} bytes, using {} bytes instead",
+                    reported,
+                    fallback
+                );
+                Ok(())
+            }
+            None => Err(Error::InvalidTransferSize(reported)),
+        }
+    }
+
+    /// Returns how long to wait for the poll timeout reported by the device.
+    fn poll_timeout(&self, poll_timeout: u64) -> Duration {
+        let poll_timeout = Duration::from_millis(poll_timeout).max(self.poll_timeout_min);
+        match self.poll_timeout_cap {
+            Some(cap) => poll_timeout.min(cap),
+            None => poll_timeout,
+        }
+    }
+
+    fn get_status(&self) -> Result<GetStatusMessage, Error> {
+        let mut buffer = [0x00; 6];
+        let n = self
+            .io_ref()
+            .read_control(REQUEST_TYPE, DFU_GETSTATUS, 0, &mut buffer)?;
+        if n < buffer.len() {
+            return Err(dfu_core::Error::ResponseTooShort {
+                got: n,
+                expected: buffer.len(),
+            }
+            .into());
+        }
+
+        Ok(GetStatusMessage {
+            status: buffer[0].into(),
+            poll_timeout: u32::from_le_bytes([buffer[1], buffer[2], buffer[3], 0]) as u64,
+            state: buffer[4].into(),
+            index: buffer[5],
+        })
+    }
+
+    /// Query the status of the device until it is not busy anymore.
+    fn poll_status(&self) -> Result<GetStatusMessage, Error> {
+        let start = Instant::now();
+        let mut limit = Duration::ZERO;
+        let mut status = self.get_status()?;
+        let mut polls = 1;
+        while matches!(status.state, State::DfuDnbusy | State::DfuDnloadSync) {
+            self.check_poll_limit(polls)?;
+            let poll_timeout = self.poll_timeout(status.poll_timeout);
+            limit = limit.max(
+                Duration::from_millis(status.poll_timeout).max(poll_timeout)
+                    * self.busy_timeout_factor,
+            );
+            if !limit.is_zero() && start.elapsed() > limit {
+                return Err(Error::Timeout);
+            }
+            self.check_interrupted()?;
+            self.wait_poll_timeout(poll_timeout)?;
+            status = self.get_status()?;
+            polls += 1;
+        }
+        Ok(status)
+    }
+
+    /// Fail if `polls` GETSTATUS requests reached the limit, see
+    /// [`Self::set_max_poll_iterations`].
+    fn check_poll_limit(&self, polls: u32) -> Result<(), Error> {
+        match self.max_poll_iterations {
+            Some(max) if polls >= max => Err(Error::PollLimitExceeded(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sleep for a poll timeout requested by the device, accounted in the download reports.
+    ///
+    /// The sleep is cut in short steps to stop as soon as the operation is interrupted, see
+    /// [`Self::check_interrupted`], even when the device asks for seconds.
+    fn wait_poll_timeout(&self, poll_timeout: Duration) -> Result<(), Error> {
+        let mut remaining = poll_timeout;
+        while !remaining.is_zero() {
+            let step = remaining.min(CANCEL_CHECK_INTERVAL);
+            std::thread::sleep(step);
+            self.poll_wait.set(self.poll_wait.get() + step);
+            remaining -= step;
+            if !remaining.is_zero() {
+                self.check_interrupted()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the bounds of the page containing an address in the memory layout of the device.
+    fn page_at(&self, address: u32) -> Result<Range<u32>, Error> {
+        if let Some(memory_layout) = self.memory_layout.as_ref() {
+            return memory_layout
+                .segment_at(address)
+                .and_then(|x| x.page_at(address))
+                .ok_or(Error::AddressOutOfRange(address));
+        }
+
+        match self.io.protocol() {
+            DfuProtocol::Dfu => Err(Error::DfuseUnsupported),
+            DfuProtocol::Dfuse {
+                address: start,
+                memory_layout,
+            } => {
+                let mut start = *start;
+                for &size in memory_layout.as_ref() {
+                    let end = start.saturating_add(size);
+                    if (start..end).contains(&address) {
+                        return Ok(start..end);
+                    }
+                    start = end;
+                }
+                Err(Error::AddressOutOfRange(address))
+            }
+        }
+    }
+
+    /// Returns the pages to erase before writing `length` bytes starting at `address`: the pages
+    /// touched, except those of the segments of the memory layout that are not erasable, e.g. OTP.
+    fn pages(&self, address: u32, length: u32) -> Vec<Range<u32>> {
+        self.touched_pages(address, length)
+            .into_iter()
+            .filter(|page| self.segment(page.start).filter(|x| !x.erasable).is_none())
+            .collect()
+    }
+
+    /// Returns the pages touched by `length` bytes starting at `address`, erasable or not.
+    fn touched_pages(&self, address: u32, length: u32) -> Vec<Range<u32>> {
+        let end = address as u64 + length as u64;
+        let mut address = address;
+        let mut pages = Vec::new();
+        while (address as u64) < end {
+            match self.page_at(address) {
+                Ok(page) if page.end > address => {
+                    address = page.end;
+                    pages.push(page);
+                }
+                _ => break,
+            }
+        }
+        pages
+    }
+
+    /// Returns the segment of the memory layout containing an address, if the layout is known.
+    fn segment(&self, address: u32) -> Option<&Segment> {
+        self.memory_layout.as_ref()?.segment_at(address)
+    }
+
+    /// Read `length` bytes from `address`, outside of the progress of the operation.
+    fn read_quietly(&mut self, address: u32, length: u32) -> Result<Vec<u8>, Error> {
+        let progress = self.progress.callback.take();
+        let mut data = Vec::with_capacity(length as usize);
+        let res = self.upload_inner(Some(address), length, |chunk| {
+            data.extend_from_slice(chunk);
+            Ok::<_, Error>(())
+        });
+        self.progress.callback = progress;
+        res.map(|_| data)
+    }
+
+    /// Check that the bytes of non-erasable segments about to be written are blank, i.e. still
+    /// `0xff`, by reading them back: these can't be erased if the firmware doesn't match.
+    ///
+    /// Nothing is checked if the memory layout is unknown or the segments are not readable.
+    fn check_blank(&mut self, address: u32, length: u32) -> Result<(), Error> {
+        let end = address as u64 + length as u64;
+        let mut ranges = Vec::new();
+        let mut start = address;
+        while (start as u64) < end {
+            let segment = match self.segment(start) {
+                Some(x) => *x,
+                None => break,
+            };
+            let stop = (segment.end() as u64).min(end) as u32;
+            if !segment.erasable {
+                if segment.readable && self.descriptor().can_upload {
+                    ranges.push((start, stop - start));
+                } else {
+                    log::warn!(
+                        "Can't check that {:#010x}..{:#010x} is blank, it is not readable",
+                        start,
+                        stop
+                    );
+                }
+            }
+            start = stop;
+        }
+
+        // This is not part of the progress of the download.
+        let progress = self.progress.callback.take();
+        let res = ranges.into_iter().try_for_each(|(start, length)| {
+            let mut offset = start;
+            self.upload_inner(Some(start), length, |chunk| {
+                if let Some(i) = chunk.iter().position(|x| *x != 0xff) {
+                    return Err(Error::OtpAlreadyProgrammed(offset + i as u32));
+                }
+                offset += chunk.len() as u32;
+                Ok(())
+            })
+            .map(|_| ())
+        });
+        self.progress.callback = progress;
+        res
+    }
+
+    /// Check that none of the pages is write-protected, see
+    /// [`Self::set_write_protected_sectors`].
+    fn check_write_protection(&self, pages: &[Range<u32>]) -> Result<(), Error> {
+        let memory_layout = match self.memory_layout.as_ref() {
+            Some(x) if !self.write_protected_sectors.is_empty() => x,
+            _ => return Ok(()),
+        };
+        for page in pages {
+            let mut sector = 0;
+            for segment in memory_layout.segments() {
+                if segment.contains(page.start) {
+                    sector += (page.start - segment.start) / segment.page_size;
+                    if self.write_protected_sectors.contains(&sector) {
+                        return Err(Error::SectorWriteProtected { sector });
+                    }
+                    break;
+                }
+                sector += segment.page_count;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `length` bytes starting at `address` are in writable segments of the memory
+    /// layout, if it is known, see [`Self::set_ignore_segment_bounds`].
+    fn check_writable(&self, address: u32, length: u32) -> Result<(), Error> {
+        let memory_layout = match self.memory_layout.as_ref() {
+            Some(x) if !self.ignore_segment_bounds => x,
+            _ => return Ok(()),
+        };
+        let start = address;
+        let end = address as u64 + length as u64;
+
+        let mut address = address;
+        while (address as u64) < end {
+            let segment = match memory_layout.segment_at(address) {
+                Some(x) => x,
+                None if address == start => return Err(Error::AddressOutOfRange(address)),
+                None => {
+                    return Err(Error::ImageExceedsSegment {
+                        end: end.min(u32::MAX as u64) as u32,
+                        segment_end: address,
+                    })
+                }
+            };
+            if !segment.writable {
+                return Err(Error::SegmentNotWritable(segment.start));
+            }
+            address = segment.end();
+        }
+
+        Ok(())
+    }
+
+    /// Check that an address is at the start of a page of the memory layout, if it is known, see
+    /// [`Self::set_allow_unaligned_address`].
+    fn check_aligned(&self, address: u32) -> Result<(), Error> {
+        let segment = match self.memory_layout.as_ref() {
+            Some(x) if !self.allow_unaligned_address => x.segment_at(address),
+            _ => None,
+        };
+        match segment {
+            Some(segment) if segment.page_at(address).map(|x| x.start) != Some(address) => {
+                Err(Error::UnalignedAddress {
+                    address,
+                    page_size: segment.page_size,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Run an operation bounded by the operation timeout, see [`Self::set_operation_timeout`], and
+    /// the cancel flag, see [`Self::set_cancel_flag`].
+    fn with_deadline<T, E>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.deadline = self.operation_timeout.map(|x| Instant::now() + x);
+        let res = operation(self);
+        self.progress.flush();
+        let interrupted = self.check_interrupted().is_err();
+        self.deadline = None;
+        if res.is_err() && interrupted {
+            self.recover();
+        }
+        res
+    }
+
+    /// Fail if the operation has been cancelled or its deadline has passed.
+    fn check_interrupted(&self) -> Result<(), Error> {
+        if let Some(cancel) = self.cancel.as_ref() {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(Error::Cancelled);
+            }
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() > deadline => Err(Error::Timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// The error reported by the device in this status.
+    fn status_error(&self, status: &GetStatusMessage) -> Error {
+        Error::DfuStatus {
+            status: status.status,
+            state: status.state,
+            description: self.status_string(status.index),
+        }
+    }
+
+    /// Read the status string at this index, unless disabled, see
+    /// [`Self::set_read_status_string`]. A device polled in a loop is only asked once.
+    fn status_string(&self, index: u8) -> Option<String> {
+        let reader = match self.status_string_reader {
+            Some(x) if self.read_status_string && index != 0 => x,
+            _ => return None,
+        };
+        self.status_strings
+            .borrow_mut()
+            .entry(index)
+            .or_insert_with(|| reader(&self.io, index))
+            .clone()
+    }
+
+    /// Try to bring the device back to dfuIDLE after an operation failed midway.
+    fn recover(&self) {
+        let _ = self.clear_status();
+        let _ = self.abort();
+    }
+
+    fn expect_idle(&self) -> Result<(), Error> {
+        let status = self.get_status()?;
+        match status.state {
+            State::DfuIdle => Ok(()),
+            State::DfuError => Err(self.status_error(&status)),
+            got => Err(dfu_core::Error::InvalidState {
+                got,
+                expected: State::DfuIdle,
+            }
+            .into()),
+        }
+    }
+
+    fn require_dfuse(&self) -> Result<(), Error> {
+        match self.io.protocol() {
+            DfuProtocol::Dfu => Err(Error::DfuseUnsupported),
+            DfuProtocol::Dfuse { .. } => Ok(()),
+        }
+    }
+
+    /// Make sure the device is in dfuIDLE before starting a new operation.
+    fn wait_idle(&self) -> Result<(), Error> {
+        let status = self.poll_status()?;
+        match status.state {
+            State::DfuIdle => Ok(()),
+            State::DfuError if self.recover_state => {
+                log::warn!(
+                    "The device is in dfuERROR ({}), clearing its status",
+                    status.status
+                );
+                self.clear_status()
+            }
+            State::DfuDnloadIdle | State::DfuUploadIdle if self.recover_state => {
+                log::warn!(
+                    "The device is in {:?} after an unfinished transfer, aborting it",
+                    status.state
+                );
+                self.abort()
+            }
+            State::DfuError => Err(self.status_error(&status)),
+            got => Err(dfu_core::Error::InvalidState {
+                got,
+                expected: State::DfuIdle,
+            }
+            .into()),
+        }
+    }
+
+    /// Send a DfuSe special command and wait for the device to process it.
+    fn dfuse_command(&self, command: &[u8]) -> Result<(), Error> {
+        self.io_ref()
+            .write_control(REQUEST_TYPE, DFU_DNLOAD, 0, command)?;
+        self.wait_dnload_idle()
+    }
+
+    /// Wait for the device to process a download request.
+    fn wait_dnload_idle(&self) -> Result<(), Error> {
+        let status = self.poll_status()?;
+        match status.state {
+            State::DfuDnloadIdle => Ok(()),
+            State::DfuError => Err(self.status_error(&status)),
+            got => Err(dfu_core::Error::InvalidState {
+                got,
+                expected: State::DfuDnloadIdle,
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<IO> DfuSync<IO>
+where
+    IO: DfuIo<Read = usize, Write = usize, Reset = (), Error = Error>,
+{
+    /// Download a firmware from an async context, on the blocking thread pool of tokio.
+    ///
+    /// The device isn't [`Send`], so it is returned by `open`, e.g. with
+    /// [`crate::DfuLibusb::open`], and configured there: it is opened, used and closed on the thread
+    /// of the pool running the download, which leaves the future and the stream free to be given to
+    /// `tokio::spawn`. The progress is reported through the returned stream as it happens, instead
+    /// of the closure given to [`Self::with_progress_ex`].
+    ///
+    /// The future replaces the flag of [`Self::set_cancel_flag`]: dropping it cancels the
+    /// download, which stops at the next transfer or within 100 ms of a poll timeout. The device
+    /// is then sent CLRSTATUS and ABORT to bring it back to dfuIDLE, like after any other error.
+    pub fn download_async<F, R>(
+        open: F,
+        reader: R,
+        length: u32,
+    ) -> (
+        impl std::future::Future<Output = Result<DownloadReport, Error>> + Send,
+        tokio_stream::wrappers::UnboundedReceiverStream<ProgressEvent>,
+    )
+    where
+        F: FnOnce() -> Result<Self, Error> + Send + 'static,
+        R: std::io::Read + Send + 'static,
+    {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let download = async move {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let _cancel_on_drop = CancelOnDrop(cancel.clone());
+            let task = tokio::task::spawn_blocking(move || {
+                let mut dfu = open()?;
+                dfu.set_cancel_flag(cancel);
+                dfu.with_progress_ex(move |event| {
+                    let _ = sender.send(event);
+                });
+                let res = dfu.download(reader, length);
+                if res.is_err() {
+                    dfu.recover();
+                }
+                res
+            });
+            match task.await {
+                Ok(res) => res,
+                Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+                // The runtime is shutting down.
+                Err(_) => Err(Error::Cancelled),
+            }
+        };
+
+        (
+            download,
+            tokio_stream::wrappers::UnboundedReceiverStream::new(receiver),
+        )
+    }
+}
+
+/// Cancel the download of a [`DfuSync::download_async`] future when it is dropped.
+#[cfg(feature = "tokio")]
+struct CancelOnDrop(Arc<AtomicBool>);
+
+#[cfg(feature = "tokio")]
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn report(progress: &mut Progress, event: ProgressEvent) {
+    progress.report(event);
+}
+
+/// Build a DfuSe special command with its 4-byte little-endian argument.
+fn special_command(command: u8, argument: u32) -> [u8; 5] {
+    let mut buffer = [0x00; 5];
+    buffer[0] = command;
+    buffer[1..].copy_from_slice(&argument.to_le_bytes());
+    buffer
+}
@@ -0,0 +1,83 @@
+use dfu_libusb::mock::ControlTransfer;
+use dfu_libusb::{Error, MockDfuIo};
+use std::io::Read;
+
+const BASE: u32 = 0x0800_0000;
+const LAYOUT: &str = "@Internal Flash /0x08000000/128*001Kg";
+
+/// Returns the addresses given to the Set Address Pointer commands, in order.
+fn address_pointers(io: &MockDfuIo) -> Vec<u32> {
+    io.transfers()
+        .into_iter()
+        .filter_map(|x| match x {
+            ControlTransfer::Out {
+                request: 1,
+                value: 0,
+                data,
+                ..
+            } if data.len() == 5 && data[0] == 0x21 => {
+                Some(u32::from_le_bytes([data[1], data[2], data[3], data[4]]))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns whether any DNLOAD request was sent.
+fn downloaded_anything(io: &MockDfuIo) -> bool {
+    io.transfers()
+        .iter()
+        .any(|x| matches!(x, ControlTransfer::Out { request: 1, .. }))
+}
+
+#[test]
+fn dfuse_download_wraps_block_number() {
+    // A one byte transfer size, so few bytes are needed to use every block.
+    let mut dfu = MockDfuIo::dfuse(LAYOUT, 1).unwrap().into_dfu();
+    let firmware: Vec<u8> = (0..70_000u32).map(|x| (x % 251) as u8).collect();
+
+    let report = dfu.download_from_slice(&firmware).unwrap();
+
+    assert_eq!(report.bytes_written, firmware.len() as u32);
+    let io = dfu.into_inner();
+    assert_eq!(io.downloaded(), firmware);
+    // Blocks 2 to 65535 are used, then the address pointer is moved past them and the blocks are
+    // numbered from 2 again. It is set back to the start before the manifestation.
+    let wrapped = BASE + (u16::MAX as u32 - 1);
+    assert_eq!(address_pointers(&io), [BASE, wrapped, BASE]);
+}
+
+#[test]
+fn dfu_download_rejects_too_many_blocks() {
+    let mut dfu = MockDfuIo::dfu(1).into_dfu();
+    let length = u16::MAX as u32 + 2;
+
+    let res = dfu.download(std::io::repeat(0).take(length as u64), length);
+
+    assert!(matches!(res, Err(Error::ImageTooLarge)));
+    assert!(!downloaded_anything(&dfu.into_inner()));
+}
+
+#[test]
+fn dfu_download_rejects_blocks_without_room_to_terminate() {
+    let mut dfu = MockDfuIo::dfu(1).into_dfu();
+    // Every block number is used by the data, none is left for the zero-length download.
+    let length = u16::MAX as u32 + 1;
+
+    let res = dfu.download(std::io::repeat(0).take(length as u64), length);
+
+    assert!(matches!(res, Err(Error::ImageTooLarge)));
+    assert!(!downloaded_anything(&dfu.into_inner()));
+}
+
+#[test]
+fn dfu_download_counts_partial_last_block() {
+    let mut dfu = MockDfuIo::dfu(2).into_dfu();
+    // The last block is short but still takes the last block number.
+    let length = 2 * u16::MAX as u32 + 1;
+
+    let res = dfu.download(std::io::repeat(0).take(length as u64), length);
+
+    assert!(matches!(res, Err(Error::ImageTooLarge)));
+    assert!(!downloaded_anything(&dfu.into_inner()));
+}
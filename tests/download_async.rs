@@ -0,0 +1,57 @@
+use dfu_core::{State, Status};
+use dfu_libusb::{DfuSync, MockDfuIo, ProgressEvent};
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+
+const FIRMWARE: [u8; 150] = [0x42; 150];
+
+fn downloaded_bytes(events: &[ProgressEvent]) -> usize {
+    events
+        .iter()
+        .map(|event| match event {
+            ProgressEvent::Downloading { bytes } => *bytes,
+            _ => 0,
+        })
+        .sum()
+}
+
+#[tokio::test]
+async fn download_async_streams_progress() {
+    let (download, progress) = DfuSync::download_async(
+        || Ok(MockDfuIo::dfu(64).into_dfu()),
+        &FIRMWARE[..],
+        FIRMWARE.len() as u32,
+    );
+
+    // The future can be spawned, the download runs on the blocking pool.
+    tokio::spawn(download).await.unwrap().unwrap();
+    let events: Vec<ProgressEvent> = progress.collect().await;
+
+    assert_eq!(downloaded_bytes(&events), FIRMWARE.len());
+    assert_eq!(events.last(), Some(&ProgressEvent::Done { success: true }));
+}
+
+#[tokio::test]
+async fn dropping_download_async_cancels() {
+    let (opened, wait_opened) = tokio::sync::oneshot::channel();
+    let (download, progress) = DfuSync::download_async(
+        move || {
+            let io = MockDfuIo::dfu(64);
+            io.push_status(Status::Ok, State::DfuIdle, 0);
+            io.push_status(Status::Ok, State::DfuDnbusy, 60_000);
+            let _ = opened.send(());
+            Ok(io.into_dfu())
+        },
+        &FIRMWARE[..],
+        FIRMWARE.len() as u32,
+    );
+    let download = tokio::spawn(download);
+    wait_opened.await.unwrap();
+
+    let start = Instant::now();
+    download.abort();
+    let events: Vec<ProgressEvent> = progress.collect().await;
+
+    assert_eq!(events.last(), Some(&ProgressEvent::Done { success: false }));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
@@ -0,0 +1,44 @@
+use dfu_libusb::{detect_format, DfuFile, FirmwareFormat};
+use std::io::{Cursor, Seek};
+
+const HEX: &[u8] = b":0400000001020304F2\n:00000001FF\n";
+
+fn detect(bytes: &[u8], extension: Option<&str>) -> FirmwareFormat {
+    detect_format(Cursor::new(bytes), extension).unwrap()
+}
+
+#[test]
+fn detects_magic_bytes() {
+    assert_eq!(detect(b"\x7fELF\x01\x01\x01", None), FirmwareFormat::Elf);
+    assert_eq!(detect(b"DfuSe\x01", None), FirmwareFormat::DfuSe);
+    assert_eq!(detect(HEX, None), FirmwareFormat::Hex);
+    assert_eq!(detect(HEX, Some("hex")), FirmwareFormat::Hex);
+}
+
+#[test]
+fn detects_dfu_suffix() {
+    let mut file = Vec::new();
+    DfuFile::write_with_suffix(&mut file, &[0x55; 32], 0x1eaf, 0x0003, 0x0201).unwrap();
+
+    assert_eq!(detect(&file, None), FirmwareFormat::Dfu);
+    assert_eq!(detect(&file[..32], None), FirmwareFormat::Raw);
+}
+
+#[test]
+fn binary_extension_breaks_hex_tie() {
+    assert_eq!(detect(HEX, Some("bin")), FirmwareFormat::Raw);
+    // Only full records are taken for HEX.
+    assert_eq!(detect(b":\x00\x01\x02", None), FirmwareFormat::Raw);
+}
+
+#[test]
+fn falls_back_to_raw_and_rewinds() {
+    let mut reader = Cursor::new(vec![0x00; 100]);
+    reader.set_position(10);
+
+    assert_eq!(
+        detect_format(&mut reader, Some("elf")).unwrap(),
+        FirmwareFormat::Raw
+    );
+    assert_eq!(reader.stream_position().unwrap(), 10);
+}
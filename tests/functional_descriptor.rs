@@ -0,0 +1,59 @@
+use dfu_libusb::functional_descriptor::Error;
+use dfu_libusb::FunctionalDescriptor;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// The functional descriptor of an STM32 DfuSe bootloader.
+const STM32: [u8; 9] = [0x09, 0x21, 0x0b, 0xff, 0x00, 0x00, 0x08, 0x1a, 0x01];
+
+#[test]
+fn parses_descriptor() {
+    let descriptor = FunctionalDescriptor::try_from(&STM32[..]).unwrap();
+
+    assert!(descriptor.can_download());
+    assert!(descriptor.can_upload());
+    assert!(!descriptor.manifestation_tolerant());
+    assert!(descriptor.will_detach());
+    assert_eq!(descriptor.detach_timeout(), Duration::from_millis(255));
+    assert_eq!(descriptor.transfer_size(), 2048);
+    assert_eq!(descriptor.dfu_version(), (1, 0x1a));
+}
+
+#[test]
+fn ignores_trailing_bytes() {
+    let mut bytes = STM32.to_vec();
+    bytes.extend_from_slice(&[0x09, 0x04]);
+
+    let descriptor = FunctionalDescriptor::try_from(&bytes[..]).unwrap();
+
+    assert_eq!(descriptor.transfer_size(), 2048);
+}
+
+#[test]
+fn rejects_truncated_descriptor() {
+    for length in 0..STM32.len() {
+        let res = FunctionalDescriptor::try_from(&STM32[..length]);
+
+        assert!(matches!(res, Err(Error::Truncated { .. })), "{}", length);
+    }
+}
+
+#[test]
+fn rejects_wrong_type() {
+    let mut bytes = STM32;
+    bytes[1] = 0x04;
+
+    let res = FunctionalDescriptor::try_from(&bytes[..]);
+
+    assert!(matches!(res, Err(Error::InvalidDescriptorType(0x04))));
+}
+
+#[test]
+fn rejects_short_length() {
+    let mut bytes = STM32;
+    bytes[0] = 7;
+
+    let res = FunctionalDescriptor::try_from(&bytes[..]);
+
+    assert!(matches!(res, Err(Error::InvalidLength(7))));
+}
@@ -0,0 +1,659 @@
+use dfu_core::{State, Status};
+use dfu_libusb::mock::ControlTransfer;
+use dfu_libusb::{
+    DfuBuilder, DfuSync, Digest, DigestAlgorithm, Error, FunctionalDescriptorOverrides, MockDfuIo,
+    ProgressEvent, Quirk,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LAYOUT: &str = "@Internal Flash /0x08000000/16*001Kg";
+
+#[test]
+fn dfuse_download_end_to_end() {
+    let mut dfu = MockDfuIo::dfuse(LAYOUT, 256).unwrap().into_dfu();
+    let firmware: Vec<u8> = (0..1500u32).map(|x| (x % 251) as u8).collect();
+
+    dfu.download_from_slice(&firmware).unwrap();
+
+    let io = dfu.into_inner();
+    assert_eq!(io.downloaded(), firmware);
+    let blocks: Vec<u16> = io.downloaded_blocks().iter().map(|x| x.0).collect();
+    assert_eq!(blocks, [2, 3, 4, 5, 6, 7]);
+    // Both pages are erased and the address pointer is set before the data.
+    let commands: Vec<Vec<u8>> = io
+        .transfers()
+        .into_iter()
+        .filter_map(|transfer| match transfer {
+            ControlTransfer::Out {
+                request: 1,
+                value: 0,
+                data,
+                ..
+            } if !data.is_empty() => Some(data),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        &commands[..3],
+        [
+            vec![0x41, 0x00, 0x00, 0x00, 0x08],
+            vec![0x41, 0x00, 0x04, 0x00, 0x08],
+            vec![0x21, 0x00, 0x00, 0x00, 0x08],
+        ]
+    );
+    assert_eq!(io.state(), State::DfuIdle);
+}
+
+#[test]
+fn dfu_download_reports_device_error() {
+    let io = MockDfuIo::dfu(64);
+    // The state is checked before the download, then the second block fails.
+    io.push_status(Status::Ok, State::DfuIdle, 0)
+        .push_status(Status::Ok, State::DfuDnloadIdle, 0)
+        .push_status(Status::ErrWrite, State::DfuError, 0);
+    let mut dfu = io.into_dfu();
+
+    let res = dfu.download_from_slice(&[0; 200]);
+
+    assert!(matches!(
+        res,
+        Err(Error::DfuStatus {
+            status: Status::ErrWrite,
+            state: State::DfuError,
+            description: None,
+        })
+    ));
+    assert_eq!(dfu.into_inner().downloaded_blocks().len(), 2);
+}
+
+#[test]
+fn device_error_includes_status_string() {
+    let io = MockDfuIo::dfu(64);
+    io.set_status_string(4, "Sector 3 is locked");
+    for _ in 0..2 {
+        io.push_status(Status::Ok, State::DfuIdle, 0).push_status(
+            Status::ErrWrite,
+            State::DfuError,
+            0,
+        );
+    }
+    let mut dfu = io.into_dfu();
+
+    for _ in 0..2 {
+        let res = dfu.download_from_slice(&[0; 100]);
+
+        match res {
+            Err(Error::DfuStatus {
+                description: Some(description),
+                ..
+            }) => assert_eq!(description, "Sector 3 is locked"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+    // The string is only read the first time.
+    assert_eq!(dfu.into_inner().string_reads(), 1);
+}
+
+#[test]
+fn dfu_upload_returns_device_data() {
+    let io = MockDfuIo::dfu(64);
+    let firmware: Vec<u8> = (0..100u8).collect();
+    io.set_upload_data(&firmware);
+    let mut dfu = io.into_dfu();
+    let mut data = Vec::new();
+
+    let n = dfu.upload(&mut data, 100).unwrap();
+
+    assert_eq!(n, 100);
+    assert_eq!(data, firmware);
+}
+
+#[test]
+fn short_upload_reports_every_block_then_done() {
+    let io = MockDfuIo::dfu(64);
+    io.set_upload_data(&[0x55; 100]);
+    let mut dfu = io.into_dfu();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    dfu.with_progress_ex({
+        let events = events.clone();
+        move |event| events.borrow_mut().push(event)
+    });
+
+    let n = dfu.upload(std::io::sink(), 200).unwrap();
+
+    assert_eq!(n, 100);
+    assert_eq!(
+        *events.borrow(),
+        [
+            ProgressEvent::Uploading { bytes: 64 },
+            ProgressEvent::Uploading { bytes: 36 },
+            ProgressEvent::Done { success: true },
+        ]
+    );
+}
+
+#[test]
+fn upload_digest_matches_source() {
+    let io = MockDfuIo::dfu(4);
+    io.set_upload_data(b"123456789");
+    let mut dfu = io.into_dfu();
+
+    let digest = dfu.upload_digest(None, 9, DigestAlgorithm::Crc32).unwrap();
+
+    assert_eq!(digest, Digest::Crc32(0xcbf4_3926));
+    assert_eq!(
+        digest,
+        DigestAlgorithm::Crc32.digest(&b"123456789"[..]).unwrap()
+    );
+}
+
+#[test]
+fn download_recovers_from_error_state() {
+    let io = MockDfuIo::dfu(64);
+    io.set_state(State::DfuError);
+    let mut dfu = io.into_dfu();
+
+    dfu.download_from_slice(&[0; 100]).unwrap();
+
+    let io = dfu.into_inner();
+    assert_eq!(io.downloaded(), [0; 100]);
+    assert!(io.transfers().contains(&ControlTransfer::Out {
+        request_type: 0x21,
+        request: 4,
+        value: 0,
+        data: Vec::new(),
+    }));
+}
+
+#[test]
+fn download_fails_in_error_state_without_recovery() {
+    let io = MockDfuIo::dfu(64);
+    io.set_state(State::DfuError);
+    let mut dfu = io.into_dfu();
+    dfu.set_recover_state(false);
+
+    let res = dfu.download_from_slice(&[0; 100]);
+
+    assert!(matches!(res, Err(Error::DfuStatus { .. })));
+    assert!(dfu.into_inner().downloaded().is_empty());
+}
+
+#[test]
+fn diff_download_only_writes_changed_pages() {
+    let io = MockDfuIo::dfuse(LAYOUT, 256).unwrap();
+    let firmware: Vec<u8> = (0..3000u32).map(|x| (x % 251) as u8).collect();
+    // The pages are read back one after the other, only the second one differs.
+    let mut flash = firmware.clone();
+    flash[1500] ^= 0xff;
+    io.set_upload_data(&flash);
+    let mut dfu = io.into_dfu();
+
+    let report = dfu.download_diff(&firmware[..], 3000).unwrap();
+
+    assert_eq!(report.pages_skipped, 2);
+    assert_eq!(report.bytes_written, 1024);
+    let io = dfu.into_inner();
+    assert_eq!(io.downloaded(), &firmware[1024..2048]);
+    let commands: Vec<Vec<u8>> = io
+        .transfers()
+        .into_iter()
+        .filter_map(|transfer| match transfer {
+            ControlTransfer::Out {
+                request: 1,
+                value: 0,
+                data,
+                ..
+            } if !data.is_empty() => Some(data),
+            _ => None,
+        })
+        .collect();
+    // The address pointer is set to every page read, then the second page is erased and
+    // written, and the device leaves DFU mode at the start of the firmware.
+    assert_eq!(
+        commands[3..],
+        [
+            vec![0x41, 0x00, 0x04, 0x00, 0x08],
+            vec![0x21, 0x00, 0x04, 0x00, 0x08],
+            vec![0x21, 0x00, 0x00, 0x00, 0x08],
+        ]
+    );
+}
+
+#[test]
+fn descriptor_overrides_replace_reported_values() {
+    let dfu = DfuBuilder::new()
+        .force_descriptor(FunctionalDescriptorOverrides {
+            transfer_size: Some(128),
+            manifestation_tolerant: Some(false),
+            ..Default::default()
+        })
+        .build(MockDfuIo::dfu(64).into_dfu());
+    let mut dfu = dfu.unwrap();
+
+    dfu.download_from_slice(&[0; 300]).unwrap();
+
+    assert!(!dfu.manifestation_tolerant());
+    assert!(!dfu.will_detach());
+    let sizes: Vec<usize> = dfu
+        .into_inner()
+        .downloaded_blocks()
+        .iter()
+        .map(|(_, data)| data.len())
+        .collect();
+    assert_eq!(sizes, [128, 128, 44]);
+}
+
+#[test]
+fn quirk_applies_workarounds() {
+    let mut dfu = MockDfuIo::dfu(2048).into_dfu();
+
+    Quirk::MAPLE.apply(&mut dfu);
+
+    assert!(!dfu.manifestation_tolerant());
+    assert_eq!(dfu.functional_descriptor().transfer_size(), 1024);
+}
+
+#[test]
+fn builder_keeps_settings_it_does_not_set() {
+    let mut dfu = MockDfuIo::dfu(64).into_dfu();
+    dfu.set_dry_run(true)
+        .set_digest(Some(DigestAlgorithm::Crc32));
+
+    let mut dfu = DfuBuilder::new().retries(1).build(dfu).unwrap();
+    let report = dfu.download_from_slice(&[0x42; 100]).unwrap();
+
+    assert!(report.digest.is_some());
+    assert!(dfu.into_inner().downloaded().is_empty());
+}
+
+#[test]
+fn forced_descriptor_keeps_quirk_overrides() {
+    let mut dfu = MockDfuIo::dfu(2048).into_dfu();
+    Quirk::MAPLE.apply(&mut dfu);
+
+    let dfu = DfuBuilder::new()
+        .force_descriptor(FunctionalDescriptorOverrides {
+            will_detach: Some(true),
+            ..Default::default()
+        })
+        .build(dfu)
+        .unwrap();
+
+    assert!(dfu.will_detach());
+    assert!(!dfu.manifestation_tolerant());
+    assert_eq!(dfu.functional_descriptor().transfer_size(), 1024);
+}
+
+#[test]
+fn dfu_download_rejects_address() {
+    let mut dfu = MockDfuIo::dfu(64).into_dfu();
+    dfu.override_address(0x0800_4000);
+
+    let res = dfu.download_from_slice(&[0; 100]);
+
+    assert!(matches!(res, Err(Error::AddressingUnsupported)));
+    assert!(dfu.into_inner().transfers().is_empty());
+}
+
+#[test]
+fn dfuse_download_refuses_write_protected_sector() {
+    let mut dfu = MockDfuIo::dfuse(LAYOUT, 256).unwrap().into_dfu();
+    dfu.set_write_protected_sectors(vec![1]);
+
+    let res = dfu.download_from_slice(&[0; 1500]);
+
+    assert!(matches!(
+        res,
+        Err(Error::SectorWriteProtected { sector: 1 })
+    ));
+    // Nothing is erased nor written.
+    let downloads = dfu
+        .into_inner()
+        .transfers()
+        .into_iter()
+        .filter(|transfer| matches!(transfer, ControlTransfer::Out { request: 1, .. }))
+        .count();
+    assert_eq!(downloads, 0);
+}
+
+/// Download with the progress events recorded.
+fn download_events(io: MockDfuIo, firmware: &[u8]) -> (Result<(), Error>, Vec<ProgressEvent>) {
+    download_events_with(io.into_dfu(), firmware)
+}
+
+fn download_events_with(
+    mut dfu: DfuSync<MockDfuIo>,
+    firmware: &[u8],
+) -> (Result<(), Error>, Vec<ProgressEvent>) {
+    let events = Rc::new(RefCell::new(Vec::new()));
+    dfu.with_progress_ex({
+        let events = events.clone();
+        move |event| events.borrow_mut().push(event)
+    });
+    let res = dfu.download_from_slice(firmware).map(|_| ());
+    let events = events.borrow().clone();
+    (res, events)
+}
+
+#[test]
+fn download_ends_with_done_event() {
+    let (res, events) = download_events(MockDfuIo::dfu(64), &[0; 100]);
+
+    res.unwrap();
+    assert_eq!(events.last(), Some(&ProgressEvent::Done { success: true }));
+}
+
+#[test]
+fn failed_download_ends_with_done_event() {
+    let io = MockDfuIo::dfu(64);
+    io.push_status(Status::Ok, State::DfuIdle, 0)
+        .push_status(Status::ErrWrite, State::DfuError, 0);
+
+    let (res, events) = download_events(io, &[0; 100]);
+
+    assert!(res.is_err());
+    assert_eq!(events, [ProgressEvent::Done { success: false }]);
+}
+
+#[test]
+fn empty_firmware_is_refused() {
+    let (res, events) = download_events(MockDfuIo::dfu(64), &[]);
+
+    assert!(matches!(res, Err(Error::EmptyFirmware)));
+    assert_eq!(events, [ProgressEvent::Done { success: false }]);
+
+    let mut dfu = MockDfuIo::dfu(64).into_dfu();
+    assert!(matches!(
+        dfu.download(std::io::empty(), 100),
+        Err(Error::EmptyFirmware)
+    ));
+    // Not even the zero-length download that would trigger the manifestation.
+    assert!(!dfu
+        .into_inner()
+        .transfers()
+        .iter()
+        .any(|x| matches!(x, ControlTransfer::Out { request: 1, .. })));
+}
+
+#[test]
+fn manifest_sends_zero_length_download() {
+    let mut dfu = MockDfuIo::dfuse(LAYOUT, 256).unwrap().into_dfu();
+
+    dfu.manifest().unwrap();
+
+    let io = dfu.into_inner();
+    let downloads: Vec<(u16, Vec<u8>)> = io
+        .transfers()
+        .into_iter()
+        .filter_map(|transfer| match transfer {
+            ControlTransfer::Out {
+                request: 1,
+                value,
+                data,
+                ..
+            } => Some((value, data)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        downloads,
+        [(0, vec![0x21, 0x00, 0x00, 0x00, 0x08]), (2, vec![])]
+    );
+}
+
+#[test]
+fn manifest_refuses_plain_dfu() {
+    let mut dfu = MockDfuIo::dfu(64).into_dfu();
+
+    assert!(matches!(dfu.manifest(), Err(Error::DfuseUnsupported)));
+    assert!(dfu.into_inner().transfers().is_empty());
+}
+
+#[test]
+fn busy_device_fails_after_poll_limit() {
+    let io = MockDfuIo::dfu(64);
+    io.push_status(Status::Ok, State::DfuIdle, 0);
+    for _ in 0..10 {
+        io.push_status(Status::Ok, State::DfuDnbusy, 0);
+    }
+    let mut dfu = io.into_dfu();
+    dfu.set_max_poll_iterations(3);
+
+    let res = dfu.download_from_slice(&[0; 100]);
+
+    assert!(matches!(res, Err(Error::PollLimitExceeded(3))));
+    let statuses = dfu
+        .into_inner()
+        .transfers()
+        .iter()
+        .filter(|x| matches!(x, ControlTransfer::In { request: 3, .. }))
+        .count();
+    assert_eq!(statuses, 1 + 3);
+}
+
+#[test]
+fn cancel_interrupts_long_poll_timeout() {
+    let io = MockDfuIo::dfu(64);
+    io.push_status(Status::Ok, State::DfuIdle, 0);
+    io.push_status(Status::Ok, State::DfuDnbusy, 10_000);
+    let mut dfu = io.into_dfu();
+    let cancel = Arc::new(AtomicBool::new(false));
+    dfu.set_cancel_flag(cancel.clone());
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        cancel.store(true, Ordering::Relaxed);
+    });
+
+    let start = Instant::now();
+    let res = dfu.download_from_slice(&[0; 100]);
+    canceller.join().unwrap();
+
+    assert!(matches!(res, Err(Error::Cancelled)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    // The device is brought back to dfuIDLE.
+    let aborted = dfu
+        .into_inner()
+        .transfers()
+        .iter()
+        .any(|x| matches!(x, ControlTransfer::Out { request: 6, .. }));
+    assert!(aborted);
+}
+
+#[test]
+fn progress_interval_coalesces_blocks() {
+    let mut dfu = MockDfuIo::dfu(64).into_dfu();
+    dfu.set_progress_interval(Duration::from_secs(3600));
+
+    let (res, events) = download_events_with(dfu, &[0; 1000]);
+
+    res.unwrap();
+    assert_eq!(
+        events,
+        [
+            ProgressEvent::Downloading { bytes: 64 },
+            ProgressEvent::Downloading { bytes: 936 },
+            ProgressEvent::Manifesting,
+            ProgressEvent::Done { success: true },
+        ]
+    );
+}
+
+const OTP_LAYOUT: &str = "@OTP Memory /0x1FFF7800/01*512 e";
+
+#[test]
+fn otp_download_is_not_erased() {
+    let io = MockDfuIo::dfuse(OTP_LAYOUT, 64).unwrap();
+    io.set_upload_data(&[0xff; 100]);
+    let mut dfu = io.into_dfu();
+
+    dfu.download_from_slice(&[0x42; 100]).unwrap();
+
+    let io = dfu.into_inner();
+    assert_eq!(io.downloaded(), [0x42; 100]);
+    assert!(!io.transfers().iter().any(|transfer| matches!(
+        transfer,
+        ControlTransfer::Out { request: 1, value: 0, data, .. } if data.first() == Some(&0x41)
+    )));
+}
+
+#[test]
+fn otp_diff_download_is_written_without_erase() {
+    let io = MockDfuIo::dfuse(OTP_LAYOUT, 64).unwrap();
+    // Read back once to be compared, once to check it is blank.
+    io.set_upload_data(&[0xff; 200]);
+    let mut dfu = io.into_dfu();
+
+    dfu.download_diff(&[0x42; 100][..], 100).unwrap();
+
+    let io = dfu.into_inner();
+    assert_eq!(io.downloaded(), [0x42; 100]);
+    assert!(!io.transfers().iter().any(|transfer| matches!(
+        transfer,
+        ControlTransfer::Out { request: 1, value: 0, data, .. } if data.first() == Some(&0x41)
+    )));
+}
+
+#[test]
+fn otp_download_refuses_programmed_bytes() {
+    let io = MockDfuIo::dfuse(OTP_LAYOUT, 64).unwrap();
+    let mut otp = [0xff; 100];
+    otp[70] = 0x00;
+    io.set_upload_data(&otp);
+    let mut dfu = io.into_dfu();
+
+    let res = dfu.download_from_slice(&[0x42; 100]);
+
+    assert!(matches!(res, Err(Error::OtpAlreadyProgrammed(0x1fff_7846))));
+    assert!(dfu.into_inner().downloaded().is_empty());
+}
+
+#[test]
+fn otp_page_is_not_erasable() {
+    let mut dfu = MockDfuIo::dfuse(OTP_LAYOUT, 64).unwrap().into_dfu();
+
+    let res = dfu.erase_page(0x1fff_7800);
+
+    assert!(matches!(res, Err(Error::SegmentNotErasable(0x1fff_7800))));
+}
+
+fn not_manifestation_tolerant(io: MockDfuIo) -> DfuSync<MockDfuIo> {
+    let mut dfu = io.into_dfu();
+    dfu.set_functional_descriptor_overrides(FunctionalDescriptorOverrides {
+        manifestation_tolerant: Some(false),
+        ..Default::default()
+    });
+    dfu
+}
+
+#[test]
+fn manifestation_disconnect_is_not_an_error() {
+    let io = MockDfuIo::dfu(64);
+    // The zero-length download after the single block of firmware.
+    io.push_error(1, 1, Error::LibUsb(rusb::Error::NoDevice));
+    let mut dfu = not_manifestation_tolerant(io);
+
+    dfu.download_from_slice(&[0x42; 64]).unwrap();
+}
+
+#[test]
+fn manifestation_stall_is_an_error() {
+    let io = MockDfuIo::dfu(64);
+    io.push_error(1, 1, Error::LibUsb(rusb::Error::Pipe));
+    let mut dfu = not_manifestation_tolerant(io);
+
+    let res = dfu.download_from_slice(&[0x42; 64]);
+
+    assert!(matches!(res, Err(Error::LibUsb(rusb::Error::Pipe))));
+}
+
+fn firmware_blocks_sent(io: &MockDfuIo) -> usize {
+    io.transfers()
+        .iter()
+        .filter(|x| matches!(x, ControlTransfer::Out { request: 1, data, .. } if !data.is_empty()))
+        .count()
+}
+
+#[test]
+fn stalled_transfer_is_retried_after_clrstatus() {
+    let io = MockDfuIo::dfu(64);
+    io.push_error(1, 0, Error::LibUsb(rusb::Error::Pipe));
+    let mut dfu = io.into_dfu();
+    dfu.set_retries(1);
+
+    dfu.download_from_slice(&[0x42; 64]).unwrap();
+
+    let io = dfu.into_inner();
+    assert_eq!(firmware_blocks_sent(&io), 2);
+    let transfers = io.transfers();
+    let stalled = transfers
+        .iter()
+        .position(|x| matches!(x, ControlTransfer::Out { request: 1, .. }))
+        .unwrap();
+    assert!(matches!(
+        transfers[stalled + 1],
+        ControlTransfer::Out { request: 4, .. }
+    ));
+}
+
+#[test]
+fn transport_error_is_not_retried() {
+    let io = MockDfuIo::dfu(64);
+    io.push_error(
+        1,
+        0,
+        std::io::Error::from(std::io::ErrorKind::BrokenPipe).into(),
+    );
+    let mut dfu = io.into_dfu();
+    dfu.set_retries(3);
+
+    let res = dfu.download_from_slice(&[0x42; 64]);
+
+    assert!(matches!(res, Err(Error::Io(_))));
+    assert_eq!(firmware_blocks_sent(&dfu.into_inner()), 1);
+}
+
+#[test]
+fn read_unprotect_takes_disconnect_for_reset() {
+    let io = MockDfuIo::dfuse(LAYOUT, 256).unwrap();
+    io.push_status(Status::Ok, State::DfuIdle, 0);
+    io.push_status_error(Error::LibUsb(rusb::Error::NoDevice));
+    let mut dfu = io.into_dfu();
+
+    assert!(dfu.read_unprotect().is_ok());
+}
+
+#[test]
+fn read_unprotect_stall_is_an_error() {
+    let io = MockDfuIo::dfuse(LAYOUT, 256).unwrap();
+    io.push_status(Status::Ok, State::DfuIdle, 0);
+    io.push_status_error(Error::LibUsb(rusb::Error::Pipe));
+    let mut dfu = io.into_dfu();
+
+    assert!(matches!(
+        dfu.read_unprotect(),
+        Err(Error::LibUsb(rusb::Error::Pipe))
+    ));
+}
+
+#[test]
+fn leave_stall_is_an_error() {
+    let io = MockDfuIo::dfuse(LAYOUT, 256).unwrap();
+    io.push_status(Status::Ok, State::DfuIdle, 0);
+    io.push_status(Status::Ok, State::DfuDnloadIdle, 0);
+    io.push_status_error(Error::LibUsb(rusb::Error::Pipe));
+    let mut dfu = io.into_dfu();
+
+    assert!(matches!(dfu.leave(), Err(Error::LibUsb(rusb::Error::Pipe))));
+
+    let io = MockDfuIo::dfuse(LAYOUT, 256).unwrap();
+    io.push_status(Status::Ok, State::DfuIdle, 0);
+    io.push_status(Status::Ok, State::DfuDnloadIdle, 0);
+    io.push_status_error(Error::LibUsb(rusb::Error::NoDevice));
+    let mut dfu = io.into_dfu();
+
+    dfu.leave().unwrap();
+}
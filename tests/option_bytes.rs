@@ -0,0 +1,56 @@
+use dfu_libusb::{OptionBytes, ReadProtection};
+
+/// The option bytes of an STM32F405 as shipped: no protection, BOR off, resets enabled in the
+/// low-power modes.
+const FACTORY: [u8; 16] = [
+    0xec, 0xaa, 0x13, 0x55, 0x00, 0x00, 0x00, 0x00, 0xff, 0x0f, 0x00, 0xf0, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[test]
+fn parses_factory_option_bytes() {
+    let option_bytes = OptionBytes::parse(&FACTORY).unwrap();
+
+    assert_eq!(option_bytes.read_protection, ReadProtection::Level0);
+    assert_eq!(option_bytes.rdp, 0xaa);
+    assert_eq!(option_bytes.bor_level, 0);
+    assert!(option_bytes.software_watchdog);
+    assert!(option_bytes.no_reset_on_stop);
+    assert!(option_bytes.no_reset_on_standby);
+    assert!(!option_bytes.pcrop);
+    assert!(option_bytes.write_protected_sectors.is_empty());
+    assert_eq!(option_bytes.raw, FACTORY);
+}
+
+#[test]
+fn parses_protections() {
+    let mut bytes = FACTORY;
+    bytes[0] = 0x00;
+    bytes[1] = 0x55;
+    bytes[8] = 0xfc;
+
+    let option_bytes = OptionBytes::parse(&bytes).unwrap();
+
+    assert_eq!(option_bytes.read_protection, ReadProtection::Level1);
+    assert_eq!(option_bytes.bor_level, 3);
+    assert!(!option_bytes.software_watchdog);
+    assert_eq!(option_bytes.write_protected_sectors, vec![0, 1]);
+}
+
+#[test]
+fn pcrop_inverts_protection_bits() {
+    let mut bytes = FACTORY;
+    bytes[1] = 0xcc;
+    bytes[8] = 0x03;
+    bytes[9] = 0x80;
+
+    let option_bytes = OptionBytes::parse(&bytes).unwrap();
+
+    assert_eq!(option_bytes.read_protection, ReadProtection::Level2);
+    assert!(option_bytes.pcrop);
+    assert_eq!(option_bytes.write_protected_sectors, vec![0, 1]);
+}
+
+#[test]
+fn rejects_truncated_option_bytes() {
+    assert!(OptionBytes::parse(&FACTORY[..9]).is_err());
+}
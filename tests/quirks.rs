@@ -0,0 +1,30 @@
+use dfu_libusb::{find_quirk, register_quirk, Quirk};
+use std::time::Duration;
+
+#[test]
+fn registered_quirks_take_precedence() {
+    assert_eq!(find_quirk(0x1eaf, 0x0003), Some(Quirk::MAPLE));
+    assert_eq!(find_quirk(0x1eaf, 0x0004), None);
+
+    let clone = Quirk {
+        vid: 0x1234,
+        pid: None,
+        transfer_size: Some(64),
+        inter_block_delay: Some(Duration::from_millis(5)),
+        manifestation_tolerant: None,
+    };
+    register_quirk(clone.clone());
+    assert_eq!(find_quirk(0x1234, 0x0001), Some(clone.clone()));
+    assert_eq!(find_quirk(0x1234, 0xffff), Some(clone));
+
+    // A quirk without any workaround disables the built-in one.
+    let none = Quirk {
+        vid: 0x1eaf,
+        pid: Some(0x0003),
+        transfer_size: None,
+        inter_block_delay: None,
+        manifestation_tolerant: None,
+    };
+    register_quirk(none.clone());
+    assert_eq!(find_quirk(0x1eaf, 0x0003), Some(none));
+}
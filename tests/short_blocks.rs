@@ -0,0 +1,61 @@
+use dfu_libusb::mock::ControlTransfer;
+use dfu_libusb::MockDfuIo;
+
+const TRANSFER_SIZE: u16 = 64;
+const LAYOUT: &str = "@Internal Flash /0x08000000/16*001Kg";
+
+/// Download `length` bytes and check the blocks sent, numbered from `first_block`.
+fn check_blocks(io: MockDfuIo, first_block: u16, length: usize) {
+    let firmware: Vec<u8> = (0..length as u32).map(|x| (x % 251) as u8).collect();
+    let mut dfu = io.into_dfu();
+
+    let report = dfu.download_from_slice(&firmware).unwrap();
+
+    assert_eq!(report.bytes_written, length as u32);
+    let io = dfu.into_inner();
+    let blocks = io.downloaded_blocks();
+    let chunks: Vec<&[u8]> = firmware.chunks(TRANSFER_SIZE as usize).collect();
+    assert_eq!(blocks.len(), chunks.len(), "length {}", length);
+    for (i, ((block_num, data), chunk)) in blocks.iter().zip(chunks).enumerate() {
+        assert_eq!(*block_num, first_block + i as u16, "length {}", length);
+        assert_eq!(
+            data.as_slice(),
+            chunk,
+            "length {}, block {}",
+            length,
+            block_num
+        );
+    }
+    // The zero-length download ending the transfer comes right after the last block.
+    let last = io.transfers().into_iter().rev().find_map(|x| match x {
+        ControlTransfer::Out {
+            request: 1, value, ..
+        } => Some(value),
+        _ => None,
+    });
+    assert_eq!(last, Some(first_block + blocks.len() as u16));
+}
+
+const LENGTHS: &[usize] = &[
+    1,
+    TRANSFER_SIZE as usize - 1,
+    TRANSFER_SIZE as usize,
+    TRANSFER_SIZE as usize + 1,
+    2 * TRANSFER_SIZE as usize - 1,
+    2 * TRANSFER_SIZE as usize,
+    5 * TRANSFER_SIZE as usize + 7,
+];
+
+#[test]
+fn dfu_download_sends_short_final_block() {
+    for &length in LENGTHS {
+        check_blocks(MockDfuIo::dfu(TRANSFER_SIZE), 0, length);
+    }
+}
+
+#[test]
+fn dfuse_download_sends_short_final_block() {
+    for &length in LENGTHS {
+        check_blocks(MockDfuIo::dfuse(LAYOUT, TRANSFER_SIZE).unwrap(), 2, length);
+    }
+}